@@ -0,0 +1,55 @@
+//! Analog trigger input, read with the ADC when built with the `adc` feature.
+
+#[cfg(feature = "adc")]
+mod imp {
+    use embedded_hal::adc::OneShot;
+    use rp2040_hal::{
+        adc::{Adc, AdcPin},
+        gpio::{
+            bank0::{Gpio26, Gpio27, Gpio28},
+            FunctionSioInput, Pin, PullNone,
+        },
+    };
+
+    type Input<I> = AdcPin<Pin<I, FunctionSioInput, PullNone>>;
+
+    /// The ADC with its inputs on GPIO26, GPIO27 and GPIO28, which are ADC
+    /// channels 0 to 2.
+    pub struct AnalogInput {
+        adc: Adc,
+        ch0: Input<Gpio26>,
+        ch1: Input<Gpio27>,
+        ch2: Input<Gpio28>,
+    }
+
+    impl AnalogInput {
+        pub fn new(adc: Adc, ch0: Input<Gpio26>, ch1: Input<Gpio27>, ch2: Input<Gpio28>) -> Self {
+            Self { adc, ch0, ch1, ch2 }
+        }
+
+        /// Read ADC channel `channel`, or return `None` if it is not available.
+        pub fn read(&mut self, channel: u8) -> Option<u16> {
+            let value: nb::Result<u16, ()> = match channel {
+                0 => self.adc.read(&mut self.ch0),
+                1 => self.adc.read(&mut self.ch1),
+                2 => self.adc.read(&mut self.ch2),
+                _ => return None,
+            };
+            value.ok()
+        }
+    }
+}
+
+#[cfg(not(feature = "adc"))]
+mod imp {
+    /// Stand-in for firmware built without the `adc` feature.
+    pub struct AnalogInput;
+
+    impl AnalogInput {
+        pub fn read(&mut self, _channel: u8) -> Option<u16> {
+            None
+        }
+    }
+}
+
+pub use imp::AnalogInput;
@@ -0,0 +1,40 @@
+//! Second output, e.g. a piezo buzzer or relay, pulsed at each trigger when
+//! built with the `buzzer` feature.
+
+#[cfg(feature = "buzzer")]
+mod imp {
+    use embedded_hal::digital::v2::OutputPin;
+    use rp2040_hal::gpio::{bank0::Gpio15, FunctionSioOutput, Pin, PullDown};
+
+    /// The output on GPIO15, driven high while on.
+    pub struct Buzzer {
+        pin: Pin<Gpio15, FunctionSioOutput, PullDown>,
+    }
+
+    impl Buzzer {
+        pub fn new(mut pin: Pin<Gpio15, FunctionSioOutput, PullDown>) -> Self {
+            pin.set_low().unwrap();
+            Self { pin }
+        }
+
+        pub fn set(&mut self, on: bool) {
+            if on {
+                self.pin.set_high().unwrap();
+            } else {
+                self.pin.set_low().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "buzzer"))]
+mod imp {
+    /// Stand-in for firmware built without the `buzzer` feature.
+    pub struct Buzzer;
+
+    impl Buzzer {
+        pub fn set(&mut self, _on: bool) {}
+    }
+}
+
+pub use imp::Buzzer;
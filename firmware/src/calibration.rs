@@ -0,0 +1,38 @@
+//! Trigger latency calibration, stored in the last sector of flash so that it
+//! survives power cycles.
+
+/// Size of the erasable unit of flash.
+const SECTOR_SIZE: u32 = 4096;
+/// Size of the programmable unit of flash.
+const PAGE_SIZE: usize = 256;
+/// Offset from the start of flash of the sector holding the record. The
+/// linker script (`memory.x`) keeps the program out of this sector.
+const RECORD_OFFSET: u32 = 2048 * 1024 - SECTOR_SIZE;
+/// Address at which flash is mapped for reading.
+const XIP_BASE: u32 = 0x1000_0000;
+/// Marks a written record, distinguishing it from erased flash.
+const MAGIC: u32 = u32::from_le_bytes(*b"CAL1");
+
+/// Read the stored trigger offset, in device ticks, or zero if none has been
+/// stored.
+pub fn load() -> u32 {
+    let record = unsafe { core::ptr::read_volatile((XIP_BASE + RECORD_OFFSET) as *const [u32; 2]) };
+    if record[0] == MAGIC {
+        record[1]
+    } else {
+        0
+    }
+}
+
+/// Store the trigger offset, in device ticks. Interrupts are disabled while
+/// the flash is erased and written, which takes tens of milliseconds.
+pub fn store(offset_ticks: u32) {
+    let mut page = [0xff_u8; PAGE_SIZE];
+    page[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    page[4..8].copy_from_slice(&offset_ticks.to_le_bytes());
+    cortex_m::interrupt::free(|_| unsafe {
+        // Nothing else executes from flash while interrupts are disabled, as
+        // the second core is not used.
+        rp2040_flash::flash::flash_range_erase_and_program(RECORD_OFFSET, &page, true);
+    });
+}
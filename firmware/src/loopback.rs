@@ -0,0 +1,41 @@
+//! Output wired back to the trigger input, pulsed on request to measure the
+//! latency of detecting an edge, when built with the `loopback` feature.
+
+#[cfg(feature = "loopback")]
+mod imp {
+    use embedded_hal::digital::v2::OutputPin;
+    use rp2040_hal::gpio::{bank0::Gpio16, FunctionSioOutput, Pin, PullNone};
+
+    /// The output on GPIO16, driven high at rest, the idle level of the
+    /// trigger input by default.
+    pub struct Loopback {
+        pin: Pin<Gpio16, FunctionSioOutput, PullNone>,
+    }
+
+    impl Loopback {
+        pub fn new(mut pin: Pin<Gpio16, FunctionSioOutput, PullNone>) -> Self {
+            pin.set_high().unwrap();
+            Self { pin }
+        }
+
+        pub fn set_high(&mut self, high: bool) {
+            if high {
+                self.pin.set_high().unwrap();
+            } else {
+                self.pin.set_low().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "loopback"))]
+mod imp {
+    /// Stand-in for firmware built without the `loopback` feature.
+    pub struct Loopback;
+
+    impl Loopback {
+        pub fn set_high(&mut self, _high: bool) {}
+    }
+}
+
+pub use imp::Loopback;
@@ -5,10 +5,35 @@ use defmt_rtt as _;
 use panic_probe as _;
 use rtic::Mutex;
 
-use red_button_trigger_timestamp_comms::{FromDevice, ToDevice};
+#[cfg(feature = "crc")]
+use red_button_trigger_timestamp_comms::crc::Checked;
+use red_button_trigger_timestamp_comms::{
+    capabilities,
+    debounce::Debouncer,
+    differential::{FirstToFire, FirstToFireDetector},
+    history::{HistoryEntry, TriggerHistory},
+    indicator::Indicator,
+    min_interval::MinInterval,
+    pairing::{EdgePair, EdgePairer},
+    pulse::PulseTimer,
+    rtc::RtcAnchor,
+    test_pattern::TestPattern,
+    threshold::ThresholdDetector,
+    tick_scale::TickScale,
+    BuildConfig, ConfigState, Debounce, DeviceConfig, DeviceStatus, FromDevice, LineState, Pull,
+    ResetReason, ToDevice, Transport, VersionResponse, BOOTLOADER_CONFIRM, MAX_FROM_DEVICE_LEN,
+    MAX_TO_DEVICE_LEN, USB_VID_PID,
+};
 
 use json_lines::accumulator::{FeedResult, NewlinesAccumulator};
 
+mod analog;
+mod buzzer;
+mod calibration;
+mod loopback;
+mod rtc;
+mod stack;
+
 #[rtic::app(device = rp_pico::hal::pac, peripherals = true, dispatchers = [I2C0_IRQ])]
 mod app {
     use super::*;
@@ -20,22 +45,131 @@ mod app {
 
     use embedded_hal::digital::v2::{InputPin, OutputPin};
     use rp2040_hal::{
-        self as hal, clocks::init_clocks_and_plls, usb::UsbBus, watchdog::Watchdog, Sio,
+        self as hal, clocks::init_clocks_and_plls, gpio::Interrupt, usb::UsbBus,
+        watchdog::Watchdog, Sio,
     };
     use rp2040_monotonic::Rp2040Monotonic;
 
-    const MAX_FRAME_SZ: usize = 256;
+    /// Capacity of a single USB read. Each queued frame reserves this many
+    /// bytes of RAM whether or not it is full.
+    const MAX_FRAME_SZ: usize = 448;
+    /// Number of frames which can be queued between the USB interrupt and
+    /// `idle`. Together with `MAX_FRAME_SZ` this sizes the receive queue, which
+    /// costs `MAX_FRAME_SZ * NUM_FRAMES` bytes of RAM.
     const NUM_FRAMES: usize = 8;
+    // A complete command must fit in one read so that it is never split by a
+    // full buffer.
+    const _: () = assert!(MAX_FRAME_SZ >= MAX_TO_DEVICE_LEN);
+    /// Minimum duration, in device ticks, that the new input level of a press
+    /// must persist before the edge is reported, unless configured with
+    /// [DeviceConfig::debounce]. Shorter pulses are rejected as glitches.
+    const DEBOUNCE_PRESS_TICKS: u64 = 5;
+    /// As `DEBOUNCE_PRESS_TICKS`, for the new level of a release.
+    const DEBOUNCE_RELEASE_TICKS: u64 = 5;
+    /// GPIO number of `trigger_pin`, reported in [BuildConfig].
+    const TRIGGER_GPIO: u8 = 13;
+    /// Ticks after a press at which, with [DeviceConfig::pair_edges], it is
+    /// reported without a release.
+    const PAIR_TIMEOUT_TICKS: u64 = 10_000_000;
+    /// Ticks after a press on one trigger input at which, with
+    /// [DeviceConfig::differential], it is reported without the other.
+    const DIFF_TIMEOUT_TICKS: u64 = 1_000_000;
+    /// Duration, in ticks, of the LED flash at each trigger
+    const LED_FLASH_TICKS: u64 = 50_000;
+    /// Period, in ticks, of the LED heartbeat with the `heartbeat-led` feature
+    const HEARTBEAT_PERIOD_TICKS: u64 = 1_000_000;
+    /// Duration, in ticks, of each heartbeat blink
+    const HEARTBEAT_ON_TICKS: u64 = 20_000;
+    /// Number of recent triggers kept for [ToDevice::History]. Each costs 16
+    /// bytes of RAM.
+    const HISTORY_DEPTH: usize = 64;
+    /// Ticks before each expected change of the RTC's seconds from which it
+    /// is read, to time the change.
+    const RTC_POLL_WINDOW_TICKS: u64 = 5_000;
+    /// Ticks after the RTC fails to answer before it is read again.
+    const RTC_RETRY_TICKS: u64 = 1_000_000;
+    /// Duration, in ticks, of each pulse on the loopback output, long enough
+    /// to pass the default debounce windows.
+    const LOOPBACK_PULSE_TICKS: u64 = 10_000;
+    /// Frequency of the monotonic timer.
+    const MONOTONIC_HZ: u32 = 1_000_000;
+    /// Frequency of the ticks in which times are measured and reported, which
+    /// are microseconds whatever the monotonic timer's rate (see [now_ticks]).
+    const TICK_HZ: u32 = 1_000_000;
+    /// Optional features of this build, reported in [VersionResponse].
+    const CAPABILITIES: u32 = capabilities::BUILD_CONFIG
+        | capabilities::STATUS
+        | capabilities::ECHO
+        | capabilities::PAIR_EDGES
+        | capabilities::RESUME_NOTIFY
+        | capabilities::CALIBRATION
+        | capabilities::DIFFERENTIAL
+        | capabilities::TEST_PATTERN
+        | capabilities::BEACON
+        | capabilities::ARM
+        | capabilities::HISTORY
+        | capabilities::MIN_INTERVAL
+        | capabilities::BOOTLOADER
+        | capabilities::DEBOUNCE
+        | capabilities::GET_CONFIG
+        | capabilities::EPOCH_MARK
+        | if cfg!(feature = "adc") {
+            capabilities::ANALOG
+        } else {
+            0
+        }
+        | if cfg!(feature = "buzzer") {
+            capabilities::BUZZER
+        } else {
+            0
+        }
+        | if cfg!(feature = "crc") {
+            capabilities::CRC
+        } else {
+            0
+        }
+        | if cfg!(feature = "rtc") {
+            capabilities::RTC
+        } else {
+            0
+        }
+        | if cfg!(feature = "loopback") {
+            capabilities::LOOPBACK
+        } else {
+            0
+        };
     type UsbFrame = heapless::Vec<u8, MAX_FRAME_SZ>;
+    type GreenLed =
+        hal::gpio::Pin<hal::gpio::bank0::Gpio25, hal::gpio::FunctionSioOutput, hal::gpio::PullNone>;
+
+    /// Bytes read from USB together with the tick at which they arrived.
+    struct RxFrame {
+        received: u64,
+        data: UsbFrame,
+    }
+
+    /// Level of a trigger input and the tick of its most recent edge, as
+    /// captured by `on_trigger_edge`.
+    #[derive(Clone, Copy)]
+    struct TriggerInput {
+        high: bool,
+        edge_tick: u64,
+    }
 
     #[shared]
     struct Shared {
-        green_led: hal::gpio::Pin<
-            hal::gpio::bank0::Gpio25,
-            hal::gpio::FunctionSioOutput,
-            hal::gpio::PullNone,
-        >,
+        green_led: GreenLed,
         usb_serial: SerialPort<'static, UsbBus>,
+        /// The trigger inputs on GPIO13 (channel 0) and GPIO14 (channel 1)
+        trigger_inputs: [TriggerInput; 2],
+        /// Tick at which the USB bus most recently resumed from suspend, not
+        /// yet reported to the host.
+        resumed_at: Option<u64>,
+        /// Tick at which a host most recently opened the serial port, seen as
+        /// DTR being asserted, not yet handled by `idle`.
+        host_connected_at: Option<u64>,
+        /// Most frames there have been in the receive queue at once
+        rx_high_water: u8,
     }
 
     #[monotonic(binds = TIMER_IRQ_0, default = true)]
@@ -48,15 +182,30 @@ mod app {
             hal::gpio::FunctionSioInput,
             hal::gpio::PullUp,
         >,
+        /// Second input, for [DeviceConfig::differential]
+        second_trigger_pin: hal::gpio::Pin<
+            hal::gpio::bank0::Gpio14,
+            hal::gpio::FunctionSioInput,
+            hal::gpio::PullUp,
+        >,
         usb_dev: UsbDevice<'static, UsbBus>,
-        rx_prod: Producer<'static, UsbFrame, NUM_FRAMES>,
-        rx_cons: Consumer<'static, UsbFrame, NUM_FRAMES>,
+        rx_prod: Producer<'static, RxFrame, NUM_FRAMES>,
+        rx_cons: Consumer<'static, RxFrame, NUM_FRAMES>,
+        analog: analog::AnalogInput,
+        buzzer: buzzer::Buzzer,
+        loopback: loopback::Loopback,
+        rtc: rtc::Rtc,
+        reset_reason: ResetReason,
     }
 
     #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBus>> = None])]
     fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+        // Interrupts are still disabled, so nothing else uses the stack.
+        stack::paint();
         defmt::info!("Hello from {}.", env!["CARGO_PKG_NAME"]);
         let mut resets = c.device.RESETS;
+        let reset_reason = read_reset_reason(&c.device.WATCHDOG, &c.device.VREG_AND_CHIP_RESET);
+        defmt::info!("Reset reason: {}", reset_reason);
         let mut watchdog = Watchdog::new(c.device.WATCHDOG);
         let clocks = init_clocks_and_plls(
             XOSC_CRYSTAL_FREQ,
@@ -80,7 +229,8 @@ mod app {
         )));
         let usb_serial = SerialPort::new(usb_bus.as_ref().unwrap());
 
-        let usb_dev = UsbDeviceBuilder::new(usb_bus.as_ref().unwrap(), UsbVidPid(0x16c0, 0x27dd))
+        let (vid, pid) = USB_VID_PID;
+        let usb_dev = UsbDeviceBuilder::new(usb_bus.as_ref().unwrap(), UsbVidPid(vid, pid))
             .manufacturer("Straw Lab")
             .product("Red Button Trigger Timestamp Logger")
             .serial_number("TEST")
@@ -96,113 +246,850 @@ mod app {
         );
 
         let mut green_led = pins.led.reconfigure();
-        green_led.set_low().unwrap();
+        set_led(&mut green_led, false);
 
-        let trigger_pin = pins.gpio13.reconfigure();
+        let mut trigger_pin = pins.gpio13.reconfigure();
+        trigger_pin.set_interrupt_enabled(Interrupt::EdgeLow, true);
+        trigger_pin.set_interrupt_enabled(Interrupt::EdgeHigh, true);
+        let mut second_trigger_pin = pins.gpio14.reconfigure();
+        second_trigger_pin.set_interrupt_enabled(Interrupt::EdgeLow, true);
+        second_trigger_pin.set_interrupt_enabled(Interrupt::EdgeHigh, true);
+        let trigger_inputs = [
+            trigger_pin.is_high().unwrap(),
+            second_trigger_pin.is_high().unwrap(),
+        ]
+        .map(|high| TriggerInput { high, edge_tick: 0 });
 
-        let rx_queue: &'static mut Queue<UsbFrame, NUM_FRAMES> = {
-            static mut Q: Queue<UsbFrame, NUM_FRAMES> = Queue::new();
+        let rx_queue: &'static mut Queue<RxFrame, NUM_FRAMES> = {
+            static mut Q: Queue<RxFrame, NUM_FRAMES> = Queue::new();
             unsafe { &mut Q }
         };
         let (rx_prod, rx_cons) = rx_queue.split();
 
+        #[cfg(feature = "adc")]
+        let analog = {
+            use hal::adc::AdcPin;
+            analog::AnalogInput::new(
+                hal::Adc::new(c.device.ADC, &mut resets),
+                AdcPin::new(pins.gpio26.into_floating_input()),
+                AdcPin::new(pins.gpio27.into_floating_input()),
+                AdcPin::new(pins.gpio28.into_floating_input()),
+            )
+        };
+        #[cfg(not(feature = "adc"))]
+        let analog = analog::AnalogInput;
+
+        #[cfg(feature = "buzzer")]
+        let buzzer = buzzer::Buzzer::new(pins.gpio15.reconfigure());
+        #[cfg(not(feature = "buzzer"))]
+        let buzzer = buzzer::Buzzer;
+
+        #[cfg(feature = "loopback")]
+        let loopback = loopback::Loopback::new(pins.gpio16.reconfigure());
+        #[cfg(not(feature = "loopback"))]
+        let loopback = loopback::Loopback;
+
+        #[cfg(feature = "rtc")]
+        let rtc = {
+            use hal::fugit::RateExtU32;
+            rtc::Rtc::new(hal::I2C::i2c1(
+                c.device.I2C1,
+                pins.gpio6.reconfigure(),
+                pins.gpio7.reconfigure(),
+                400.kHz(),
+                &mut resets,
+                &clocks.system_clock,
+            ))
+        };
+        #[cfg(not(feature = "rtc"))]
+        let rtc = rtc::Rtc;
+
         let mono = Monotonic::new(c.device.TIMER);
 
         (
             Shared {
                 green_led,
                 usb_serial,
+                trigger_inputs,
+                resumed_at: None,
+                host_connected_at: None,
+                rx_high_water: 0,
             },
             Local {
                 trigger_pin,
+                second_trigger_pin,
                 usb_dev,
                 rx_prod,
                 rx_cons,
+                analog,
+                buzzer,
+                loopback,
+                rtc,
+                reset_reason,
             },
             init::Monotonics(mono),
         )
     }
 
+    /// The current tick, in microseconds since boot, converted from the
+    /// monotonic timer so that reported times keep their unit if the timer's
+    /// rate changes.
+    fn now_ticks() -> u64 {
+        TickScale::new(MONOTONIC_HZ).to_micros(monotonics::Monotonic::now().ticks())
+    }
+
+    /// The protocol version and capabilities of this build.
+    fn version_response() -> VersionResponse {
+        VersionResponse {
+            capabilities: CAPABILITIES,
+            ..Default::default()
+        }
+    }
+
+    /// Turn the green LED on or off. With the `led-active-low` feature, for
+    /// boards whose LED lights when its pin is driven low, the pin is driven
+    /// low to turn it on.
+    fn set_led(green_led: &mut GreenLed, on: bool) {
+        if on != cfg!(feature = "led-active-low") {
+            green_led.set_high().unwrap();
+        } else {
+            green_led.set_low().unwrap();
+        }
+    }
+
+    /// Longest time, in monotonic timer ticks, to wait for the host to drain
+    /// the USB endpoint before a message is dropped.
+    const WRITE_TIMEOUT_TICKS: u64 = MONOTONIC_HZ as u64 / 100;
+
     fn send_response(
         response: &FromDevice,
         ctx: &mut idle::Context,
-        &mut mut out_buf: &mut [u8; 256],
-    ) {
+        &mut mut out_buf: &mut [u8; MAX_FROM_DEVICE_LEN],
+    ) -> Result<(), UsbError> {
+        #[cfg(not(feature = "crc"))]
         let encoded = json_lines::to_slice_newline(&response, &mut out_buf[..]).unwrap();
+        #[cfg(feature = "crc")]
+        let encoded = {
+            let mut msg_buf = [0u8; MAX_FROM_DEVICE_LEN];
+            let n = serde_json_core::to_slice(response, &mut msg_buf).unwrap();
+            let checked = Checked::new(response, &msg_buf[..n]);
+            json_lines::to_slice_newline(&checked, &mut out_buf[..]).unwrap()
+        };
+
+        // A line left incomplete by a failed write is ended first, so that
+        // the host discards only it rather than this message too.
+        if *ctx.local.unterminated_line {
+            write_all(ctx, b"\n").map_err(|(e, _)| e)?;
+            *ctx.local.unterminated_line = false;
+        }
+        if let Err((e, written)) = write_all(ctx, encoded) {
+            *ctx.local.unterminated_line = written > 0;
+            return Err(e);
+        }
+        defmt::trace!("sent {} bytes", encoded.len());
+        Ok(())
+    }
 
-        ctx.shared.usb_serial.lock(|usb_serial| {
-            match usb_serial.write(encoded) {
-                // this can panic with WouldBlock
-                Ok(_nbytes) => {
-                    // Should we check if nbytes == encoded.len()?
+    /// Write all of `bytes`, waiting up to [WRITE_TIMEOUT_TICKS] for the host
+    /// to drain the endpoint. On failure, returns the error with the number
+    /// of bytes which were written.
+    fn write_all(ctx: &mut idle::Context, bytes: &[u8]) -> Result<(), (UsbError, usize)> {
+        let start = monotonics::Monotonic::now().ticks();
+        let mut written = 0;
+        let mut warned = false;
+        while written < bytes.len() {
+            // Release the lock between attempts so that the USB interrupt can
+            // run and drain the endpoint.
+            let result = ctx
+                .shared
+                .usb_serial
+                .lock(|usb_serial| usb_serial.write(&bytes[written..]));
+            match result {
+                Ok(nbytes) => {
+                    written += nbytes;
                 }
                 Err(UsbError::WouldBlock) => {
-                    defmt::error!("failed to send message: WouldBlock");
+                    if monotonics::Monotonic::now().ticks() - start >= WRITE_TIMEOUT_TICKS {
+                        return Err((UsbError::WouldBlock, written));
+                    }
+                    if !warned {
+                        defmt::warn!("write would block, retrying");
+                        warned = true;
+                    }
                 }
                 Err(e) => {
-                    panic!("Writing message to USB: {e:?}");
+                    return Err((e, written));
                 }
             }
-        });
-        defmt::trace!("sent {} bytes", encoded.len());
+        }
+        Ok(())
+    }
+
+    /// Whether a request received with the `crc` feature matches its CRC.
+    #[cfg(feature = "crc")]
+    fn crc_matches(checked: &Checked<ToDevice>) -> bool {
+        let mut buf = [0u8; MAX_TO_DEVICE_LEN];
+        match serde_json_core::to_slice(&checked.msg, &mut buf) {
+            Ok(n) => checked.matches(&buf[..n]),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether a trigger at `tick` is within the configured daily window, if
+    /// any, counting it in `outside_window` if not. Until the RTC's time is
+    /// known, every trigger is.
+    fn in_daily_window(
+        config: &DeviceConfig,
+        rtc_anchor: &RtcAnchor,
+        tick: u64,
+        outside_window: &mut u32,
+    ) -> bool {
+        let (Some(window), Some(unix_micros)) = (config.daily_window, rtc_anchor.unix_micros(tick))
+        else {
+            return true;
+        };
+        let inside = window.contains(unix_micros);
+        if !inside {
+            *outside_window = outside_window.saturating_add(1);
+        }
+        inside
+    }
+
+    /// The debounce windows in effect with `config`, configured or built in.
+    fn debounce_windows(config: &DeviceConfig) -> Debounce {
+        config.debounce.clone().unwrap_or(Debounce {
+            press_ticks: DEBOUNCE_PRESS_TICKS as u32,
+            release_ticks: DEBOUNCE_RELEASE_TICKS as u32,
+        })
+    }
+
+    /// Set the debounce windows of `debouncers` from `config`, mapping press
+    /// and release to the input levels of its polarity.
+    fn set_debounce(debouncers: &mut [Debouncer], config: &DeviceConfig) {
+        let windows = debounce_windows(config);
+        let (press, release) = (windows.press_ticks.into(), windows.release_ticks.into());
+        let (low, high) = if config.active_high {
+            (release, press)
+        } else {
+            (press, release)
+        };
+        for debouncer in debouncers {
+            debouncer.set_min_pulse_ticks(low, high);
+        }
+    }
+
+    /// Send a trigger message, logging any failure, and keep it in `history`.
+    /// Triggers are not retried, but are kept whether or not they were sent.
+    fn send_trigger(
+        response: &FromDevice,
+        history: &mut TriggerHistory<HISTORY_DEPTH>,
+        ctx: &mut idle::Context,
+        out_buf: &mut [u8; MAX_FROM_DEVICE_LEN],
+    ) {
+        if let Some(entry) = HistoryEntry::of(response) {
+            history.push(entry);
+        }
+        if let Err(e) = send_response(response, ctx, out_buf) {
+            defmt::error!("failed to send trigger: {}", defmt::Debug2Format(&e));
+        }
     }
 
-    #[idle(shared = [usb_serial, green_led], local = [trigger_pin, rx_cons])]
+    #[idle(shared = [usb_serial, green_led, trigger_inputs, resumed_at, host_connected_at, rx_high_water], local = [rx_cons, analog, buzzer, loopback, rtc, reset_reason, unterminated_line: bool = false])]
     fn idle(mut ctx: idle::Context) -> ! {
         let mut decoder = NewlinesAccumulator::<512>::new();
-        let mut out_buf = [0u8; 256];
+        let mut out_buf = [0u8; MAX_FROM_DEVICE_LEN];
 
-        let mut prev_state = ctx.local.trigger_pin.is_high().unwrap();
+        let mut config = DeviceConfig::default();
+        // Tick at which the most recent ping was received.
+        let mut last_ping: Option<u64> = None;
+        // Number of triggers since boot, including any which failed to send.
+        let mut trigger_count: u32 = 0;
+        let initial = ctx.shared.trigger_inputs.lock(|inputs| *inputs);
+        let mut debouncers = initial.map(|input| Debouncer::new(input.high, DEBOUNCE_PRESS_TICKS));
+        set_debounce(&mut debouncers, &config);
+        let mut first_to_fire = FirstToFireDetector::new(DIFF_TIMEOUT_TICKS);
+        let mut pairer = EdgePairer::new(PAIR_TIMEOUT_TICKS);
+        let mut threshold_detector: Option<ThresholdDetector> = None;
+        // Loop iterations since the tick of the previous status report.
+        let mut n_loops: u64 = 0;
+        let mut last_status_tick = now_ticks();
+        // Latency, in ticks, subtracted from the tick of each trigger.
+        let mut trigger_offset = calibration::load();
+        // Pulse on the buzzer output, and whether the output is on.
+        let mut buzzer_pulse = PulseTimer::new();
+        let mut buzzer_on = false;
+        // Pulse on the loopback output, the output's level, and the ticks at
+        // which the pulse in progress was driven and detected.
+        let mut loopback_pulse = PulseTimer::new();
+        let mut loopback_high = true;
+        let mut loopback_edge: Option<(u64, Option<u64>)> = None;
+        // The LED, and whether it is lit.
+        let mut indicator = if cfg!(feature = "heartbeat-led") {
+            Indicator::new(LED_FLASH_TICKS)
+                .with_heartbeat(HEARTBEAT_PERIOD_TICKS, HEARTBEAT_ON_TICKS)
+        } else {
+            Indicator::new(LED_FLASH_TICKS)
+        };
+        let mut led_on = false;
+        let mut test_pattern: Option<TestPattern> = None;
+        // Tick at which the next beacon is due.
+        let mut next_beacon: Option<u64> = None;
+        // Bytes of a read following a message, decoded before the next read.
+        let mut leftover: Option<RxFrame> = None;
+        // Whether triggers are reported. Detection continues while disarmed,
+        // so that arming does not report an edge from before.
+        let mut armed = !cfg!(feature = "require-arm");
+        let mut history = TriggerHistory::<HISTORY_DEPTH>::new();
+        let mut min_interval = MinInterval::new(0);
+        let mut rtc_anchor = RtcAnchor::new(RTC_POLL_WINDOW_TICKS);
+        // Triggers suppressed outside the daily window since boot.
+        let mut outside_window: u32 = 0;
+        // Tick before which the RTC is not read, after it failed to answer.
+        let mut rtc_retry_at: u64 = 0;
         loop {
-            let this_state = ctx.local.trigger_pin.is_high().unwrap();
-            if this_state != prev_state {
-                if !this_state {
-                    let now = monotonics::Monotonic::now().ticks();
-                    let response = FromDevice::Trigger(now);
-                    send_response(&response, &mut ctx, &mut out_buf);
-                    defmt::info!("Trigger: {}", now);
+            n_loops += 1;
+            let prev_trigger_count = trigger_count;
+            let inputs = ctx.shared.trigger_inputs.lock(|inputs| *inputs);
+            let now = now_ticks();
+            let mut pair: Option<EdgePair> = pairer.poll(now);
+            // A timed out press on one input, and one completed by both.
+            let mut diffs: [Option<FirstToFire>; 2] = [first_to_fire.poll(now), None];
+            // Edges are timed by the interrupt rather than when noticed here.
+            for (channel, (debouncer, input)) in debouncers.iter_mut().zip(inputs).enumerate() {
+                let Some((level, edge_tick)) = debouncer.update(input.high, input.edge_tick, now)
+                else {
+                    continue;
+                };
+                if cfg!(feature = "raw-edges") {
+                    let response = FromDevice::RawEdge {
+                        channel: channel as u8,
+                        level,
+                        raw_tick: debouncer
+                            .raw_edge_tick()
+                            .saturating_sub(trigger_offset.into()),
+                        tick: edge_tick.saturating_sub(trigger_offset.into()),
+                    };
+                    if let Err(e) = send_response(&response, &mut ctx, &mut out_buf) {
+                        defmt::error!("failed to send raw edge: {}", defmt::Debug2Format(&e));
+                    }
+                }
+                let is_press = level == config.active_high;
+                if let Some((driven, detected @ None)) = &mut loopback_edge {
+                    if channel == 0 && is_press && edge_tick >= *driven {
+                        *detected = Some(edge_tick);
+                    }
+                }
+                if config.differential {
+                    if is_press {
+                        // Of the two inputs, at most the second completes.
+                        diffs[1] = diffs[1].or(first_to_fire.fire(channel as u8, edge_tick));
+                    }
+                } else if channel != 0 {
+                    // The second input is only used in differential mode.
+                } else if config.pair_edges {
+                    let completed = if is_press {
+                        pairer.press(edge_tick)
+                    } else {
+                        pairer.release(edge_tick)
+                    };
+                    // At most one of a timeout and an edge completes a pair in
+                    // one iteration, since either empties the pairer before a
+                    // press refills it.
+                    pair = pair.or(completed);
+                } else if is_press
+                    && armed
+                    && in_daily_window(&config, &rtc_anchor, edge_tick, &mut outside_window)
+                    && min_interval.allow(edge_tick)
+                {
+                    trigger_count = trigger_count.wrapping_add(1);
+                    let response = FromDevice::Trigger {
+                        timestamp: edge_tick.saturating_sub(trigger_offset.into()),
+                        last_ping: last_ping.filter(|_| config.report_last_ping),
+                        sequence: trigger_count,
+                        level: None,
+                        test: false,
+                        rtc_unix_micros: rtc_anchor
+                            .unix_micros(edge_tick.saturating_sub(trigger_offset.into())),
+                    };
+                    send_trigger(&response, &mut history, &mut ctx, &mut out_buf);
+                    defmt::info!("Trigger: {}", edge_tick);
+                }
+            }
+
+            if let (Some(adc), Some(detector)) = (&config.adc, threshold_detector.as_mut()) {
+                if let Some(level) = ctx.local.analog.read(adc.channel) {
+                    if detector.update(level)
+                        && armed
+                        && in_daily_window(&config, &rtc_anchor, now, &mut outside_window)
+                        && min_interval.allow(now)
+                    {
+                        trigger_count = trigger_count.wrapping_add(1);
+                        let response = FromDevice::Trigger {
+                            timestamp: now.saturating_sub(trigger_offset.into()),
+                            last_ping: last_ping.filter(|_| config.report_last_ping),
+                            sequence: trigger_count,
+                            level: Some(level),
+                            test: false,
+                            rtc_unix_micros: rtc_anchor
+                                .unix_micros(now.saturating_sub(trigger_offset.into())),
+                        };
+                        send_trigger(&response, &mut history, &mut ctx, &mut out_buf);
+                        defmt::info!("Analog trigger: {} at level {}", now, level);
+                    }
+                }
+            }
+
+            if !armed {
+                diffs = [None, None];
+                pair = None;
+            }
+            for diff in diffs.iter_mut() {
+                *diff = diff.take().filter(|diff| {
+                    in_daily_window(&config, &rtc_anchor, diff.first_tick, &mut outside_window)
+                        && min_interval.allow(diff.first_tick)
+                });
+            }
+            pair = pair.filter(|pair| {
+                in_daily_window(&config, &rtc_anchor, pair.press_tick, &mut outside_window)
+                    && min_interval.allow(pair.press_tick)
+            });
+            for diff in diffs.into_iter().flatten() {
+                trigger_count = trigger_count.wrapping_add(1);
+                let response = FromDevice::TriggerDiff {
+                    first_channel: diff.first_channel,
+                    timestamp: diff.first_tick.saturating_sub(trigger_offset.into()),
+                    delta_ticks: diff.delta_ticks,
+                    simultaneous: diff.simultaneous(),
+                    last_ping: last_ping.filter(|_| config.report_last_ping),
+                    sequence: trigger_count,
+                    rtc_unix_micros: rtc_anchor
+                        .unix_micros(diff.first_tick.saturating_sub(trigger_offset.into())),
+                };
+                send_trigger(&response, &mut history, &mut ctx, &mut out_buf);
+                defmt::info!("Trigger diff: {}", diff.first_tick);
+            }
+
+            let test_tick = test_pattern.as_mut().and_then(|pattern| pattern.poll(now));
+            if let Some(tick) = test_tick.filter(|_| armed) {
+                trigger_count = trigger_count.wrapping_add(1);
+                let response = FromDevice::Trigger {
+                    timestamp: tick,
+                    last_ping: last_ping.filter(|_| config.report_last_ping),
+                    sequence: trigger_count,
+                    level: None,
+                    test: true,
+                    rtc_unix_micros: rtc_anchor.unix_micros(tick),
+                };
+                send_trigger(&response, &mut history, &mut ctx, &mut out_buf);
+                if test_pattern.as_ref().is_some_and(TestPattern::is_done) {
+                    test_pattern = None;
+                }
+            }
+
+            if let Some(pair) = pair {
+                trigger_count = trigger_count.wrapping_add(1);
+                let response = FromDevice::TriggerPair {
+                    press_tick: pair.press_tick.saturating_sub(trigger_offset.into()),
+                    release_tick: pair
+                        .release_tick
+                        .map(|tick| tick.saturating_sub(trigger_offset.into())),
+                    last_ping: last_ping.filter(|_| config.report_last_ping),
+                    sequence: trigger_count,
+                    rtc_unix_micros: rtc_anchor
+                        .unix_micros(pair.press_tick.saturating_sub(trigger_offset.into())),
+                };
+                send_trigger(&response, &mut history, &mut ctx, &mut out_buf);
+                defmt::info!("Trigger pair: {}", pair.press_tick);
+            }
+
+            if trigger_count != prev_trigger_count && config.buzzer_ticks > 0 {
+                buzzer_pulse.start(now, config.buzzer_ticks.into());
+            }
+            // Only the output level changes here, so the pulse never blocks
+            // the loop.
+            let on = buzzer_pulse.poll(now);
+            if on != buzzer_on {
+                ctx.local.buzzer.set(on);
+                buzzer_on = on;
+            }
+
+            // The loopback output rests at the trigger input's idle level.
+            let on = loopback_pulse.poll(now);
+            let high = on == config.active_high;
+            if high != loopback_high {
+                ctx.local.loopback.set_high(high);
+                loopback_high = high;
+            }
+            if !on {
+                if let Some((driven, detected)) = loopback_edge.take() {
+                    let response = FromDevice::LoopbackEdge { driven, detected };
+                    if let Err(e) = send_response(&response, &mut ctx, &mut out_buf) {
+                        defmt::error!("failed to send loopback edge: {}", defmt::Debug2Format(&e));
+                    }
                 }
-                prev_state = this_state;
             }
 
-            let frame = match ctx.local.rx_cons.dequeue() {
+            if trigger_count != prev_trigger_count {
+                indicator.flash(now);
+            }
+            let on = indicator.poll(now);
+            if on != led_on {
+                ctx.shared
+                    .green_led
+                    .lock(|green_led| set_led(green_led, on));
+                led_on = on;
+            }
+
+            if now >= rtc_retry_at && rtc_anchor.is_poll_due(now) {
+                // The RTC's time is latched at the start of the read.
+                let tick = now_ticks();
+                match ctx.local.rtc.read() {
+                    Some(unix_seconds) => rtc_anchor.update(tick, unix_seconds),
+                    None => rtc_retry_at = tick + RTC_RETRY_TICKS,
+                }
+            }
+
+            if let Some(tick) = ctx.shared.resumed_at.lock(|resumed_at| resumed_at.take()) {
+                if let Err(e) = send_response(&FromDevice::Resumed(tick), &mut ctx, &mut out_buf) {
+                    defmt::error!("failed to send resume: {}", defmt::Debug2Format(&e));
+                }
+            }
+
+            if let Some(tick) = ctx.shared.host_connected_at.lock(|at| at.take()) {
+                defmt::info!("host connected at {}", tick);
+                // A ping from a previous host does not anchor this one's
+                // triggers.
+                last_ping = None;
+                if cfg!(feature = "announce-version") {
+                    let response = FromDevice::VersionResponse(version_response());
+                    if let Err(e) = send_response(&response, &mut ctx, &mut out_buf) {
+                        defmt::error!("failed to announce version: {}", defmt::Debug2Format(&e));
+                    }
+                }
+            }
+
+            if let Some(due) = next_beacon.filter(|due| now >= *due) {
+                // Skip beacons missed while the loop was busy rather than
+                // sending them in a burst.
+                let interval = u64::from(config.beacon_interval_ticks);
+                next_beacon = Some(due + interval * ((now - due) / interval + 1));
+                let tick = now_ticks();
+                if let Err(e) = send_response(&FromDevice::Beacon(tick), &mut ctx, &mut out_buf) {
+                    defmt::error!("failed to send beacon: {}", defmt::Debug2Format(&e));
+                }
+            }
+
+            let frame = match leftover.take() {
                 Some(frame) => frame,
-                None => continue,
+                None => match ctx.local.rx_cons.dequeue() {
+                    Some(frame) => frame,
+                    None => continue,
+                },
             };
-            let src = &frame.as_slice();
+            let src = &frame.data.as_slice();
 
-            let ret = match decoder.feed::<ToDevice>(src) {
-                FeedResult::Consumed => None,
-                FeedResult::OverFull(_remaining) => {
+            #[cfg(not(feature = "crc"))]
+            let fed = decoder.feed::<ToDevice>(src);
+            #[cfg(feature = "crc")]
+            let fed = decoder.feed::<Checked<ToDevice>>(src);
+            let (ret, remaining) = match fed {
+                FeedResult::Consumed => (None, &[][..]),
+                FeedResult::OverFull(remaining) => {
                     defmt::error!("frame overflow");
-                    None
+                    (None, remaining)
                 }
-                FeedResult::DeserError(_remaining) => {
+                FeedResult::DeserError(remaining) => {
                     defmt::error!("deserialization");
+                    (None, remaining)
+                }
+                FeedResult::Success { data, remaining } => (Some(data), remaining),
+            };
+            if !remaining.is_empty() {
+                // Another message follows in the same read.
+                leftover = Some(RxFrame {
+                    received: frame.received,
+                    data: UsbFrame::from_slice(remaining).unwrap(),
+                });
+            }
+            #[cfg(feature = "crc")]
+            let ret = match ret {
+                Some(checked) if crc_matches(&checked) => Some(checked.msg),
+                Some(_) => {
+                    defmt::error!("CRC mismatch");
+                    if let Err(e) = send_response(&FromDevice::CrcMismatch, &mut ctx, &mut out_buf)
+                    {
+                        defmt::error!("failed to send CRC mismatch: {}", defmt::Debug2Format(&e));
+                    }
                     None
                 }
-                FeedResult::Success { data, remaining: _ } => Some(data),
+                None => None,
             };
 
             if let Some(request) = ret {
                 let response;
+                let mut enter_bootloader = false;
                 defmt::info!("Request: {:?}", request);
                 match request {
                     ToDevice::Ping => {
-                        let now = monotonics::Monotonic::now().ticks();
-                        response = FromDevice::Pong(now);
+                        let now = now_ticks();
+                        last_ping = Some(frame.received);
+                        response = FromDevice::Pong {
+                            ping_received: frame.received,
+                            sent: now,
+                        };
                         defmt::debug!("device state set");
                     }
                     ToDevice::VersionRequest => {
-                        response = FromDevice::VersionResponse(Default::default());
+                        response = FromDevice::VersionResponse(version_response());
+                    }
+                    ToDevice::ReadPin => {
+                        let high = ctx.shared.trigger_inputs.lock(|inputs| inputs[0].high);
+                        response = FromDevice::PinState { channel: 0, high };
+                    }
+                    ToDevice::SetLed(on) => {
+                        // Applied by the main loop.
+                        indicator.set_host_level(on);
+                        response = FromDevice::Ack;
+                    }
+                    ToDevice::Configure(new_config) => {
+                        // What is ignored is also left out of the stored
+                        // configuration, so that GetConfig reports it unset.
+                        #[cfg(not(feature = "adc"))]
+                        let new_config = {
+                            if new_config.adc.is_some() {
+                                defmt::warn!(
+                                    "built without the adc feature, ignoring analog input"
+                                );
+                            }
+                            DeviceConfig {
+                                adc: None,
+                                ..new_config
+                            }
+                        };
+                        #[cfg(not(feature = "buzzer"))]
+                        let new_config = {
+                            if new_config.buzzer_ticks > 0 {
+                                defmt::warn!("built without the buzzer feature, ignoring buzzer");
+                            }
+                            DeviceConfig {
+                                buzzer_ticks: 0,
+                                ..new_config
+                            }
+                        };
+                        #[cfg(not(feature = "rtc"))]
+                        let new_config = {
+                            if new_config.daily_window.is_some() {
+                                defmt::warn!(
+                                    "built without the rtc feature, ignoring daily window"
+                                );
+                            }
+                            DeviceConfig {
+                                daily_window: None,
+                                ..new_config
+                            }
+                        };
+                        next_beacon = (new_config.beacon_interval_ticks > 0)
+                            .then(|| now_ticks() + u64::from(new_config.beacon_interval_ticks));
+                        threshold_detector = new_config
+                            .adc
+                            .as_ref()
+                            .map(|adc| ThresholdDetector::new(adc.threshold, adc.hysteresis));
+                        min_interval.set_interval(new_config.min_interval_ticks.into());
+                        set_debounce(&mut debouncers, &new_config);
+                        config = new_config;
+                        response = FromDevice::Ack;
+                    }
+                    ToDevice::StatusRequest => {
+                        let now = now_ticks();
+                        let elapsed = (now - last_status_tick).max(1);
+                        let idle_loops_per_second = n_loops * u64::from(TICK_HZ) / elapsed;
+                        response = FromDevice::Status(DeviceStatus {
+                            uptime_ticks: now,
+                            idle_loops_per_second: idle_loops_per_second
+                                .try_into()
+                                .unwrap_or(u32::MAX),
+                            reset_reason: *ctx.local.reset_reason,
+                            stack_free_bytes: stack::free_bytes(),
+                            rx_queue_high_water: ctx
+                                .shared
+                                .rx_high_water
+                                .lock(|high_water| *high_water),
+                            rx_queue_capacity: ctx
+                                .local
+                                .rx_cons
+                                .capacity()
+                                .try_into()
+                                .unwrap_or(u8::MAX),
+                            armed,
+                            suppressed_triggers: min_interval.suppressed(),
+                            outside_window_triggers: outside_window,
+                            line_state: cfg!(feature = "line-coding").then(|| {
+                                ctx.shared.usb_serial.lock(|usb_serial| {
+                                    let coding = usb_serial.line_coding();
+                                    LineState {
+                                        baud: coding.data_rate(),
+                                        data_bits: coding.data_bits(),
+                                        parity: coding.parity_type() as u8,
+                                        stop_bits: coding.stop_bits() as u8,
+                                        dtr: usb_serial.dtr(),
+                                        rts: usb_serial.rts(),
+                                    }
+                                })
+                            }),
+                        });
+                        n_loops = 0;
+                        last_status_tick = now;
+                    }
+                    ToDevice::Echo(payload) => {
+                        response = FromDevice::Echo(payload);
+                    }
+                    ToDevice::GetConfig => {
+                        response = FromDevice::ConfigState(ConfigState {
+                            config: config.clone(),
+                            debounce: debounce_windows(&config),
+                            armed,
+                        });
+                    }
+                    ToDevice::QueryConfig => {
+                        response = FromDevice::BuildConfig(BuildConfig {
+                            trigger_pin: TRIGGER_GPIO,
+                            pull: Pull::Up,
+                            active_high: config.active_high,
+                            debounce_ticks: DEBOUNCE_PRESS_TICKS as u32,
+                            transport: Transport::UsbSerial,
+                            tick_hz: TICK_HZ,
+                            trigger_offset_ticks: trigger_offset,
+                            boots_disarmed: cfg!(feature = "require-arm"),
+                        });
+                    }
+                    ToDevice::SetTriggerOffset(offset_ticks) => {
+                        if offset_ticks != trigger_offset {
+                            calibration::store(offset_ticks);
+                            trigger_offset = offset_ticks;
+                        }
+                        response = FromDevice::Ack;
+                    }
+                    ToDevice::Arm(on) => {
+                        defmt::info!("armed: {}", on);
+                        if on && !armed {
+                            // Forget presses from before arming.
+                            pairer = EdgePairer::new(PAIR_TIMEOUT_TICKS);
+                            first_to_fire = FirstToFireDetector::new(DIFF_TIMEOUT_TICKS);
+                        }
+                        armed = on;
+                        response = FromDevice::Ack;
+                    }
+                    ToDevice::History { max } => {
+                        for entry in history.recent(max.into()) {
+                            let msg = FromDevice::HistoryEntry {
+                                timestamp: entry.timestamp,
+                                sequence: entry.sequence,
+                            };
+                            if let Err(e) = send_response(&msg, &mut ctx, &mut out_buf) {
+                                defmt::error!(
+                                    "failed to send history: {}",
+                                    defmt::Debug2Format(&e)
+                                );
+                            }
+                        }
+                        response = FromDevice::Ack;
+                    }
+                    ToDevice::SetRtc(unix_seconds) => {
+                        if !ctx.local.rtc.set(unix_seconds) {
+                            defmt::error!("failed to set the RTC");
+                        }
+                        // The seconds restarted, so time the next change anew.
+                        rtc_anchor = RtcAnchor::new(RTC_POLL_WINDOW_TICKS);
+                        rtc_retry_at = 0;
+                        response = FromDevice::Ack;
+                    }
+                    ToDevice::EnterBootloader { confirm } => {
+                        if confirm == BOOTLOADER_CONFIRM {
+                            enter_bootloader = true;
+                            response = FromDevice::Ack;
+                        } else {
+                            defmt::error!("bootloader request without confirmation");
+                            response = FromDevice::Rejected;
+                        }
+                    }
+                    ToDevice::LoopbackPulse => {
+                        if loopback_edge.is_some() || !cfg!(feature = "loopback") {
+                            response = FromDevice::Rejected;
+                        } else {
+                            // Driven here rather than by the main loop, so
+                            // that the tick is that of the edge.
+                            let driven = now_ticks();
+                            ctx.local.loopback.set_high(config.active_high);
+                            loopback_high = config.active_high;
+                            loopback_pulse.start(driven, LOOPBACK_PULSE_TICKS);
+                            loopback_edge = Some((driven, None));
+                            response = FromDevice::Ack;
+                        }
+                    }
+                    ToDevice::MarkEpoch => {
+                        response = FromDevice::EpochMark(now_ticks());
+                    }
+                    ToDevice::TestPattern { period_us, count } => {
+                        // Start one period from now, after the acknowledgement.
+                        let now = now_ticks();
+                        let period_ticks = u64::from(period_us.max(1));
+                        test_pattern =
+                            Some(TestPattern::new(now + period_ticks, period_ticks, count));
+                        response = FromDevice::Ack;
                     }
                 }
                 defmt::info!("Response: {:?}", response);
-                send_response(&response, &mut ctx, &mut out_buf);
+                if let Err(e) = send_response(&response, &mut ctx, &mut out_buf) {
+                    defmt::error!("failed to send response: {}", defmt::Debug2Format(&e));
+                }
+                if enter_bootloader {
+                    reboot_to_bootloader();
+                }
             }
         }
     }
 
+    /// Time allowed for the USB interrupt to send what has been written, e.g.
+    /// the acknowledgement of a bootloader request, before rebooting.
+    const BOOTLOADER_FLUSH_TICKS: u64 = 100_000;
+
+    /// Reboot into the USB mass storage bootloader in the RP2040's boot ROM,
+    /// as if BOOTSEL were held at power on, after giving the USB interrupt
+    /// time to send any buffered messages. The ROM's `reset_to_usb_boot`
+    /// function is called with no activity LED and both of its interfaces,
+    /// mass storage and PICOBOOT, enabled. It does not return.
+    fn reboot_to_bootloader() {
+        defmt::info!("rebooting into the bootloader");
+        let start = now_ticks();
+        while now_ticks() - start < BOOTLOADER_FLUSH_TICKS {}
+        hal::rom_data::reset_to_usb_boot(0, 0);
+    }
+
+    /// Determine why the device last reset, from the watchdog's record of
+    /// resets it caused and otherwise the chip-level reset flags.
+    fn read_reset_reason(
+        watchdog: &hal::pac::WATCHDOG,
+        chip_reset: &hal::pac::VREG_AND_CHIP_RESET,
+    ) -> ResetReason {
+        let watchdog_reason = watchdog.reason.read();
+        if watchdog_reason.force().bit_is_set() {
+            return ResetReason::WatchdogForced;
+        }
+        if watchdog_reason.timer().bit_is_set() {
+            return ResetReason::Watchdog;
+        }
+        let chip_reset = chip_reset.chip_reset.read();
+        if chip_reset.had_psm_restart().bit_is_set() {
+            ResetReason::Debugger
+        } else if chip_reset.had_run().bit_is_set() {
+            ResetReason::RunPin
+        } else if chip_reset.had_por().bit_is_set() {
+            ResetReason::PowerOn
+        } else {
+            ResetReason::Unknown
+        }
+    }
+
     /// This function is called from the USB interrupt handler function (which
     /// does not have a return value). By here returning Result, we can abort
     /// processing early using idiomatic rust, even in the interrupt handler
@@ -210,8 +1097,9 @@ mod app {
     #[inline]
     fn on_usb_inner(
         usb_serial: &mut SerialPort<'static, UsbBus>,
-        rx_prod: &mut Producer<'static, UsbFrame, NUM_FRAMES>,
+        rx_prod: &mut Producer<'static, RxFrame, NUM_FRAMES>,
     ) -> Result<usize, ()> {
+        let received = now_ticks();
         let mut new_frame = UsbFrame::new();
         new_frame.resize_default(MAX_FRAME_SZ)?;
         let new_frame_data = new_frame.as_mut_slice();
@@ -219,7 +1107,12 @@ mod app {
         match usb_serial.read(&mut new_frame_data[..]) {
             Ok(sz) => {
                 new_frame.resize_default(sz)?;
-                rx_prod.enqueue(new_frame).map_err(|_e| ())?;
+                rx_prod
+                    .enqueue(RxFrame {
+                        received,
+                        data: new_frame,
+                    })
+                    .map_err(|_e| ())?;
                 Ok(sz)
             }
             Err(usb_device::UsbError::WouldBlock) => Ok(0),
@@ -230,19 +1123,87 @@ mod app {
         }
     }
 
-    #[task(binds=USBCTRL_IRQ, shared = [usb_serial], local=[usb_dev, rx_prod])]
+    /// Capture the tick of each edge of the trigger inputs.
+    ///
+    /// This runs at a higher priority than `on_usb`, so a burst of USB
+    /// traffic cannot delay the capture, and `idle` debounces and reports the
+    /// edge later. `trigger_inputs` is shared only with `idle`, whose brief
+    /// locks (like reading the monotonic anywhere, which is done in a critical
+    /// section) delay this task by at most a few cycles. The tick is read
+    /// first so that it is as close to the edge as possible. Edges on both
+    /// inputs handled in one call get the same tick.
+    #[task(binds = IO_IRQ_BANK0, priority = 2, shared = [trigger_inputs], local = [trigger_pin, second_trigger_pin])]
+    fn on_trigger_edge(mut ctx: on_trigger_edge::Context) {
+        let edge_tick = now_ticks();
+        let trigger_pin = ctx.local.trigger_pin;
+        let second_trigger_pin = ctx.local.second_trigger_pin;
+        let edges = [
+            trigger_pin.interrupt_status(Interrupt::EdgeLow)
+                || trigger_pin.interrupt_status(Interrupt::EdgeHigh),
+            second_trigger_pin.interrupt_status(Interrupt::EdgeLow)
+                || second_trigger_pin.interrupt_status(Interrupt::EdgeHigh),
+        ];
+        trigger_pin.clear_interrupt(Interrupt::EdgeLow);
+        trigger_pin.clear_interrupt(Interrupt::EdgeHigh);
+        second_trigger_pin.clear_interrupt(Interrupt::EdgeLow);
+        second_trigger_pin.clear_interrupt(Interrupt::EdgeHigh);
+        let levels = [
+            trigger_pin.is_high().unwrap(),
+            second_trigger_pin.is_high().unwrap(),
+        ];
+        ctx.shared.trigger_inputs.lock(|inputs| {
+            for ((input, edge), high) in inputs.iter_mut().zip(edges).zip(levels) {
+                if edge {
+                    *input = TriggerInput { high, edge_tick };
+                }
+            }
+        });
+    }
+
+    /// Poll the USB device, queueing received frames for `idle`.
+    ///
+    /// A host opening the serial port asserts DTR, and closing it clears DTR,
+    /// so a rising DTR marks a new connection, which `idle` handles by
+    /// resetting per-connection state and, with the `announce-version`
+    /// feature, sending the version unasked.
+    #[task(binds=USBCTRL_IRQ, shared = [usb_serial, resumed_at, host_connected_at, rx_high_water], local=[usb_dev, rx_prod, was_suspended: bool = false, had_dtr: bool = false])]
     fn on_usb(ctx: on_usb::Context) {
         let mut usb_serial = ctx.shared.usb_serial;
+        let mut resumed_at = ctx.shared.resumed_at;
+        let mut host_connected_at = ctx.shared.host_connected_at;
+        let mut rx_high_water = ctx.shared.rx_high_water;
         let usb_dev = ctx.local.usb_dev;
         let rx_prod = ctx.local.rx_prod;
+        let was_suspended = ctx.local.was_suspended;
+        let had_dtr = ctx.local.had_dtr;
         usb_serial.lock(|usb_serial| {
-            if !usb_dev.poll(&mut [&mut *usb_serial]) {
+            let has_data = usb_dev.poll(&mut [&mut *usb_serial]);
+            let suspended = usb_dev.state() == UsbDeviceState::Suspend;
+            if *was_suspended && !suspended {
+                let now = now_ticks();
+                defmt::info!("USB resumed at {}", now);
+                resumed_at.lock(|resumed_at| *resumed_at = Some(now));
+            }
+            *was_suspended = suspended;
+            let dtr = usb_serial.dtr();
+            if dtr != *had_dtr {
+                if dtr {
+                    let now = now_ticks();
+                    host_connected_at.lock(|at| *at = Some(now));
+                } else {
+                    defmt::info!("host disconnected");
+                }
+                *had_dtr = dtr;
+            }
+            if !has_data {
                 return;
             }
             match on_usb_inner(usb_serial, rx_prod) {
                 Ok(0) => {}
                 Ok(nbytes) => {
                     defmt::trace!("received {} bytes", nbytes);
+                    let queued = rx_prod.len().try_into().unwrap_or(u8::MAX);
+                    rx_high_water.lock(|high_water| *high_water = (*high_water).max(queued));
                 }
                 Err(_) => {
                     defmt::error!("USB error");
@@ -0,0 +1,75 @@
+//! Real-time clock, a DS3231 on I2C1 with SDA on GPIO6 and SCL on GPIO7, read
+//! when built with the `rtc` feature.
+
+#[cfg(feature = "rtc")]
+mod imp {
+    use embedded_hal::blocking::i2c::{Write, WriteRead};
+    use red_button_trigger_timestamp_comms::rtc::{ds3231_registers, ds3231_unix_seconds};
+    use rp2040_hal::{
+        gpio::{
+            bank0::{Gpio6, Gpio7},
+            FunctionI2C, Pin, PullUp,
+        },
+        pac::I2C1,
+        I2C,
+    };
+
+    /// I2C address of the DS3231
+    const ADDRESS: u8 = 0x68;
+
+    pub type Bus = I2C<
+        I2C1,
+        (
+            Pin<Gpio6, FunctionI2C, PullUp>,
+            Pin<Gpio7, FunctionI2C, PullUp>,
+        ),
+    >;
+
+    pub struct Rtc {
+        i2c: Bus,
+    }
+
+    impl Rtc {
+        pub fn new(i2c: Bus) -> Self {
+            Self { i2c }
+        }
+
+        /// Read the time in seconds since the Unix epoch, or return `None` if
+        /// the RTC does not answer or does not hold a valid time.
+        pub fn read(&mut self) -> Option<i64> {
+            let mut regs = [0u8; 7];
+            self.i2c.write_read(ADDRESS, &[0x00], &mut regs).ok()?;
+            ds3231_unix_seconds(&regs)
+        }
+
+        /// Set the time to the start of second `unix_seconds` since the Unix
+        /// epoch, returning whether it was set.
+        pub fn set(&mut self, unix_seconds: i64) -> bool {
+            let Some(regs) = ds3231_registers(unix_seconds) else {
+                return false;
+            };
+            // Register address 0, then the registers from there.
+            let mut buf = [0u8; 8];
+            buf[1..].copy_from_slice(&regs);
+            self.i2c.write(ADDRESS, &buf).is_ok()
+        }
+    }
+}
+
+#[cfg(not(feature = "rtc"))]
+mod imp {
+    /// Stand-in for firmware built without the `rtc` feature.
+    pub struct Rtc;
+
+    impl Rtc {
+        pub fn read(&mut self) -> Option<i64> {
+            None
+        }
+
+        pub fn set(&mut self, _unix_seconds: i64) -> bool {
+            false
+        }
+    }
+}
+
+pub use imp::Rtc;
@@ -0,0 +1,43 @@
+//! Stack usage measurement. The unused stack is filled with a pattern at
+//! startup, and the deepest the stack has reached is later found as the
+//! lowest word no longer holding the pattern.
+
+/// Pattern written to unused stack.
+const PAINT: u32 = 0xcccc_cccc;
+
+/// Bytes just below the stack pointer left unpainted, for the frame of
+/// [paint] itself.
+const MARGIN: usize = 64;
+
+extern "C" {
+    /// End of static data and so the lowest address the stack can reach,
+    /// defined by the `cortex-m-rt` linker script.
+    static mut __sheap: u32;
+}
+
+/// Fill the stack below the current stack pointer with the pattern. Must be
+/// called before interrupts are enabled.
+pub fn paint() {
+    let bottom = unsafe { core::ptr::addr_of_mut!(__sheap) };
+    let top = (cortex_m::register::msp::read() as usize - MARGIN) as *mut u32;
+    let mut word = bottom;
+    while word < top {
+        unsafe {
+            word.write_volatile(PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// The least free stack, in bytes, there has been since [paint]. This reads
+/// every word of the never used stack, so takes around a millisecond when
+/// most of RAM is free.
+pub fn free_bytes() -> u32 {
+    let bottom = unsafe { core::ptr::addr_of!(__sheap) };
+    let mut word = bottom;
+    // The stack in use never holds only the pattern, so this stops within it.
+    while unsafe { word.read_volatile() } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+    (word as usize - bottom as usize) as u32
+}
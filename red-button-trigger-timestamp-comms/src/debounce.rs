@@ -0,0 +1,157 @@
+/// Rejects glitches on a digital input whose edges are timestamped as they
+/// occur. An edge is reported once the new level has persisted, without a
+/// further edge, for the minimum pulse of that level, which may differ for
+/// the two levels, e.g. for a button which bounces longer on release than on
+/// press.
+///
+/// The reported tick is that of the edge which started the level which
+/// persisted, i.e. the last bounce of a bouncing contact. The first edge of
+/// the change, before any bounce, is kept for [Debouncer::raw_edge_tick].
+pub struct Debouncer {
+    level: bool,
+    /// Minimum duration of a low and of a high level
+    min_pulse_ticks: [u64; 2],
+    /// A level change which has not yet persisted, with the tick of its edge
+    pending: Option<(bool, u64)>,
+    /// Tick of the first edge away from `level` since it was last steady
+    first_edge: Option<u64>,
+    /// Tick of the first edge of the most recently reported change
+    raw_edge: u64,
+}
+
+impl Debouncer {
+    pub const fn new(level: bool, min_pulse_ticks: u64) -> Self {
+        Self {
+            level,
+            min_pulse_ticks: [min_pulse_ticks; 2],
+            pending: None,
+            first_edge: None,
+            raw_edge: 0,
+        }
+    }
+
+    /// Require a low level to persist for `low_ticks` and a high level for
+    /// `high_ticks` before reporting the edge to it.
+    pub fn set_min_pulse_ticks(&mut self, low_ticks: u64, high_ticks: u64) {
+        self.min_pulse_ticks = [low_ticks, high_ticks];
+    }
+
+    /// Handle the input's `level` and the tick `edge_tick` of its most recent
+    /// edge, as seen at tick `now`. Returns the new level and the tick of its
+    /// edge once the level has persisted.
+    pub fn update(&mut self, level: bool, edge_tick: u64, now: u64) -> Option<(bool, u64)> {
+        match self.pending {
+            None => {
+                if level != self.level {
+                    self.first_edge.get_or_insert(edge_tick);
+                    self.pending = Some((level, edge_tick));
+                } else if now.saturating_sub(edge_tick) >= self.min_pulse_ticks[usize::from(level)]
+                {
+                    // Steady again, so earlier edges were glitches rather
+                    // than the start of a change.
+                    self.first_edge = None;
+                }
+                None
+            }
+            // A further edge, even if the level has since returned, is a
+            // glitch.
+            Some((pending_level, pending_tick))
+                if level != pending_level || edge_tick != pending_tick =>
+            {
+                self.pending = None;
+                None
+            }
+            Some((pending_level, pending_tick)) => {
+                if now.saturating_sub(pending_tick)
+                    < self.min_pulse_ticks[usize::from(pending_level)]
+                {
+                    return None;
+                }
+                self.pending = None;
+                self.level = pending_level;
+                self.raw_edge = self.first_edge.take().unwrap_or(pending_tick);
+                Some((pending_level, pending_tick))
+            }
+        }
+    }
+
+    /// The tick of the first edge of the change most recently reported by
+    /// [Debouncer::update], before any bounce. The difference from the
+    /// reported tick is the duration of the bounce, as seen by polling.
+    pub fn raw_edge_tick(&self) -> u64 {
+        self.raw_edge
+    }
+}
+
+#[test]
+fn test_debouncer() {
+    let mut debouncer = Debouncer::new(true, 5);
+    assert_eq!(debouncer.update(true, 0, 10), None);
+
+    // A low pulse of 2 ticks is a glitch.
+    assert_eq!(debouncer.update(false, 20, 21), None);
+    assert_eq!(debouncer.update(true, 22, 23), None);
+    assert_eq!(debouncer.update(true, 22, 40), None);
+
+    // So is a pulse which returns high between polls.
+    assert_eq!(debouncer.update(false, 50, 51), None);
+    assert_eq!(debouncer.update(false, 53, 60), None);
+
+    // A low level which persists is reported at the tick of its edge.
+    assert_eq!(debouncer.update(false, 70, 71), None);
+    assert_eq!(debouncer.update(false, 70, 74), None);
+    assert_eq!(debouncer.update(false, 70, 75), Some((false, 70)));
+    assert_eq!(debouncer.update(false, 70, 100), None);
+}
+
+#[test]
+fn test_asymmetric_debouncer() {
+    // An active low button: the press (to low) is clean, the release (to
+    // high) bounces for up to 20 ticks.
+    let mut debouncer = Debouncer::new(true, 5);
+    debouncer.set_min_pulse_ticks(5, 30);
+
+    // The press is reported after 5 ticks.
+    assert_eq!(debouncer.update(false, 100, 101), None);
+    assert_eq!(debouncer.update(false, 100, 105), Some((false, 100)));
+
+    // Release bounce: high for 10 ticks, which would pass a 5 tick window,
+    // then low again, is rejected.
+    assert_eq!(debouncer.update(true, 200, 201), None);
+    assert_eq!(debouncer.update(true, 200, 210), None);
+    assert_eq!(debouncer.update(false, 212, 213), None);
+    // The final high edge is reported once it has persisted for 30 ticks.
+    assert_eq!(debouncer.update(true, 220, 221), None);
+    assert_eq!(debouncer.update(true, 220, 249), None);
+    assert_eq!(debouncer.update(true, 220, 250), Some((true, 220)));
+
+    // A press still needs only 5 ticks.
+    assert_eq!(debouncer.update(false, 300, 301), None);
+    assert_eq!(debouncer.update(false, 300, 305), Some((false, 300)));
+}
+
+#[test]
+fn test_raw_edge_tick() {
+    let mut debouncer = Debouncer::new(true, 5);
+
+    // A press which bounces from tick 100 until it settles at tick 106.
+    assert_eq!(debouncer.update(false, 100, 101), None);
+    assert_eq!(debouncer.update(true, 102, 103), None);
+    assert_eq!(debouncer.update(true, 102, 104), None);
+    assert_eq!(debouncer.update(false, 106, 107), None);
+    assert_eq!(debouncer.update(false, 106, 111), Some((false, 106)));
+    assert_eq!(debouncer.raw_edge_tick(), 100);
+
+    // A clean release has no bounce.
+    assert_eq!(debouncer.update(true, 200, 201), None);
+    assert_eq!(debouncer.update(true, 200, 205), Some((true, 200)));
+    assert_eq!(debouncer.raw_edge_tick(), 200);
+
+    // A glitch which settles back is not the start of the next press.
+    assert_eq!(debouncer.update(false, 300, 301), None);
+    assert_eq!(debouncer.update(true, 302, 303), None);
+    assert_eq!(debouncer.update(true, 302, 310), None);
+    assert_eq!(debouncer.update(false, 400, 401), None);
+    assert_eq!(debouncer.update(false, 400, 405), Some((false, 400)));
+    assert_eq!(debouncer.raw_edge_tick(), 400);
+}
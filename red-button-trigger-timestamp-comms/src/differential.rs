@@ -0,0 +1,125 @@
+/// Which of two inputs fired first, and by how much.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirstToFire {
+    /// Channel which fired first, arbitrarily 0 if both fired at once
+    pub first_channel: u8,
+    /// Tick at which the first channel fired
+    pub first_tick: u64,
+    /// Ticks until the other channel fired, if it did in time
+    pub delta_ticks: Option<u64>,
+}
+
+impl FirstToFire {
+    /// Whether both channels fired within the resolution of the clock, so
+    /// that the order is unknown.
+    pub fn simultaneous(&self) -> bool {
+        self.delta_ticks == Some(0)
+    }
+}
+
+/// Determines which of two inputs, channels 0 and 1, fires first. If the other
+/// channel does not fire within `timeout_ticks`, the first is reported alone.
+pub struct FirstToFireDetector {
+    timeout_ticks: u64,
+    /// Channel and tick of a first firing whose counterpart is awaited
+    pending: Option<(u8, u64)>,
+}
+
+impl FirstToFireDetector {
+    pub const fn new(timeout_ticks: u64) -> Self {
+        Self {
+            timeout_ticks,
+            pending: None,
+        }
+    }
+
+    /// Handle `channel` firing at `tick`. Returns the result once the other
+    /// channel has also fired. Repeated firings of the first channel are
+    /// ignored.
+    pub fn fire(&mut self, channel: u8, tick: u64) -> Option<FirstToFire> {
+        match self.pending {
+            None => {
+                self.pending = Some((channel, tick));
+                None
+            }
+            Some((first_channel, _)) if first_channel == channel => None,
+            Some((pending_channel, pending_tick)) => {
+                self.pending = None;
+                let delta_ticks = tick.abs_diff(pending_tick);
+                // Debouncing may confirm the edges out of order.
+                let (first_channel, first_tick) = if delta_ticks == 0 {
+                    (0, tick)
+                } else if tick < pending_tick {
+                    (channel, tick)
+                } else {
+                    (pending_channel, pending_tick)
+                };
+                Some(FirstToFire {
+                    first_channel,
+                    first_tick,
+                    delta_ticks: Some(delta_ticks),
+                })
+            }
+        }
+    }
+
+    /// Return the pending firing alone if its counterpart has not fired by
+    /// tick `now`.
+    pub fn poll(&mut self, now: u64) -> Option<FirstToFire> {
+        match self.pending {
+            Some((first_channel, first_tick))
+                if now.saturating_sub(first_tick) >= self.timeout_ticks =>
+            {
+                self.pending = None;
+                Some(FirstToFire {
+                    first_channel,
+                    first_tick,
+                    delta_ticks: None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_first_to_fire() {
+    let mut detector = FirstToFireDetector::new(100);
+
+    // Channel 1 first, with a repeat of channel 1 ignored.
+    assert_eq!(detector.fire(1, 10), None);
+    assert_eq!(detector.fire(1, 12), None);
+    let result = detector.fire(0, 25).unwrap();
+    assert_eq!(
+        result,
+        FirstToFire {
+            first_channel: 1,
+            first_tick: 10,
+            delta_ticks: Some(15),
+        }
+    );
+    assert!(!result.simultaneous());
+
+    // Confirmed out of order.
+    assert_eq!(detector.fire(0, 210), None);
+    assert_eq!(detector.fire(1, 200).unwrap().first_channel, 1);
+
+    // Within the resolution of the clock.
+    assert_eq!(detector.fire(1, 300), None);
+    let result = detector.fire(0, 300).unwrap();
+    assert_eq!(result.first_channel, 0);
+    assert!(result.simultaneous());
+
+    // The other channel never fires.
+    assert_eq!(detector.fire(0, 400), None);
+    assert_eq!(detector.poll(499), None);
+    assert_eq!(
+        detector.poll(500),
+        Some(FirstToFire {
+            first_channel: 0,
+            first_tick: 400,
+            delta_ticks: None,
+        })
+    );
+    assert_eq!(detector.poll(600), None);
+}
@@ -0,0 +1,130 @@
+use crate::FromDevice;
+
+/// A reported trigger, as kept in a [TriggerHistory].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub sequence: u32,
+}
+
+impl HistoryEntry {
+    /// The entry for a trigger message, or `None` for other messages and
+    /// synthetic triggers. Paired and differential triggers are kept by the
+    /// tick of their first edge.
+    pub fn of(msg: &FromDevice) -> Option<Self> {
+        let (timestamp, sequence) = match *msg {
+            FromDevice::Trigger {
+                timestamp,
+                sequence,
+                test: false,
+                ..
+            } => (timestamp, sequence),
+            FromDevice::TriggerPair {
+                press_tick,
+                sequence,
+                ..
+            } => (press_tick, sequence),
+            FromDevice::TriggerDiff {
+                timestamp,
+                sequence,
+                ..
+            } => (timestamp, sequence),
+            _ => return None,
+        };
+        Some(Self {
+            timestamp,
+            sequence,
+        })
+    }
+}
+
+/// The most recent `N` triggers, oldest overwritten first.
+pub struct TriggerHistory<const N: usize> {
+    entries: [HistoryEntry; N],
+    /// Index at which the next entry is written
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> TriggerHistory<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: [HistoryEntry {
+                timestamp: 0,
+                sequence: 0,
+            }; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// The most recent `max` entries, oldest first.
+    pub fn recent(&self, max: usize) -> impl Iterator<Item = HistoryEntry> + '_ {
+        let n = self.len.min(max);
+        (0..n).map(move |i| self.entries[(self.next + N - n + i) % N])
+    }
+}
+
+impl<const N: usize> Default for TriggerHistory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_trigger_history() {
+    let entry = |sequence| HistoryEntry {
+        timestamp: u64::from(sequence) * 10,
+        sequence,
+    };
+    let sequences_eq = |history: &TriggerHistory<3>, max, expected: &[u32]| {
+        history
+            .recent(max)
+            .map(|e| e.sequence)
+            .eq(expected.iter().copied())
+    };
+
+    let mut history = TriggerHistory::<3>::new();
+    assert!(sequences_eq(&history, 10, &[]));
+    history.push(entry(1));
+    history.push(entry(2));
+    assert!(sequences_eq(&history, 10, &[1, 2]));
+    assert!(sequences_eq(&history, 1, &[2]));
+    // The oldest entries are overwritten.
+    history.push(entry(3));
+    history.push(entry(4));
+    history.push(entry(5));
+    assert!(sequences_eq(&history, 10, &[3, 4, 5]));
+    assert!(sequences_eq(&history, 2, &[4, 5]));
+    assert!(sequences_eq(&history, 0, &[]));
+
+    let test_trigger = FromDevice::Trigger {
+        timestamp: 1,
+        last_ping: None,
+        sequence: 1,
+        level: None,
+        test: true,
+        rtc_unix_micros: None,
+    };
+    assert_eq!(HistoryEntry::of(&test_trigger), None);
+    let pair = FromDevice::TriggerPair {
+        press_tick: 7,
+        release_tick: Some(9),
+        last_ping: None,
+        sequence: 2,
+        rtc_unix_micros: None,
+    };
+    assert_eq!(
+        HistoryEntry::of(&pair),
+        Some(HistoryEntry {
+            timestamp: 7,
+            sequence: 2
+        })
+    );
+}
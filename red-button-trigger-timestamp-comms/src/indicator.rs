@@ -0,0 +1,89 @@
+use crate::pulse::PulseTimer;
+
+/// Level of an indicator LED, which shows, in order of precedence:
+///
+/// 1. a brief flash at each trigger, inverting whatever the LED would
+///    otherwise show, so that it is visible in any state,
+/// 2. the level set by the host, if on,
+/// 3. a heartbeat, a brief blink at a fixed period, showing that the
+///    firmware is running, if enabled.
+pub struct Indicator {
+    host_on: bool,
+    flash: PulseTimer,
+    flash_ticks: u64,
+    /// Period of the heartbeat and the duration of each blink, in ticks
+    heartbeat: Option<(u64, u64)>,
+}
+
+impl Indicator {
+    /// An indicator which flashes for `flash_ticks` at each trigger, without a
+    /// heartbeat.
+    pub const fn new(flash_ticks: u64) -> Self {
+        Self {
+            host_on: false,
+            flash: PulseTimer::new(),
+            flash_ticks,
+            heartbeat: None,
+        }
+    }
+
+    /// Blink for `on_ticks` at the start of every `period_ticks`, which must
+    /// be nonzero.
+    pub const fn with_heartbeat(mut self, period_ticks: u64, on_ticks: u64) -> Self {
+        assert!(period_ticks > 0);
+        self.heartbeat = Some((period_ticks, on_ticks));
+        self
+    }
+
+    /// Light the LED steadily (`true`), or leave it to the heartbeat
+    /// (`false`).
+    pub fn set_host_level(&mut self, on: bool) {
+        self.host_on = on;
+    }
+
+    /// Flash for a trigger at tick `now`.
+    pub fn flash(&mut self, now: u64) {
+        self.flash.start(now, self.flash_ticks);
+    }
+
+    /// Return whether the LED should be lit at tick `now`.
+    pub fn poll(&mut self, now: u64) -> bool {
+        let heartbeat = self
+            .heartbeat
+            .is_some_and(|(period_ticks, on_ticks)| now % period_ticks < on_ticks);
+        (self.host_on || heartbeat) != self.flash.poll(now)
+    }
+}
+
+#[test]
+fn test_indicator() {
+    let mut indicator = Indicator::new(100).with_heartbeat(1000, 50);
+    // The heartbeat blinks at the start of each period.
+    assert!(indicator.poll(0));
+    assert!(indicator.poll(49));
+    assert!(!indicator.poll(50));
+    assert!(indicator.poll(1020));
+
+    // A trigger flash overrides the heartbeat, lighting the LED between
+    // blinks and darkening it during one.
+    indicator.flash(1990);
+    assert!(indicator.poll(1995));
+    assert!(!indicator.poll(2000));
+    assert!(!indicator.poll(2049));
+    assert!(indicator.poll(2060));
+    assert!(!indicator.poll(2090));
+
+    // Lit by the host, the LED is steady, and a flash darkens it.
+    indicator.set_host_level(true);
+    assert!(indicator.poll(2500));
+    indicator.flash(2600);
+    assert!(!indicator.poll(2650));
+    assert!(indicator.poll(2700));
+
+    // Without a heartbeat, the LED is dark but for flashes.
+    let mut indicator = Indicator::new(100);
+    assert!(!indicator.poll(0));
+    indicator.flash(10);
+    assert!(indicator.poll(50));
+    assert!(!indicator.poll(110));
+}
@@ -4,14 +4,119 @@
 extern crate alloc;
 use serde::{Deserialize, Serialize};
 
+pub mod crc;
+pub mod debounce;
+pub mod differential;
+pub mod history;
+pub mod indicator;
+pub mod min_interval;
+pub mod pairing;
+pub mod pulse;
+pub mod rtc;
+pub mod test_pattern;
+pub mod threshold;
+pub mod tick_scale;
+
 pub const COMMS_NAME: &[u8; 11] = b"triggertime";
-pub const COMM_VERSION: u16 = 1;
+pub const COMM_VERSION: u16 = 36;
+
+/// Upper bound on the length of an encoded [ToDevice] message, including the
+/// trailing newline and any CRC framing (see [crc]). Receive buffers on the
+/// device must be at least this large.
+pub const MAX_TO_DEVICE_LEN: usize = 448;
+
+/// Upper bound on the length of an encoded [FromDevice] message, including
+/// the trailing newline and any CRC framing. Send buffers on the device must
+/// be at least this large.
+pub const MAX_FROM_DEVICE_LEN: usize = 512;
+
+/// Value of [ToDevice::EnterBootloader::confirm] without which the device
+/// does not enter its bootloader, so that a corrupted or mistaken request
+/// cannot end a recording.
+pub const BOOTLOADER_CONFIRM: u32 = 0x424f_4f54;
+
+/// USB vendor and product IDs with which the firmware enumerates, so that the
+/// host can pick out the device among other serial ports.
+pub const USB_VID_PID: (u16, u16) = (0x16c0, 0x27dd);
+
+/// Length of the payload of [ToDevice::Echo].
+pub const ECHO_LEN: usize = 16;
+
+/// Bits of [VersionResponse::capabilities]. Optional features of the
+/// firmware are advertised so that the host can avoid commands the firmware
+/// cannot honour.
+pub mod capabilities {
+    /// [ToDevice::QueryConfig](super::ToDevice::QueryConfig) is supported.
+    pub const BUILD_CONFIG: u32 = 1 << 0;
+    /// [ToDevice::StatusRequest](super::ToDevice::StatusRequest) is supported.
+    pub const STATUS: u32 = 1 << 1;
+    /// [ToDevice::Echo](super::ToDevice::Echo) is supported.
+    pub const ECHO: u32 = 1 << 2;
+    /// [DeviceConfig::pair_edges](super::DeviceConfig::pair_edges) is
+    /// supported.
+    pub const PAIR_EDGES: u32 = 1 << 3;
+    /// [DeviceConfig::adc](super::DeviceConfig::adc) is supported.
+    pub const ANALOG: u32 = 1 << 4;
+    /// [FromDevice::Resumed](super::FromDevice::Resumed) is sent after USB
+    /// suspend.
+    pub const RESUME_NOTIFY: u32 = 1 << 5;
+    /// [ToDevice::SetTriggerOffset](super::ToDevice::SetTriggerOffset) is
+    /// supported.
+    pub const CALIBRATION: u32 = 1 << 6;
+    /// [DeviceConfig::buzzer_ticks](super::DeviceConfig::buzzer_ticks) is
+    /// supported.
+    pub const BUZZER: u32 = 1 << 7;
+    /// [DeviceConfig::differential](super::DeviceConfig::differential) is
+    /// supported.
+    pub const DIFFERENTIAL: u32 = 1 << 8;
+    /// [ToDevice::TestPattern](super::ToDevice::TestPattern) is supported.
+    pub const TEST_PATTERN: u32 = 1 << 9;
+    /// [DeviceConfig::beacon_interval_ticks](super::DeviceConfig::beacon_interval_ticks)
+    /// is supported.
+    pub const BEACON: u32 = 1 << 10;
+    /// Messages are framed with a CRC (see [crc](super::crc)).
+    pub const CRC: u32 = 1 << 11;
+    /// [ToDevice::Arm](super::ToDevice::Arm) is supported.
+    pub const ARM: u32 = 1 << 12;
+    /// [ToDevice::History](super::ToDevice::History) is supported.
+    pub const HISTORY: u32 = 1 << 13;
+    /// [DeviceConfig::min_interval_ticks](super::DeviceConfig::min_interval_ticks)
+    /// is supported.
+    pub const MIN_INTERVAL: u32 = 1 << 14;
+    /// Triggers include the time of a real-time clock, and
+    /// [ToDevice::SetRtc](super::ToDevice::SetRtc) and
+    /// [DeviceConfig::daily_window](super::DeviceConfig::daily_window) are
+    /// supported.
+    pub const RTC: u32 = 1 << 15;
+    /// [ToDevice::EnterBootloader](super::ToDevice::EnterBootloader) is
+    /// supported.
+    pub const BOOTLOADER: u32 = 1 << 16;
+    /// [DeviceConfig::debounce](super::DeviceConfig::debounce) is supported.
+    pub const DEBOUNCE: u32 = 1 << 17;
+    /// [ToDevice::GetConfig](super::ToDevice::GetConfig) is supported.
+    pub const GET_CONFIG: u32 = 1 << 18;
+    /// [ToDevice::MarkEpoch](super::ToDevice::MarkEpoch) is supported.
+    pub const EPOCH_MARK: u32 = 1 << 19;
+    /// [ToDevice::LoopbackPulse](super::ToDevice::LoopbackPulse) is
+    /// supported.
+    pub const LOOPBACK: u32 = 1 << 20;
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
 pub struct VersionResponse {
     pub name: [u8; 11],
     pub version: u16,
+    /// Bitmask of [capabilities] supported by the firmware. The default is
+    /// none.
+    pub capabilities: u32,
+}
+
+impl VersionResponse {
+    /// Whether all bits of `capability` are set in [Self::capabilities].
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
 }
 
 impl Default for VersionResponse {
@@ -19,16 +124,304 @@ impl Default for VersionResponse {
         Self {
             name: *COMMS_NAME,
             version: COMM_VERSION,
+            capabilities: 0,
         }
     }
 }
 
+/// Analog trigger input settings. A trigger occurs when the ADC reading
+/// rises to `threshold`, and the input is re-armed once the reading falls
+/// below `threshold - hysteresis`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct AdcConfig {
+    pub channel: u8,
+    pub threshold: u16,
+    pub hysteresis: u16,
+}
+
+/// Debounce windows, in device ticks, for which the new level of a press and
+/// of a release must persist before the edge is reported. A button often
+/// bounces for longer on release than on press.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct Debounce {
+    pub press_ticks: u32,
+    pub release_ticks: u32,
+}
+
+/// Runtime configuration of the device, set with [ToDevice::Configure].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct DeviceConfig {
+    /// Whether a trigger is a transition to high (rather than to low). The
+    /// default, active low, suits a button which shorts the pulled-up input to
+    /// ground.
+    pub active_high: bool,
+    /// Whether triggers include the tick at which the most recent ping was
+    /// received.
+    pub report_last_ping: bool,
+    /// Whether each press is paired with the following release and reported
+    /// as [FromDevice::TriggerPair], rather than as [FromDevice::Trigger].
+    pub pair_edges: bool,
+    /// Analog trigger input, in addition to the digital one. Requires firmware
+    /// built with the `adc` feature.
+    pub adc: Option<AdcConfig>,
+    /// Duration, in device ticks, of a pulse on the buzzer output at each
+    /// trigger, or zero for none. Requires firmware built with the `buzzer`
+    /// feature.
+    pub buzzer_ticks: u32,
+    /// Whether presses on the two digital trigger inputs are combined and
+    /// reported as [FromDevice::TriggerDiff], rather than only the first input
+    /// triggering.
+    pub differential: bool,
+    /// Interval, in device ticks, at which [FromDevice::Beacon] is sent, or
+    /// zero for none.
+    pub beacon_interval_ticks: u32,
+    /// Minimum interval, in device ticks, from one reported trigger to the
+    /// next, or zero for none. Triggers within it are suppressed and counted
+    /// in [DeviceStatus::suppressed_triggers].
+    pub min_interval_ticks: u32,
+    /// Daily window of UTC time of day outside which triggers are suppressed
+    /// and counted in [DeviceStatus::outside_window_triggers], or `None` to
+    /// report triggers at any time. Requires a real-time clock (see
+    /// [capabilities::RTC]); until its time is known, every trigger is
+    /// reported.
+    pub daily_window: Option<rtc::DailyWindow>,
+    /// Debounce windows of presses and releases, or `None` for those the
+    /// firmware was built with (see [BuildConfig::debounce_ticks]).
+    pub debounce: Option<Debounce>,
+}
+
+/// Runtime configuration in effect on the device, sent in reply to
+/// [ToDevice::GetConfig], so that a host can confirm that its
+/// [ToDevice::Configure] took effect, or find the configuration left by a
+/// previous host.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct ConfigState {
+    /// The configuration most recently set with [ToDevice::Configure], or
+    /// the default if none has been since boot.
+    pub config: DeviceConfig,
+    /// Debounce windows in effect, whether configured or built in.
+    pub debounce: Debounce,
+    /// Whether triggers are reported (see [ToDevice::Arm]).
+    pub armed: bool,
+}
+
+/// Internal resistor on the trigger input.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub enum Pull {
+    Up,
+    Down,
+    Floating,
+}
+
+/// Link over which the device communicates with the host.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub enum Transport {
+    UsbSerial,
+}
+
+/// Settings the firmware was built with, sent in reply to
+/// [ToDevice::QueryConfig].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct BuildConfig {
+    /// GPIO number of the trigger input
+    pub trigger_pin: u8,
+    pub pull: Pull,
+    /// Trigger polarity in effect, which is the built-in default unless
+    /// changed with [ToDevice::Configure].
+    pub active_high: bool,
+    /// Minimum duration, in device ticks, of a press which is not rejected as
+    /// a glitch, as built in. See [DeviceConfig::debounce].
+    pub debounce_ticks: u32,
+    pub transport: Transport,
+    /// Frequency of the device clock in which timestamps are given
+    pub tick_hz: u32,
+    /// Latency, in device ticks, subtracted from the tick of each trigger, as
+    /// stored with [ToDevice::SetTriggerOffset].
+    pub trigger_offset_ticks: u32,
+    /// Whether the device starts disarmed after a reset, reporting no
+    /// triggers until sent [ToDevice::Arm].
+    pub boots_disarmed: bool,
+}
+
+/// Cause of the most recent reset of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub enum ResetReason {
+    /// Power was applied, or the supply dropped out.
+    PowerOn,
+    /// The RUN pin was pulled low, e.g. by a reset button.
+    RunPin,
+    /// A debugger reset the device.
+    Debugger,
+    /// The watchdog timer expired, meaning the firmware hung.
+    Watchdog,
+    /// The firmware deliberately reset itself with the watchdog.
+    WatchdogForced,
+    /// No cause was recorded.
+    Unknown,
+}
+
+/// Health of the device, sent in reply to [ToDevice::StatusRequest].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct DeviceStatus {
+    /// Device tick at which the status was sent
+    pub uptime_ticks: u64,
+    /// Iterations of the firmware's main loop per second since the previous
+    /// status. A drop indicates that the loop, which polls the trigger input,
+    /// was blocked.
+    pub idle_loops_per_second: u32,
+    pub reset_reason: ResetReason,
+    /// Least free stack, in bytes, there has been since boot
+    pub stack_free_bytes: u32,
+    /// Most frames there have been in the receive queue at once since boot
+    pub rx_queue_high_water: u8,
+    /// Number of frames the receive queue holds
+    pub rx_queue_capacity: u8,
+    /// Whether triggers are reported (see [ToDevice::Arm])
+    pub armed: bool,
+    /// Triggers suppressed by [DeviceConfig::min_interval_ticks] since boot
+    pub suppressed_triggers: u32,
+    /// Triggers suppressed by [DeviceConfig::daily_window] since boot
+    pub outside_window_triggers: u32,
+    /// The serial settings of the host, from firmware built with the
+    /// `line-coding` feature
+    pub line_state: Option<LineState>,
+}
+
+/// The USB CDC line coding and control lines as last set by the host. USB CDC
+/// ignores the line coding, so it matters only for diagnosing how the host has
+/// configured the serial port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct LineState {
+    pub baud: u32,
+    pub data_bits: u8,
+    /// USB CDC `bParityType`: 0 none, 1 odd, 2 even, 3 mark, 4 space
+    pub parity: u8,
+    /// USB CDC `bCharFormat`: 0 one, 1 one and a half, 2 two stop bits
+    pub stop_bits: u8,
+    pub dtr: bool,
+    pub rts: bool,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
 pub enum FromDevice {
-    Pong(u64),
-    Trigger(u64),
+    /// Reply to [ToDevice::Ping] with the device ticks at which the ping was
+    /// received and at which the reply was sent.
+    Pong {
+        ping_received: u64,
+        sent: u64,
+    },
+    /// A trigger occurred at device tick `timestamp`. `last_ping` is the tick
+    /// at which the most recent ping was received, if enabled with
+    /// [DeviceConfig::report_last_ping]. `sequence` counts triggers since
+    /// boot, starting at 1, so that lost messages can be detected. `level` is
+    /// the ADC reading for a trigger from the analog input. `test` is set for
+    /// synthetic triggers requested with [ToDevice::TestPattern].
+    /// `rtc_unix_micros` is the time of the trigger, in microseconds since the
+    /// Unix epoch, according to a real-time clock on the device, if there is
+    /// one (see [capabilities::RTC]).
+    Trigger {
+        timestamp: u64,
+        last_ping: Option<u64>,
+        sequence: u32,
+        level: Option<u16>,
+        test: bool,
+        rtc_unix_micros: Option<i64>,
+    },
+    /// A press at device tick `press_tick`, with the following release if it
+    /// occurred before a timeout. Other fields are as for
+    /// [FromDevice::Trigger].
+    TriggerPair {
+        press_tick: u64,
+        release_tick: Option<u64>,
+        last_ping: Option<u64>,
+        sequence: u32,
+        rtc_unix_micros: Option<i64>,
+    },
+    /// With [DeviceConfig::differential], a press on both trigger inputs.
+    /// `first_channel` fired first, at device tick `timestamp`, and the other
+    /// fired `delta_ticks` later, or not within a timeout. `simultaneous` is
+    /// set if both fired within the resolution of the clock, when
+    /// `first_channel` is 0 and `delta_ticks` is zero. Other fields are as
+    /// for [FromDevice::Trigger].
+    TriggerDiff {
+        first_channel: u8,
+        timestamp: u64,
+        delta_ticks: Option<u64>,
+        simultaneous: bool,
+        last_ping: Option<u64>,
+        sequence: u32,
+        rtc_unix_micros: Option<i64>,
+    },
     VersionResponse(VersionResponse),
+    /// Instantaneous level of a trigger input, sent in reply to
+    /// [ToDevice::ReadPin].
+    PinState {
+        channel: u8,
+        high: bool,
+    },
+    /// Acknowledges a command which has no other reply.
+    Ack,
+    BuildConfig(BuildConfig),
+    /// The USB bus resumed from suspend at the given device tick. The device
+    /// clock kept running, but the host's clock model may be stale.
+    Resumed(u64),
+    /// Reply to [ToDevice::Echo] with the same payload.
+    Echo([u8; ECHO_LEN]),
+    Status(DeviceStatus),
+    ConfigState(ConfigState),
+    /// The device tick at which the message was sent, sent unprompted every
+    /// [DeviceConfig::beacon_interval_ticks].
+    Beacon(u64),
+    /// A message from the host failed its CRC check (see [crc]) and was
+    /// discarded.
+    CrcMismatch,
+    /// A well-formed request was refused, e.g. [ToDevice::EnterBootloader]
+    /// without its confirmation.
+    Rejected,
+    /// A trigger recorded by the device, sent in reply to
+    /// [ToDevice::History]. `timestamp` is the tick of the trigger, or of its
+    /// first edge, and `sequence` is as for [FromDevice::Trigger].
+    HistoryEntry {
+        timestamp: u64,
+        sequence: u32,
+    },
+    /// The device tick at which it was sent, in reply to
+    /// [ToDevice::MarkEpoch]. Every host reading the device's messages
+    /// receives it, so each can record the same device instant against its
+    /// own clock.
+    EpochMark(u64),
+    /// From firmware built with the `raw-edges` feature, a debounced edge of
+    /// trigger input `channel` to `level` at device tick `tick`, whose first
+    /// edge, before any contact bounce, was at `raw_tick`. Sent for every
+    /// debounced edge, press or release, armed or not, before any trigger it
+    /// causes. Ticks are corrected by the trigger offset like trigger ticks.
+    RawEdge {
+        channel: u8,
+        level: bool,
+        raw_tick: u64,
+        tick: u64,
+    },
+    /// Sent once a pulse requested with [ToDevice::LoopbackPulse] has ended:
+    /// the device tick at which the loopback output was driven, and the tick
+    /// at which the first trigger input saw the edge, or `None` if it did
+    /// not, e.g. because the output is not wired to it. Neither is corrected
+    /// by the trigger offset, so their difference is the latency to store
+    /// with [ToDevice::SetTriggerOffset].
+    LoopbackEdge {
+        driven: u64,
+        detected: Option<u64>,
+    },
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -36,4 +429,58 @@ pub enum FromDevice {
 pub enum ToDevice {
     Ping,
     VersionRequest,
+    ReadPin,
+    /// Turn the green LED on (`true`) or off (`false`).
+    SetLed(bool),
+    /// Replace the device configuration. Acknowledged with [FromDevice::Ack].
+    Configure(DeviceConfig),
+    /// Request the [FromDevice::BuildConfig] of the firmware.
+    QueryConfig,
+    /// Request the [FromDevice::ConfigState] of the device.
+    GetConfig,
+    /// Request that the payload be sent back unchanged in
+    /// [FromDevice::Echo], to test the link.
+    Echo([u8; ECHO_LEN]),
+    /// Request the [FromDevice::Status] of the device.
+    StatusRequest,
+    /// Store in flash the latency, in device ticks, from a trigger edge to
+    /// its detection, which is then subtracted from the tick of each trigger.
+    /// Acknowledged with [FromDevice::Ack].
+    SetTriggerOffset(u32),
+    /// Send `count` synthetic [FromDevice::Trigger] messages, with `test` set,
+    /// every `period_us` microseconds. Acknowledged with [FromDevice::Ack].
+    TestPattern {
+        period_us: u32,
+        count: u32,
+    },
+    /// Report (`true`) or ignore (`false`) triggers. Edges while disarmed are
+    /// never reported and do not advance the trigger sequence. Acknowledged
+    /// with [FromDevice::Ack].
+    Arm(bool),
+    /// Request the most recent triggers, up to `max`, which the device keeps
+    /// even when no host is connected. Each is sent as a
+    /// [FromDevice::HistoryEntry], oldest first, followed by [FromDevice::Ack].
+    History {
+        max: u16,
+    },
+    /// Set the real-time clock to the start of the given second since the
+    /// Unix epoch. Acknowledged with [FromDevice::Ack].
+    SetRtc(i64),
+    /// Reboot into the RP2040's USB mass storage bootloader, for reflashing,
+    /// if `confirm` is [BOOTLOADER_CONFIRM]. Acknowledged with
+    /// [FromDevice::Ack] before rebooting, after which the device leaves the
+    /// bus, or otherwise answered with [FromDevice::Rejected].
+    EnterBootloader {
+        confirm: u32,
+    },
+    /// Request a [FromDevice::EpochMark], a shared reference point for hosts
+    /// which each fit their own clock model, e.g. to beacons.
+    MarkEpoch,
+    /// Drive the loopback output, which is wired to the first trigger input,
+    /// to the press level of the configured polarity for a moment and back,
+    /// to measure the latency from an edge to its detection. Acknowledged
+    /// with [FromDevice::Ack], and followed by [FromDevice::LoopbackEdge] once
+    /// the pulse ends, or answered with [FromDevice::Rejected] while a pulse
+    /// is in progress.
+    LoopbackPulse,
 }
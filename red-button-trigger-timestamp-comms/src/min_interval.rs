@@ -0,0 +1,62 @@
+/// Suppresses triggers within a minimum interval of the previous reported
+/// one, as a hard cap on the trigger rate. Unlike debouncing, which rejects
+/// short pulses on an input, this rejects whole triggers whatever their
+/// cause.
+pub struct MinInterval {
+    interval_ticks: u64,
+    last_tick: Option<u64>,
+    suppressed: u32,
+}
+
+impl MinInterval {
+    /// Suppress triggers within `interval_ticks` of the previous one. Zero
+    /// suppresses none.
+    pub const fn new(interval_ticks: u64) -> Self {
+        Self {
+            interval_ticks,
+            last_tick: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Change the interval, keeping the count of suppressed triggers.
+    pub fn set_interval(&mut self, interval_ticks: u64) {
+        self.interval_ticks = interval_ticks;
+    }
+
+    /// Whether a trigger at `tick` may be reported. A suppressed trigger is
+    /// counted and does not restart the interval.
+    pub fn allow(&mut self, tick: u64) -> bool {
+        if let Some(last_tick) = self.last_tick {
+            if tick.saturating_sub(last_tick) < self.interval_ticks {
+                self.suppressed = self.suppressed.saturating_add(1);
+                return false;
+            }
+        }
+        self.last_tick = Some(tick);
+        true
+    }
+
+    /// Number of triggers suppressed since creation.
+    pub fn suppressed(&self) -> u32 {
+        self.suppressed
+    }
+}
+
+#[test]
+fn test_min_interval() {
+    let mut min_interval = MinInterval::new(100);
+    assert!(min_interval.allow(1000));
+    assert!(!min_interval.allow(1050));
+    assert!(!min_interval.allow(1099));
+    // The interval runs from the last reported trigger.
+    assert!(min_interval.allow(1100));
+    assert!(!min_interval.allow(1150));
+    assert!(min_interval.allow(1250));
+    assert_eq!(min_interval.suppressed(), 3);
+
+    min_interval.set_interval(0);
+    assert!(min_interval.allow(1250));
+    assert!(min_interval.allow(1251));
+    assert_eq!(min_interval.suppressed(), 3);
+}
@@ -0,0 +1,85 @@
+/// A press, with the following release if it occurred in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgePair {
+    pub press_tick: u64,
+    pub release_tick: Option<u64>,
+}
+
+/// Pairs each press with the subsequent release. A press which is not
+/// released within `timeout_ticks` is reported without a release.
+pub struct EdgePairer {
+    timeout_ticks: u64,
+    pending_press: Option<u64>,
+}
+
+impl EdgePairer {
+    pub const fn new(timeout_ticks: u64) -> Self {
+        Self {
+            timeout_ticks,
+            pending_press: None,
+        }
+    }
+
+    /// Handle a press at `tick`. A previous press whose release was missed is
+    /// returned without a release.
+    pub fn press(&mut self, tick: u64) -> Option<EdgePair> {
+        self.pending_press.replace(tick).map(|press_tick| EdgePair {
+            press_tick,
+            release_tick: None,
+        })
+    }
+
+    /// Handle a release at `tick`, returning the completed pair. A release
+    /// without a pending press, e.g. after a timeout, is ignored.
+    pub fn release(&mut self, tick: u64) -> Option<EdgePair> {
+        self.pending_press.take().map(|press_tick| EdgePair {
+            press_tick,
+            release_tick: Some(tick),
+        })
+    }
+
+    /// Return the pending press without a release if it has timed out by
+    /// tick `now`.
+    pub fn poll(&mut self, now: u64) -> Option<EdgePair> {
+        match self.pending_press {
+            Some(press_tick) if now.saturating_sub(press_tick) >= self.timeout_ticks => {
+                self.pending_press = None;
+                Some(EdgePair {
+                    press_tick,
+                    release_tick: None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_edge_pairer() {
+    let mut pairer = EdgePairer::new(100);
+    let unpaired = |press_tick| EdgePair {
+        press_tick,
+        release_tick: None,
+    };
+
+    // A press followed by a release in time.
+    assert_eq!(pairer.press(10), None);
+    assert_eq!(pairer.poll(50), None);
+    assert_eq!(
+        pairer.release(60),
+        Some(EdgePair {
+            press_tick: 10,
+            release_tick: Some(60),
+        })
+    );
+
+    // A press which times out, with its late release ignored.
+    assert_eq!(pairer.press(200), None);
+    assert_eq!(pairer.poll(300), Some(unpaired(200)));
+    assert_eq!(pairer.poll(400), None);
+    assert_eq!(pairer.release(450), None);
+
+    // A missed release is reported when the next press arrives.
+    assert_eq!(pairer.press(500), None);
+    assert_eq!(pairer.press(550), Some(unpaired(500)));
+}
@@ -0,0 +1,40 @@
+/// Times a pulse on an output without blocking. Starting a pulse while one is
+/// in progress extends it.
+#[derive(Default)]
+pub struct PulseTimer {
+    /// Tick at which the pulse in progress ends
+    end_tick: Option<u64>,
+}
+
+impl PulseTimer {
+    pub const fn new() -> Self {
+        Self { end_tick: None }
+    }
+
+    /// Start a pulse of `duration_ticks` at tick `now`.
+    pub fn start(&mut self, now: u64, duration_ticks: u64) {
+        self.end_tick = Some(now.saturating_add(duration_ticks));
+    }
+
+    /// Return whether the output should be on at tick `now`.
+    pub fn poll(&mut self, now: u64) -> bool {
+        if self.end_tick.is_some_and(|end_tick| now >= end_tick) {
+            self.end_tick = None;
+        }
+        self.end_tick.is_some()
+    }
+}
+
+#[test]
+fn test_pulse_timer() {
+    let mut timer = PulseTimer::new();
+    assert!(!timer.poll(0));
+    timer.start(10, 100);
+    assert!(timer.poll(10));
+    assert!(timer.poll(109));
+    // A second pulse extends the first.
+    timer.start(50, 100);
+    assert!(timer.poll(149));
+    assert!(!timer.poll(150));
+    assert!(!timer.poll(1000));
+}
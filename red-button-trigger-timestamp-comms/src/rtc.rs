@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+
+/// Device ticks per second. Device ticks are microseconds.
+const TICKS_PER_SECOND: u64 = 1_000_000;
+
+/// Seconds in a day, ignoring leap seconds as Unix time does.
+pub const SECONDS_PER_DAY: u32 = 86_400;
+
+/// Maps device ticks to UTC using a real-time clock which counts whole
+/// seconds. The device clock is anchored at the tick at which the RTC's
+/// seconds are seen to change, so the mapping is only as late as the
+/// detection of the change. To detect it promptly without reading the RTC
+/// continuously, the RTC need only be read while [Self::is_poll_due].
+pub struct RtcAnchor {
+    /// Ticks before an expected change of the seconds from which to poll
+    window_ticks: u64,
+    last_seconds: Option<i64>,
+    /// Seconds since the Unix epoch and the tick at which they began
+    anchor: Option<(i64, u64)>,
+}
+
+impl RtcAnchor {
+    /// Poll from `window_ticks` before each expected change of the seconds.
+    /// This must exceed the drift of the device clock relative to the RTC
+    /// over a second.
+    pub const fn new(window_ticks: u64) -> Self {
+        Self {
+            window_ticks,
+            last_seconds: None,
+            anchor: None,
+        }
+    }
+
+    /// Whether the RTC should be read at tick `tick`, i.e. before the first
+    /// anchor or once the next change of the seconds is near.
+    pub fn is_poll_due(&self, tick: u64) -> bool {
+        match self.anchor {
+            None => true,
+            Some((_, anchor_tick)) => {
+                tick.saturating_sub(anchor_tick) + self.window_ticks >= TICKS_PER_SECOND
+            }
+        }
+    }
+
+    /// Handle a reading of `unix_seconds` from the RTC at tick `tick`.
+    pub fn update(&mut self, tick: u64, unix_seconds: i64) {
+        if self.last_seconds.is_some_and(|last| last != unix_seconds) {
+            self.anchor = Some((unix_seconds, tick));
+        }
+        self.last_seconds = Some(unix_seconds);
+    }
+
+    /// Microseconds since the Unix epoch at tick `tick`, once anchored.
+    pub fn unix_micros(&self, tick: u64) -> Option<i64> {
+        let (seconds, anchor_tick) = self.anchor?;
+        let elapsed = tick as i64 - anchor_tick as i64;
+        Some(seconds * TICKS_PER_SECOND as i64 + elapsed)
+    }
+}
+
+/// Daily window of UTC time of day within which triggers are reported, set
+/// with [DeviceConfig::daily_window](crate::DeviceConfig::daily_window).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "print-defmt", derive(defmt::Format))]
+pub struct DailyWindow {
+    /// Start of the window, in seconds since midnight UTC, inclusive
+    pub start_seconds: u32,
+    /// End of the window, in seconds since midnight UTC, exclusive. An end
+    /// before the start makes the window span midnight, and an end equal to
+    /// the start makes it the whole day.
+    pub end_seconds: u32,
+}
+
+impl DailyWindow {
+    /// Whether the time `unix_micros` is within the window.
+    pub fn contains(&self, unix_micros: i64) -> bool {
+        let day_micros = i64::from(SECONDS_PER_DAY) * TICKS_PER_SECOND as i64;
+        let seconds = (unix_micros.rem_euclid(day_micros) / TICKS_PER_SECOND as i64) as u32;
+        match self.start_seconds.cmp(&self.end_seconds) {
+            core::cmp::Ordering::Less => (self.start_seconds..self.end_seconds).contains(&seconds),
+            core::cmp::Ordering::Greater => {
+                seconds >= self.start_seconds || seconds < self.end_seconds
+            }
+            core::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+/// Seconds since the Unix epoch of the time in registers 0 to 6 of a DS3231
+/// RTC, or `None` if they do not hold a valid time.
+pub fn ds3231_unix_seconds(regs: &[u8; 7]) -> Option<i64> {
+    let seconds = from_bcd(regs[0])?;
+    let minutes = from_bcd(regs[1])?;
+    let hours = if regs[2] & 0x40 != 0 {
+        // 12 hour mode, with bit 5 set for PM
+        let hour = from_bcd(regs[2] & 0x1f)?;
+        if !(1..=12).contains(&hour) {
+            return None;
+        }
+        hour % 12 + if regs[2] & 0x20 != 0 { 12 } else { 0 }
+    } else {
+        from_bcd(regs[2] & 0x3f)?
+    };
+    let date = from_bcd(regs[4])?;
+    let month = from_bcd(regs[5] & 0x1f)?;
+    let century = i64::from(regs[5] >> 7);
+    let year = 2000 + 100 * century + i64::from(from_bcd(regs[6])?);
+    if seconds > 59 || minutes > 59 || hours > 23 || !(1..=12).contains(&month) {
+        return None;
+    }
+    if date == 0 || date > days_in_month(year, month) {
+        return None;
+    }
+    let days = days_from_civil(year, month.into(), date.into());
+    Some(((days * 24 + i64::from(hours)) * 60 + i64::from(minutes)) * 60 + i64::from(seconds))
+}
+
+/// Registers 0 to 6 of a DS3231 RTC, in 24 hour mode, for `unix_seconds`, or
+/// `None` if the time is outside the years 2000 to 2199 which it can hold.
+/// The day of the week is numbered from 1 for Monday.
+pub fn ds3231_registers(unix_seconds: i64) -> Option<[u8; 7]> {
+    let days = unix_seconds.div_euclid(86_400);
+    let secs_of_day = unix_seconds.rem_euclid(86_400);
+    let (year, month, date) = civil_from_days(days);
+    if !(2000..2200).contains(&year) {
+        return None;
+    }
+    let century = if year >= 2100 { 0x80 } else { 0 };
+    // 1970-01-01 was a Thursday.
+    let weekday = (days + 3).rem_euclid(7) + 1;
+    Some([
+        to_bcd((secs_of_day % 60) as u8),
+        to_bcd((secs_of_day / 60 % 60) as u8),
+        to_bcd((secs_of_day / 3600) as u8),
+        weekday as u8,
+        to_bcd(date as u8),
+        to_bcd(month as u8) | century,
+        to_bcd((year % 100) as u8),
+    ])
+}
+
+fn from_bcd(bcd: u8) -> Option<u8> {
+    let (tens, units) = (bcd >> 4, bcd & 0x0f);
+    (tens < 10 && units < 10).then_some(tens * 10 + units)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March, so that the leap day ends the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of days since 1970-01-01, the inverse of
+/// [days_from_civil].
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[test]
+fn test_ds3231_registers() {
+    // 2024-02-29T13:45:07Z, a Thursday
+    let regs = [0x07, 0x45, 0x13, 0x04, 0x29, 0x02, 0x24];
+    assert_eq!(ds3231_unix_seconds(&regs), Some(1_709_214_307));
+    assert_eq!(ds3231_registers(1_709_214_307), Some(regs));
+    // 2100-03-01T00:00:00Z, a Monday, with the century bit set
+    let regs = [0x00, 0x00, 0x00, 0x01, 0x01, 0x83, 0x00];
+    assert_eq!(ds3231_unix_seconds(&regs), Some(4_107_542_400));
+    assert_eq!(ds3231_registers(4_107_542_400), Some(regs));
+    // 1999 cannot be held.
+    assert_eq!(ds3231_registers(946_684_799), None);
+
+    // 12 hour mode: 1 PM, 12 AM and 12 PM
+    let time = |hours| ds3231_unix_seconds(&[0x07, 0x45, hours, 0x04, 0x29, 0x02, 0x24]);
+    assert_eq!(time(0x61), Some(1_709_214_307));
+    assert_eq!(time(0x52), Some(1_709_214_307 - 13 * 3600));
+    assert_eq!(time(0x72), Some(1_709_214_307 - 3600));
+
+    // Invalid BCD, seconds and dates
+    assert_eq!(time(0x1a), None);
+    assert_eq!(
+        ds3231_unix_seconds(&[0x60, 0, 0, 1, 0x01, 0x01, 0x24]),
+        None
+    );
+    assert_eq!(ds3231_unix_seconds(&[0, 0, 0, 1, 0x29, 0x02, 0x23]), None);
+}
+
+#[test]
+fn test_rtc_anchor() {
+    let mut anchor = RtcAnchor::new(2_000);
+    assert!(anchor.is_poll_due(0));
+    anchor.update(100, 1_000);
+    assert_eq!(anchor.unix_micros(100), None);
+    // The seconds change between readings at ticks 200 and 300.
+    anchor.update(200, 1_000);
+    anchor.update(300, 1_001);
+    assert_eq!(anchor.unix_micros(300), Some(1_001_000_000));
+    assert_eq!(anchor.unix_micros(250), Some(1_000_999_950));
+    assert_eq!(anchor.unix_micros(500_300), Some(1_001_500_000));
+
+    // Poll only near the next change.
+    assert!(!anchor.is_poll_due(500_300));
+    assert!(!anchor.is_poll_due(998_299));
+    assert!(anchor.is_poll_due(998_300));
+    // Keep polling if the change is late.
+    assert!(anchor.is_poll_due(1_010_000));
+    anchor.update(1_000_350, 1_002);
+    assert!(!anchor.is_poll_due(1_010_000));
+}
+
+#[test]
+fn test_daily_window() {
+    // 2024-02-29T00:00:00Z
+    let midnight: i64 = 1_709_164_800 * 1_000_000;
+    let at = |hours: i64, minutes: i64| midnight + (hours * 60 + minutes) * 60_000_000;
+    let office = DailyWindow {
+        start_seconds: 9 * 3600,
+        end_seconds: 17 * 3600,
+    };
+    assert!(!office.contains(at(8, 59)));
+    assert!(office.contains(at(9, 0)));
+    assert!(office.contains(at(16, 59)));
+    assert!(!office.contains(at(17, 0)));
+    assert!(office.contains(at(24 + 12, 0)));
+
+    let night = DailyWindow {
+        start_seconds: 22 * 3600,
+        end_seconds: 6 * 3600,
+    };
+    assert!(night.contains(at(23, 0)));
+    assert!(night.contains(at(0, 0)));
+    assert!(night.contains(at(5, 59)));
+    assert!(!night.contains(at(6, 0)));
+    assert!(!night.contains(at(12, 0)));
+    // Before 1970
+    assert!(night.contains(-1));
+
+    let all_day = DailyWindow {
+        start_seconds: 0,
+        end_seconds: 0,
+    };
+    assert!(all_day.contains(at(13, 0)));
+}
@@ -0,0 +1,50 @@
+/// Schedules synthetic triggers at a fixed period, for testing the host
+/// without a physical input.
+pub struct TestPattern {
+    next_tick: u64,
+    period_ticks: u64,
+    remaining: u32,
+}
+
+impl TestPattern {
+    /// Start a pattern of `count` triggers, the first at `first_tick` and the
+    /// rest every `period_ticks` after it.
+    pub const fn new(first_tick: u64, period_ticks: u64, count: u32) -> Self {
+        Self {
+            next_tick: first_tick,
+            period_ticks,
+            remaining: count,
+        }
+    }
+
+    /// Return the scheduled tick of the next trigger if it is due by tick
+    /// `now`. Triggers are timestamped with their scheduled tick, so lateness
+    /// in polling does not affect the pattern.
+    pub fn poll(&mut self, now: u64) -> Option<u64> {
+        if self.remaining == 0 || now < self.next_tick {
+            return None;
+        }
+        let tick = self.next_tick;
+        self.remaining -= 1;
+        self.next_tick = tick.saturating_add(self.period_ticks);
+        Some(tick)
+    }
+
+    /// Whether all triggers of the pattern have been returned.
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+#[test]
+fn test_test_pattern() {
+    let mut pattern = TestPattern::new(100, 50, 3);
+    assert_eq!(pattern.poll(99), None);
+    assert_eq!(pattern.poll(100), Some(100));
+    assert_eq!(pattern.poll(100), None);
+    // Polling late still gives the scheduled ticks.
+    assert_eq!(pattern.poll(230), Some(150));
+    assert_eq!(pattern.poll(230), Some(200));
+    assert!(pattern.is_done());
+    assert_eq!(pattern.poll(1000), None);
+}
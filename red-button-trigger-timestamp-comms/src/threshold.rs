@@ -0,0 +1,52 @@
+/// Detects upward crossings of a threshold. After a crossing, the value must
+/// fall below `threshold - hysteresis` before another crossing is detected, so
+/// that noise near the threshold does not produce repeated crossings.
+pub struct ThresholdDetector {
+    threshold: u16,
+    hysteresis: u16,
+    /// Whether the value is above the threshold, unknown before the first
+    above: Option<bool>,
+}
+
+impl ThresholdDetector {
+    pub const fn new(threshold: u16, hysteresis: u16) -> Self {
+        Self {
+            threshold,
+            hysteresis,
+            above: None,
+        }
+    }
+
+    /// Handle a new value, returning whether it crossed the threshold. The
+    /// first value only establishes the initial state.
+    pub fn update(&mut self, value: u16) -> bool {
+        match self.above {
+            None => {
+                self.above = Some(value >= self.threshold);
+                false
+            }
+            Some(false) if value >= self.threshold => {
+                self.above = Some(true);
+                true
+            }
+            Some(true) if value < self.threshold.saturating_sub(self.hysteresis) => {
+                self.above = Some(false);
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn test_threshold_detector() {
+    let mut detector = ThresholdDetector::new(1000, 100);
+    let values = [1200, 500, 999, 1000, 1050, 950, 1010, 899, 1001];
+    let crossings: [bool; 9] = values.map(|value| detector.update(value));
+    // The initial value above the threshold is not a crossing, and noise
+    // within the hysteresis band after a crossing is ignored.
+    assert_eq!(
+        crossings,
+        [false, false, false, true, false, false, false, false, true]
+    );
+}
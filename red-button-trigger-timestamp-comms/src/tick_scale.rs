@@ -0,0 +1,58 @@
+/// Converts ticks of a clock of known frequency to microseconds, so that
+/// timestamps can be reported in a fixed unit whatever the clock's rate.
+#[derive(Debug, Clone, Copy)]
+pub struct TickScale {
+    tick_hz: u32,
+}
+
+impl TickScale {
+    /// A scale for a clock of `tick_hz` ticks per second, which must be
+    /// nonzero.
+    pub const fn new(tick_hz: u32) -> Self {
+        assert!(tick_hz > 0);
+        Self { tick_hz }
+    }
+
+    /// Microseconds in `ticks`, rounded down. The whole seconds and the
+    /// remainder are converted separately, which is exact in 64-bit
+    /// arithmetic over the full range without a 128-bit division.
+    pub const fn to_micros(&self, ticks: u64) -> u64 {
+        let tick_hz = self.tick_hz as u64;
+        if tick_hz == 1_000_000 {
+            return ticks;
+        }
+        let seconds = ticks / tick_hz;
+        let remainder = ticks % tick_hz;
+        seconds * 1_000_000 + remainder * 1_000_000 / tick_hz
+    }
+}
+
+#[test]
+fn test_tick_scale() {
+    // Microsecond ticks are unchanged.
+    let micros = TickScale::new(1_000_000);
+    assert_eq!(micros.to_micros(123_456_789), 123_456_789);
+
+    // The RP2040's 12 MHz crystal, after a year of uptime, is exact.
+    let crystal = TickScale::new(12_000_000);
+    let year_micros = 365 * 24 * 3600 * 1_000_000u64;
+    assert_eq!(crystal.to_micros(year_micros * 12), year_micros);
+    assert_eq!(crystal.to_micros(year_micros * 12 + 11), year_micros);
+    assert_eq!(crystal.to_micros(year_micros * 12 + 12), year_micros + 1);
+
+    // A rate which is not a multiple of 1 MHz is within a microsecond,
+    // without drift, and increases monotonically.
+    let odd = TickScale::new(32_768);
+    let mut previous = 0;
+    for ticks in (0..10 * 32_768u64).step_by(97) {
+        let exact = ticks as f64 * 1e6 / 32_768.0;
+        let micros = odd.to_micros(ticks);
+        assert!(exact - (micros as f64) < 1.0 && micros as f64 <= exact);
+        assert!(micros >= previous);
+        previous = micros;
+    }
+    assert_eq!(
+        odd.to_micros(u64::MAX / 1_000_000 * 32_768),
+        u64::MAX / 1_000_000 * 1_000_000
+    );
+}
@@ -0,0 +1,51 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Capped exponential backoff with jitter for reconnection attempts.
+pub(crate) struct Backoff {
+    min_delay: Duration,
+    max_delay: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(min_delay: Duration, max_delay: Duration) -> Self {
+        let max_delay = max_delay.max(min_delay);
+        Self {
+            min_delay,
+            max_delay,
+            current: min_delay,
+        }
+    }
+
+    /// Return to the minimum delay, e.g. after a successful connection.
+    pub(crate) fn reset(&mut self) {
+        self.current = self.min_delay;
+    }
+
+    /// Return the delay before the next attempt and double the base delay.
+    ///
+    /// The returned value is drawn uniformly from the upper half of the
+    /// current base delay so that several hosts do not retry in lockstep.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let base = self.current;
+        self.current = (self.current * 2).min(self.max_delay);
+        let jitter: f64 = rand::thread_rng().gen_range(0.5..=1.0);
+        base.mul_f64(jitter)
+    }
+}
+
+#[test]
+fn test_backoff_is_capped_and_resets() {
+    let min = Duration::from_millis(500);
+    let max = Duration::from_secs(30);
+    let mut backoff = Backoff::new(min, max);
+    for _ in 0..20 {
+        let delay = backoff.next_delay();
+        assert!(delay <= max);
+        assert!(delay >= min / 2);
+    }
+    assert_eq!(backoff.current, max);
+    backoff.reset();
+    assert!(backoff.next_delay() <= min);
+}
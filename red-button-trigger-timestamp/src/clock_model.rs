@@ -13,18 +13,29 @@ impl std::fmt::Display for ClockModelFitError {
 
 impl std::error::Error for ClockModelFitError {}
 
+/// Fit `host_time = gain * device_time + offset` by least squares. Returns
+/// `(gain, offset, residuals)`.
+///
+/// The fit is to the samples relative to the first. Weeks into a session,
+/// device and host times are large but a window of samples spans only
+/// minutes, and fitting them as they are would leave the offset determined
+/// by the difference of nearly equal large numbers, losing the fit's
+/// precision to rounding.
 pub(crate) fn fit_time_model(
     past_data: &[(f64, f64)],
 ) -> Result<(f64, f64, f64), ClockModelFitError> {
     use na::{OMatrix, OVector, U2};
 
+    let (x0, y0) = *past_data
+        .first()
+        .ok_or_else(|| ClockModelFitError("no samples".into()))?;
     let mut a: Vec<f64> = Vec::with_capacity(past_data.len() * 2);
     let mut b: Vec<f64> = Vec::with_capacity(past_data.len());
 
     for row in past_data.iter() {
-        a.push(row.0);
+        a.push(row.0 - x0);
         a.push(1.0);
-        b.push(row.1);
+        b.push(row.1 - y0);
     }
     let a = OMatrix::<f64, na::Dyn, U2>::from_row_slice(&a);
     let b = OVector::<f64, na::Dyn>::from_row_slice(&b);
@@ -33,12 +44,49 @@ pub(crate) fn fit_time_model(
     let results = lstsq::lstsq(&a, &b, epsilon).map_err(|msg| ClockModelFitError(msg.into()))?;
 
     let gain = results.solution[0];
-    let offset = results.solution[1];
+    let offset = results.solution[1] + y0 - gain * x0;
     let residuals = results.residuals;
 
     Ok((gain, offset, residuals))
 }
 
+/// Fit `host_time = gain * device_time + offset` with the Theil–Sen
+/// estimator: the gain is the median of the slopes between all pairs of
+/// samples and the offset the median of the intercepts at that gain. Unlike
+/// least squares, this is unaffected by a minority of gross outliers, at a
+/// cost quadratic in the number of samples. Returns `(gain, offset)`.
+pub(crate) fn fit_time_model_robust(
+    past_data: &[(f64, f64)],
+) -> Result<(f64, f64), ClockModelFitError> {
+    let mut slopes = Vec::with_capacity(past_data.len() * past_data.len() / 2);
+    for (i, (x0, y0)) in past_data.iter().enumerate() {
+        for (x1, y1) in &past_data[i + 1..] {
+            if x1 != x0 {
+                slopes.push((y1 - y0) / (x1 - x0));
+            }
+        }
+    }
+    let gain = median(&mut slopes)
+        .ok_or_else(|| ClockModelFitError("no two samples differ in device time".into()))?;
+    let mut intercepts: Vec<f64> = past_data.iter().map(|(x, y)| y - gain * x).collect();
+    let offset = median(&mut intercepts).unwrap();
+    Ok((gain, offset))
+}
+
+/// Median of `values`, which are reordered, or `None` if there are none.
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    Some(if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
+    })
+}
+
 #[test]
 fn test_fit_time_model() {
     let epsilon = 1e-12;
@@ -54,26 +102,239 @@ fn test_fit_time_model() {
     assert!((offset - 12.0).abs() < epsilon);
 }
 
+#[test]
+fn test_robust_fit_resists_outliers() {
+    // Samples on host = 2 * device + 5, except a few delayed by a busy host.
+    let data: Vec<_> = (0..30)
+        .map(|i| {
+            let x = i as f64 * 10.0;
+            let outlier = if [7, 15, 22].contains(&i) { 500.0 } else { 0.0 };
+            (x, 2.0 * x + 5.0 + outlier)
+        })
+        .collect();
+    let (lsq_gain, lsq_offset, _residuals) = fit_time_model(&data).unwrap();
+    let (gain, offset) = fit_time_model_robust(&data).unwrap();
+    assert!((gain - 2.0).abs() < (lsq_gain - 2.0).abs());
+    assert!((offset - 5.0).abs() < (lsq_offset - 5.0).abs());
+    assert!((gain - 2.0).abs() < 1e-9);
+    assert!((offset - 5.0).abs() < 1e-6);
+
+    assert!(fit_time_model_robust(&[(1.0, 1.0), (1.0, 2.0)]).is_err());
+}
+
+#[test]
+fn test_high_rtt_samples_are_rejected() {
+    let epoch = test_epoch();
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let low_rtt = TimeDelta::milliseconds(1);
+    let high_rtt = TimeDelta::milliseconds(50);
+    let mut n_low = 0;
+    for i in 0..30 {
+        let t0 = epoch + TimeDelta::seconds(i);
+        // The device ticks once per microsecond, on the same epoch as the
+        // host, and replies at the midpoint of a low RTT exchange.
+        let device_timestamp = test_device_tick(t0 + low_rtt / 2);
+        // Every third measurement has a high RTT. Were it used, the device
+        // timestamp would be paired with a midpoint 24.5 msecs too late.
+        let rtt = if i % 3 == 2 { high_rtt } else { low_rtt };
+        if rtt == low_rtt {
+            n_low += 1;
+        }
+        model.update(t0, t0 + rtt, device_timestamp, device_timestamp);
+    }
+    assert_eq!(model.samples().len(), n_low);
+
+    let expected = epoch + TimeDelta::seconds(40);
+    let error = model.compute_utc(test_device_tick(expected)).unwrap() - expected;
+    assert!(error.num_microseconds().unwrap().abs() <= 1);
+}
+
+/// Check that a fitted `gain`, in host microseconds per device tick, agrees
+/// with the device's reported clock frequency of `tick_hz`. A mismatch means
+/// the firmware and host disagree about timestamp units.
+pub(crate) fn check_tick_rate(gain: f64, tick_hz: u32) -> Result<(), String> {
+    let expected = 1e6 / tick_hz as f64;
+    let ratio = gain / expected;
+    if !(0.5..=2.0).contains(&ratio) {
+        return Err(format!(
+            "device reports {tick_hz} Hz ticks but the clock model measures {:.0} Hz",
+            1e6 / gain
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_tick_rate() {
+    let epoch = test_epoch();
+    // This synthetic device ticks at 1 kHz but claims 1 MHz.
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    for i in 0..20 {
+        let t = epoch + TimeDelta::seconds(i);
+        let tick = (i * 1000) as u64;
+        model.update(t, t, tick, tick);
+    }
+    let gain = model.gain().unwrap();
+    assert!(check_tick_rate(gain, 1_000_000).is_err());
+    assert!(check_tick_rate(gain, 1_000).is_ok());
+}
+
+/// Unit of host time in the clock model fit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TimeBasis {
+    Micros,
+    /// Finer resolution, for device clocks faster than 1 MHz. Host times of
+    /// up to about 292 years from the epoch are representable.
+    Nanos,
+}
+
+impl TimeBasis {
+    /// Units per microsecond
+    fn per_micro(self) -> f64 {
+        match self {
+            Self::Micros => 1.0,
+            Self::Nanos => 1000.0,
+        }
+    }
+
+    /// Convert to a whole number of units, or `None` on overflow.
+    fn units_of(self, delta: TimeDelta) -> Option<i64> {
+        match self {
+            Self::Micros => delta.num_microseconds(),
+            Self::Nanos => delta.num_nanoseconds(),
+        }
+    }
+
+    fn delta_of(self, units: i64) -> TimeDelta {
+        match self {
+            Self::Micros => TimeDelta::microseconds(units),
+            Self::Nanos => TimeDelta::nanoseconds(units),
+        }
+    }
+}
+
+/// Estimator used to fit the clock model to its samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FitMethod {
+    /// Least squares, with [fit_time_model]
+    LeastSquares,
+    /// Theil–Sen, with [fit_time_model_robust], for jittery links
+    Robust,
+}
+
+/// Linear map from device ticks to host time in units of the model's
+/// [TimeBasis].
 struct InnerModel {
     gain: f64,
     offset: f64,
+    /// Root mean square difference between the fit and the samples
+    rms_residual: f64,
 }
 
 impl InnerModel {
-    fn from_samples(samples: &VecDeque<(f64, f64)>) -> Self {
-        let data: Vec<_> = samples.iter().cloned().collect();
-        let (gain, offset, _residuals) = fit_time_model(&data).unwrap();
-        InnerModel { gain, offset }
+    fn from_samples(data: &[(f64, f64)], fit: FitMethod) -> Self {
+        let (gain, offset) = match fit {
+            FitMethod::LeastSquares => {
+                let (gain, offset, _residuals) = fit_time_model(data).unwrap();
+                (gain, offset)
+            }
+            FitMethod::Robust => fit_time_model_robust(data).unwrap(),
+        };
+        let sum_squares: f64 = data
+            .iter()
+            .map(|(device_time, host_time)| (device_time * gain + offset - host_time).powi(2))
+            .sum();
+        InnerModel {
+            gain,
+            offset,
+            rms_residual: (sum_squares / data.len() as f64).sqrt(),
+        }
     }
 }
 
+/// A linear segment of the clock model which has been superseded, after a
+/// change in the device clock's rate, but is still used to estimate the times
+/// of device ticks within it.
+struct Segment {
+    /// First device time of the segment, relative to the device epoch
+    start: f64,
+    /// Device time, relative to the device epoch, at which the next segment
+    /// starts
+    end: f64,
+    model: InnerModel,
+}
+
+/// A superseded segment in a [ClockModelDump].
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct SegmentDump {
+    start_device_time: f64,
+    end_device_time: f64,
+    gain: f64,
+    offset: f64,
+}
+
+/// Minimum number of samples to fit, and on each side of a change in rate.
+const MIN_SAMPLES: usize = 10;
+
+/// Snapshot of a [ClockModel], written with `--dump-samples`.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct ClockModelDump {
+    /// Host time from which `samples` and `offset` are measured
+    epoch: DateTime<Utc>,
+    /// Device tick from which `samples` are measured
+    device_epoch: Option<u64>,
+    /// Unit of host times
+    host_units: &'static str,
+    /// Host time units per device tick, once fitted
+    gain: Option<f64>,
+    offset: Option<f64>,
+    /// Root mean square residual of the fit, in seconds
+    residual_seconds: Option<f64>,
+    /// `(device_time, host_time)` pairs
+    samples: Vec<(f64, f64)>,
+    /// Superseded segments, oldest first, with device times relative to
+    /// `device_epoch`. `gain` and `offset` are those of the latest segment.
+    segments: Vec<SegmentDump>,
+}
+
+/// Map from device ticks to UTC, fitted to clock measurements.
+///
+/// Times are held as `f64` relative to the device and host epochs, which are
+/// exact for integers up to 2^53: device ticks for 285 years at 1 MHz or 2.8
+/// years at 100 MHz, and host times for 285 years in microseconds or 104 days
+/// in nanoseconds, after which nanosecond times round to 2 ns. Each step of an
+/// estimate rounds by about 1e-16 of its magnitude, under a nanosecond 30
+/// days into a session, so long sessions need no re-basing as long as the
+/// fit itself is made near its samples (see [fit_time_model]).
 pub struct ClockModel {
     epoch: DateTime<Utc>,
     device_epoch: Option<u64>,
     /// maximum round trip time
     max_rtt: TimeDelta,
+    /// Unit of host times in `samples`, `anchors` and the fit
+    basis: TimeBasis,
+    fit: FitMethod,
+    /// `(device_time, host_time)` pairs relative to the respective epochs
     samples: VecDeque<(f64, f64)>,
+    /// Device tick at which recent accepted pings were received, with the
+    /// estimated host time (relative to the epoch) at that moment
+    anchors: VecDeque<(u64, f64)>,
     model: Option<InnerModel>,
+    /// Model prediction minus host time of the latest sample, in seconds
+    latest_error: Option<f64>,
+    /// Largest root mean square residual, in seconds, at which the fit is
+    /// trusted to estimate times
+    max_residual: Option<f64>,
+    /// Relative change in gain at which a new linear segment is started, if
+    /// the model is piecewise linear
+    segment_gain_change: Option<f64>,
+    /// Superseded segments, oldest first
+    segments: Vec<Segment>,
+    /// Device time, relative to the device epoch, at which the current
+    /// segment starts
+    segment_start: f64,
+    /// Device tick at which a segment started since [Self::take_new_segment]
+    new_segment: Option<u64>,
 }
 
 impl Default for ClockModel {
@@ -84,25 +345,206 @@ impl Default for ClockModel {
 
 impl ClockModel {
     pub fn new(max_rtt: TimeDelta) -> Self {
+        Self::with_epoch(Utc::now(), max_rtt)
+    }
+
+    /// Create a model whose host times are relative to `epoch`.
+    pub fn with_epoch(epoch: DateTime<Utc>, max_rtt: TimeDelta) -> Self {
+        Self::with_basis(epoch, max_rtt, TimeBasis::Micros)
+    }
+
+    /// Create a model whose host times are relative to `epoch` and fitted in
+    /// units of `basis`.
+    pub(crate) fn with_basis(epoch: DateTime<Utc>, max_rtt: TimeDelta, basis: TimeBasis) -> Self {
         Self {
-            epoch: Utc::now(),
+            epoch,
             device_epoch: None,
             max_rtt,
+            basis,
+            fit: FitMethod::LeastSquares,
             samples: Default::default(),
+            anchors: Default::default(),
             model: None,
+            latest_error: None,
+            max_residual: None,
+            segment_gain_change: None,
+            segments: Vec::new(),
+            segment_start: 0.0,
+            new_segment: None,
+        }
+    }
+    /// Snapshot of the current `(device_time, host_time)` samples used for the
+    /// fit, for plotting. Device time is in ticks since the first sample and
+    /// host time is in units of [Self::basis] since the model was created.
+    pub fn samples(&self) -> Vec<(f64, f64)> {
+        self.samples.iter().cloned().collect()
+    }
+
+    /// The samples and fitted parameters, for offline analysis.
+    pub(crate) fn dump(&self) -> ClockModelDump {
+        ClockModelDump {
+            epoch: self.epoch,
+            device_epoch: self.device_epoch,
+            host_units: match self.basis {
+                TimeBasis::Micros => "microseconds",
+                TimeBasis::Nanos => "nanoseconds",
+            },
+            gain: self.model.as_ref().map(|m| m.gain),
+            offset: self.model.as_ref().map(|m| m.offset),
+            residual_seconds: self.residual_seconds(),
+            samples: self.samples(),
+            segments: self
+                .segments
+                .iter()
+                .map(|s| SegmentDump {
+                    start_device_time: s.start,
+                    end_device_time: s.end,
+                    gain: s.model.gain,
+                    offset: s.model.offset,
+                })
+                .collect(),
+        }
+    }
+
+    /// Number of samples currently fitted.
+    pub fn num_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Number of samples needed before times can be estimated.
+    pub fn min_samples(&self) -> usize {
+        MIN_SAMPLES
+    }
+
+    /// Whether enough samples have been accepted to estimate times, and the
+    /// fit to them is close enough (see [Self::with_max_residual]).
+    pub fn is_ready(&self) -> bool {
+        self.trusted_model().is_some()
+    }
+
+    /// The fitted model, if its residual is within the maximum.
+    fn trusted_model(&self) -> Option<&InnerModel> {
+        self.model.as_ref().filter(|m| self.is_trusted(m))
+    }
+
+    fn is_trusted(&self, model: &InnerModel) -> bool {
+        self.max_residual
+            .is_none_or(|max| model.rms_residual / self.basis.per_micro() / 1e6 <= max)
+    }
+
+    /// The model of the segment containing `device_time`, relative to the
+    /// device epoch, if it is trusted. Times before the first segment, e.g.
+    /// of triggers kept by the device from before the host connected, are
+    /// extrapolated from it.
+    fn trusted_model_at(&self, device_time: f64) -> Option<&InnerModel> {
+        if device_time >= self.segment_start {
+            return self.trusted_model();
         }
+        let model = match self.segments.first() {
+            None => return self.trusted_model(),
+            Some(first) if device_time < first.start => &first.model,
+            Some(_) => {
+                &self
+                    .segments
+                    .iter()
+                    .rfind(|s| s.start <= device_time && device_time < s.end)?
+                    .model
+            }
+        };
+        Some(model).filter(|m| self.is_trusted(m))
+    }
+
+    pub(crate) fn basis(&self) -> TimeBasis {
+        self.basis
+    }
+
+    /// Use `fit` instead of least squares to fit the samples.
+    pub(crate) fn with_fit(mut self, fit: FitMethod) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    /// Estimate no times until the root mean square residual of the fit is
+    /// at most `seconds`, so that a poor initial fit, e.g. to samples taken
+    /// while the host was busy, does not timestamp early triggers.
+    pub(crate) fn with_max_residual(mut self, seconds: Option<f64>) -> Self {
+        self.max_residual = seconds;
+        self
     }
-    pub fn update(&mut self, t0: DateTime<Utc>, t1: DateTime<Utc>, device_timestamp: u64) {
+
+    /// Fit a new linear segment whenever the gain changes by more than the
+    /// fraction `relative_change`, e.g. as the device clock's oscillator
+    /// drifts with temperature, rather than one line through all samples.
+    /// Each segment is used for device ticks within it.
+    pub(crate) fn with_segment_gain_change(mut self, relative_change: Option<f64>) -> Self {
+        self.segment_gain_change = relative_change;
+        self
+    }
+
+    /// Use the fit settings of `other`, e.g. when restarting a model.
+    pub(crate) fn with_options_of(self, other: &Self) -> Self {
+        self.with_fit(other.fit)
+            .with_max_residual(other.max_residual)
+            .with_segment_gain_change(other.segment_gain_change)
+    }
+
+    /// The device tick at which the latest segment started, if one has since
+    /// the previous call.
+    pub(crate) fn take_new_segment(&mut self) -> Option<u64> {
+        self.new_segment.take()
+    }
+
+    /// Root mean square difference between the fit and its samples, in
+    /// seconds, once fitted.
+    pub fn residual_seconds(&self) -> Option<f64> {
+        self.model
+            .as_ref()
+            .map(|m| m.rms_residual / self.basis.per_micro() / 1e6)
+    }
+
+    /// Fitted host microseconds per device tick, once the model is ready.
+    pub fn gain(&self) -> Option<f64> {
+        self.model.as_ref().map(|m| m.gain / self.basis.per_micro())
+    }
+
+    /// Error of the model at the most recent clock measurement, in seconds:
+    /// the predicted host time minus the measured host time.
+    pub fn latest_error_seconds(&self) -> Option<f64> {
+        self.latest_error
+    }
+
+    /// Round trip times longer than this are ignored in [Self::update].
+    pub fn max_rtt(&self) -> TimeDelta {
+        self.max_rtt
+    }
+
+    /// Add a clock measurement from a ping sent at host time `t0` whose pong
+    /// was received at host time `t1`. `ping_received` and `pong_sent` are the
+    /// device ticks (microseconds) at which the device received the ping and
+    /// sent the pong.
+    pub fn update(
+        &mut self,
+        t0: DateTime<Utc>,
+        t1: DateTime<Utc>,
+        ping_received: u64,
+        pong_sent: u64,
+    ) {
         // First remove potentially giant offset from the epoch.
         let t0 = t0 - self.epoch;
         let t1 = t1 - self.epoch;
         if self.device_epoch.is_none() {
-            self.device_epoch = Some(device_timestamp);
+            self.device_epoch = Some(ping_received);
         }
-        let device_timestamp = device_timestamp - self.device_epoch.as_ref().unwrap();
+        let device_epoch = self.device_epoch.unwrap();
+        let anchor_tick = ping_received;
+        let ping_received = ping_received - device_epoch;
+        let pong_sent = pong_sent - device_epoch;
 
         // Now the giant offset from the epoch is removed.
-        let rtt = t1 - t0;
+
+        // Time spent on the device does not count towards transmission delay.
+        let device_processing = pong_sent.saturating_sub(ping_received);
+        let rtt = (t1 - t0) - TimeDelta::microseconds(device_processing as i64);
         if rtt > self.max_rtt {
             tracing::warn!(
                 "Ignoring clock measurement with round trip time of {} msecs.",
@@ -110,52 +552,631 @@ impl ClockModel {
             );
             return;
         }
-        let est_time = t0 + (rtt / 2);
-        let est_time_micros = est_time.num_microseconds().unwrap();
+        // Assuming symmetric transmission delays, the midpoint of the device
+        // processing interval corresponds to the midpoint of the host interval.
+        let device_timestamp = ping_received + device_processing / 2;
+        let est_time = t0 + ((t1 - t0) / 2);
+        let anchor_time = t0 + (rtt / 2);
+        let (Some(est_time), Some(anchor_time)) = (
+            self.basis.units_of(est_time),
+            self.basis.units_of(anchor_time),
+        ) else {
+            tracing::warn!("Ignoring clock measurement too far from the model epoch.");
+            return;
+        };
+        self.anchors.push_back((anchor_tick, anchor_time as f64));
+        while self.anchors.len() > 10 {
+            self.anchors.pop_front();
+        }
+        self.push_sample(device_timestamp, est_time);
+    }
+
+    /// Add a one-way clock measurement from a message sent by the device at
+    /// tick `device_timestamp` and received at host time `received`.
+    ///
+    /// Without a round trip the transport delay cannot be measured, so
+    /// estimated times are late by the typical delay, and jitter in the delay
+    /// adds directly to their error.
+    pub fn update_one_way(&mut self, received: DateTime<Utc>, device_timestamp: u64) {
+        let received = received - self.epoch;
+        let device_epoch = *self.device_epoch.get_or_insert(device_timestamp);
+        // Messages are not strictly ordered, so one may predate the first.
+        let Some(device_timestamp) = device_timestamp.checked_sub(device_epoch) else {
+            return;
+        };
+        let Some(received) = self.basis.units_of(received) else {
+            tracing::warn!("Ignoring clock measurement too far from the model epoch.");
+            return;
+        };
+        self.push_sample(device_timestamp, received);
+    }
+
+    /// Add a sample relative to the epochs and refit the model.
+    fn push_sample(&mut self, device_time: u64, host_time: i64) {
         self.samples
-            .push_back((est_time_micros as f64, device_timestamp as f64));
+            .push_back((device_time as f64, host_time as f64));
         while self.samples.len() > 100 {
             self.samples.pop_front();
         }
-        if self.samples.len() >= 10 {
-            if self.model.is_none() {
+        if self.samples.len() >= MIN_SAMPLES {
+            let was_ready = self.is_ready();
+            let model = self.split_segment().unwrap_or_else(|| {
+                InnerModel::from_samples(self.samples.make_contiguous(), self.fit)
+            });
+            let predicted = device_time as f64 * model.gain + model.offset;
+            self.latest_error = Some((predicted - host_time as f64) / self.basis.per_micro() / 1e6);
+            self.model = Some(model);
+            if !was_ready && self.is_ready() {
                 tracing::info!(
                     "Obtained {} samples. Now capable of estimating clock.",
                     self.samples.len()
                 );
             }
-            self.model = Some(InnerModel::from_samples(&self.samples));
         }
     }
 
+    /// If the gain has changed within the current segment, end it where two
+    /// lines best fit its samples, keeping only the samples after that, and
+    /// return the fit to them.
+    ///
+    /// The best split is searched for with fewer samples after it than are
+    /// needed to act on it, so that a change is not acted on until enough
+    /// samples after it locate it.
+    fn split_segment(&mut self) -> Option<InnerModel> {
+        let max_change = self.segment_gain_change?;
+        let fit = self.fit;
+        let data = self.samples.make_contiguous();
+        let n = data.len();
+        if n < 2 * MIN_SAMPLES {
+            return None;
+        }
+        let (split, before, after, _sum_squares) = (MIN_SAMPLES / 2..=n - MIN_SAMPLES / 2)
+            .map(|split| {
+                let before = InnerModel::from_samples(&data[..split], fit);
+                let after = InnerModel::from_samples(&data[split..], fit);
+                let sum_squares = before.rms_residual.powi(2) * split as f64
+                    + after.rms_residual.powi(2) * (n - split) as f64;
+                (split, before, after, sum_squares)
+            })
+            .min_by(|a, b| a.3.total_cmp(&b.3))?;
+        let change = (after.gain - before.gain) / before.gain;
+        if split < MIN_SAMPLES
+            || n - split < MIN_SAMPLES
+            || change.is_nan()
+            || change.abs() <= max_change
+        {
+            return None;
+        }
+        let boundary = data[split].0;
+        tracing::info!(
+            "Device clock rate changed by {:.1} ppm. Starting a new clock model segment.",
+            (before.gain / after.gain - 1.0) * 1e6
+        );
+        self.segments.push(Segment {
+            start: self.segment_start,
+            end: boundary,
+            model: before,
+        });
+        self.segment_start = boundary;
+        self.new_segment = self.device_epoch.map(|epoch| epoch + boundary as u64);
+        self.samples.drain(..split);
+        Some(after)
+    }
+
     pub fn compute_utc(&self, device_timestamp: u64) -> Option<DateTime<Utc>> {
         // First remove potentially giant offset from the epoch.
-        let device_timestamp = match &self.device_epoch {
+        // Ticks before the device epoch are negative.
+        let device_time = match &self.device_epoch {
             None => {
                 return None;
             }
-            Some(device_epoch) => device_timestamp - device_epoch,
+            Some(device_epoch) => (i128::from(device_timestamp) - i128::from(*device_epoch)) as f64,
         };
 
         // Now the giant offset from the epoch is removed.
-        let model = match self.model.as_ref() {
+        let model = match self.trusted_model_at(device_time) {
             None => return None,
             Some(m) => m,
         };
 
         // Compute the predicted time as a float...
-        let est_time_micros = device_timestamp as f64 * model.gain + model.offset;
-        // ...and convert back to integer.
-        if est_time_micros > i64::MAX as f64 {
+        let est_time = device_time * model.gain + model.offset;
+        self.to_utc(est_time)
+    }
+
+    /// Predict the device tick at time `utc`, the inverse of
+    /// [Self::compute_utc], e.g. to schedule an event on the device. Each
+    /// segment of a piecewise model is inverted, and the result taken from the
+    /// one whose range of device times contains it. Returns `None` if the
+    /// model is not ready, a gain is degenerate or the tick would be out of
+    /// range.
+    #[allow(dead_code)]
+    pub fn compute_device_ticks(&self, utc: DateTime<Utc>) -> Option<u64> {
+        let device_epoch = self.device_epoch?;
+        let host_time = self.basis.units_of(utc - self.epoch)? as f64;
+        let device_time = self
+            .model
+            .iter()
+            .chain(self.segments.iter().rev().map(|s| &s.model))
+            .filter(|m| m.gain.abs() >= f64::EPSILON)
+            .map(|m| (m, ((host_time - m.offset) / m.gain).round()))
+            .find(|(m, device_time)| {
+                self.trusted_model_at(*device_time)
+                    .is_some_and(|found| std::ptr::eq(found, *m))
+            })?
+            .1;
+        let tick = i128::from(device_epoch) + device_time as i128;
+        u64::try_from(tick).ok()
+    }
+
+    /// Compute the UTC time of `device_timestamp` relative to the ping
+    /// received by the device at tick `ping_received`, using the model only
+    /// for the clock rate. Returns `None` if that ping is not among the
+    /// recent accepted clock measurements or the model is not ready.
+    pub fn compute_utc_anchored(
+        &self,
+        device_timestamp: u64,
+        ping_received: u64,
+    ) -> Option<DateTime<Utc>> {
+        let model = self.trusted_model()?;
+        let (_, anchor_time) = self
+            .anchors
+            .iter()
+            .find(|(tick, _)| *tick == ping_received)?;
+        let elapsed_ticks = device_timestamp as f64 - ping_received as f64;
+        self.to_utc(anchor_time + elapsed_ticks * model.gain)
+    }
+
+    /// Convert host time in units of the basis since the epoch to UTC.
+    fn to_utc(&self, est_time: f64) -> Option<DateTime<Utc>> {
+        // Convert back to integer.
+        if est_time > i64::MAX as f64 {
             return None;
         }
-        if est_time_micros < i64::MIN as f64 {
+        if est_time < i64::MIN as f64 {
             return None;
         }
-        let est_time_micros = est_time_micros as i64;
+        let est_time = est_time as i64;
 
         // Add back the offset
-        let est_time = self.epoch + TimeDelta::microseconds(est_time_micros);
-        Some(est_time)
+        self.epoch.checked_add_signed(self.basis.delta_of(est_time))
+    }
+}
+
+/// The host time at which clock models in tests start.
+#[cfg(test)]
+fn test_epoch() -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc)
+}
+
+/// The tick at host time `t` of a 1 MHz device clock which reads zero at
+/// [test_epoch].
+#[cfg(test)]
+fn test_device_tick(t: DateTime<Utc>) -> u64 {
+    (t - test_epoch()).num_microseconds().unwrap() as u64
+}
+
+#[test]
+fn test_device_processing_time_is_compensated() {
+    let transmission = TimeDelta::microseconds(1_000);
+    let processing = TimeDelta::microseconds(5_000);
+    // The device clock reads this many microseconds at the host epoch.
+    let device_offset = 123_456_789;
+
+    let mut model = ClockModel::default();
+    let start = model.epoch + TimeDelta::milliseconds(2);
+    for i in 0..20 {
+        let t0 = start + TimeDelta::seconds(i);
+        let ping_received = t0 + transmission;
+        let pong_sent = ping_received + processing;
+        let t1 = pong_sent + transmission;
+        let to_device = |t: DateTime<Utc>| {
+            ((t - model.epoch).num_microseconds().unwrap() + device_offset) as u64
+        };
+        let (ping_received, pong_sent) = (to_device(ping_received), to_device(pong_sent));
+        model.update(t0, t1, ping_received, pong_sent);
+    }
+
+    let expected = start + TimeDelta::seconds(30);
+    let device_timestamp =
+        ((expected - model.epoch).num_microseconds().unwrap() + device_offset) as u64;
+    let error = model.compute_utc(device_timestamp).unwrap() - expected;
+    assert!(error.num_microseconds().unwrap().abs() <= 1);
+}
+
+#[test]
+fn test_anchored_is_better_after_rate_change() {
+    let epoch = test_epoch();
+    // The device clock runs 100 ppm fast after 50 seconds, e.g. after a
+    // change in temperature.
+    let device_tick = |t: DateTime<Utc>| {
+        let micros = (t - epoch).num_microseconds().unwrap() as f64;
+        let fast_micros = (micros - 50e6).max(0.0);
+        (micros + fast_micros * 100e-6) as u64
+    };
+
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let mut last_ping_received = 0;
+    for i in 0..100 {
+        let t = epoch + TimeDelta::seconds(i);
+        last_ping_received = device_tick(t);
+        model.update(t, t, last_ping_received, last_ping_received);
+    }
+
+    let trigger_time = epoch + TimeDelta::milliseconds(99_500);
+    let trigger_tick = device_tick(trigger_time);
+    let pure = model.compute_utc(trigger_tick).unwrap() - trigger_time;
+    let anchored = model
+        .compute_utc_anchored(trigger_tick, last_ping_received)
+        .unwrap()
+        - trigger_time;
+    let pure = pure.num_microseconds().unwrap().abs();
+    let anchored = anchored.num_microseconds().unwrap().abs();
+    assert!(anchored < 100);
+    assert!(anchored < pure);
+}
+
+#[test]
+fn test_nanos_basis_resolves_sub_microsecond() {
+    let epoch = test_epoch();
+    // A 10 MHz device clock, on the same epoch as the host, with pings
+    // landing a fraction of a microsecond after each whole second.
+    let device_tick = |t: DateTime<Utc>| ((t - epoch).num_nanoseconds().unwrap() / 100) as u64;
+    let offset = TimeDelta::nanoseconds(400);
+
+    let mut errors = Vec::new();
+    for basis in [TimeBasis::Micros, TimeBasis::Nanos] {
+        let mut model = ClockModel::with_basis(epoch, TimeDelta::milliseconds(20), basis);
+        for i in 0..20 {
+            let t = epoch + TimeDelta::seconds(i) + offset;
+            model.update(t, t, device_tick(t), device_tick(t));
+        }
+        let expected = epoch + TimeDelta::seconds(30) + offset;
+        let error = model.compute_utc(device_tick(expected)).unwrap() - expected;
+        errors.push(error.num_nanoseconds().unwrap().abs());
+    }
+    assert!(errors[0] >= 300, "micros basis error {} ns", errors[0]);
+    assert!(errors[1] <= 10, "nanos basis error {} ns", errors[1]);
+}
+
+#[test]
+fn test_one_way_is_late_by_transport_delay() {
+    let epoch = test_epoch();
+    let delay = TimeDelta::milliseconds(2);
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    for i in 0..20 {
+        let sent = epoch + TimeDelta::milliseconds(1500 * i);
+        model.update_one_way(sent + delay, test_device_tick(sent));
+    }
+    let expected = epoch + TimeDelta::seconds(40);
+    let error = model.compute_utc(test_device_tick(expected)).unwrap() - expected;
+    assert!((error - delay).num_microseconds().unwrap().abs() <= 1);
+}
+
+#[test]
+fn test_compute_device_ticks_inverts_compute_utc() {
+    let epoch = test_epoch();
+    // A device clock 50 ppm slow, far from zero at the epoch.
+    let device_tick = |t: DateTime<Utc>| {
+        (5_000_000_000 + ((t - epoch).num_microseconds().unwrap() as f64 * 0.99995) as i64) as u64
+    };
+
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    assert_eq!(model.compute_device_ticks(epoch), None);
+    for i in 0..20 {
+        let t = epoch + TimeDelta::seconds(i);
+        model.update(t, t, device_tick(t), device_tick(t));
+    }
+    // Including a tick from before the first sample, which is extrapolated.
+    for tick in [4_990_000_000, 5_000_000_000, 5_012_345_678, 5_100_000_000] {
+        let utc = model.compute_utc(tick).unwrap();
+        assert_eq!(model.compute_device_ticks(utc), Some(tick));
+    }
+}
+
+#[test]
+fn test_compute_device_ticks_inverts_each_segment() {
+    let epoch = test_epoch();
+    // The device clock runs at nominal rate for 40 seconds, then 200 ppm
+    // fast.
+    let device_tick = |t: DateTime<Utc>| {
+        let micros = (t - epoch).num_microseconds().unwrap() as f64;
+        let fast_micros = (micros - 40e6).max(0.0);
+        (micros + fast_micros * 200e-6).round() as u64
+    };
+
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20))
+        .with_segment_gain_change(Some(50e-6));
+    for i in 0..80 {
+        let t = epoch + TimeDelta::seconds(i);
+        model.update(t, t, device_tick(t), device_tick(t));
+    }
+    assert_eq!(model.segments.len(), 1);
+
+    // A time in each segment maps back to its tick, where inverting the
+    // latest segment alone would be off by thousands of ticks in the first.
+    for seconds in [10, 20, 60, 70] {
+        let expected = epoch + TimeDelta::seconds(seconds);
+        let tick = device_tick(expected);
+        let utc = model.compute_utc(tick).unwrap();
+        let error = utc - expected;
+        assert!(error.num_microseconds().unwrap().abs() <= 1);
+        assert_eq!(
+            model.compute_device_ticks(utc),
+            Some(tick),
+            "at {seconds} s"
+        );
+    }
+}
+
+#[test]
+fn test_compute_utc_before_first_sample() {
+    let epoch = test_epoch();
+    let device_tick = |t: DateTime<Utc>| {
+        (5_000_000_000 + ((t - epoch).num_microseconds().unwrap() as f64 * 0.99995) as i64) as u64
+    };
+
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    for i in 0..20 {
+        let t = epoch + TimeDelta::seconds(i);
+        model.update(t, t, device_tick(t), device_tick(t));
+    }
+    // A trigger from ten seconds before the first ping is extrapolated.
+    let before = epoch - TimeDelta::seconds(10);
+    let error = model.compute_utc(device_tick(before)).unwrap() - before;
+    assert!(error.num_microseconds().unwrap().abs() <= 1);
+}
+
+#[test]
+fn test_compute_utc_is_none_until_ready() {
+    let epoch = test_epoch();
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let query = test_device_tick(epoch + TimeDelta::seconds(30));
+
+    // No samples, so no device epoch.
+    assert!(!model.is_ready());
+    assert_eq!(model.compute_utc(query), None);
+
+    // The device epoch is set but there are too few samples to fit.
+    for i in 0..9 {
+        let t = epoch + TimeDelta::seconds(i);
+        model.update(t, t, test_device_tick(t), test_device_tick(t));
+        assert!(!model.is_ready());
+        assert_eq!(model.compute_utc(query), None);
+    }
+
+    // The tenth sample makes the model ready.
+    let t = epoch + TimeDelta::seconds(9);
+    model.update(t, t, test_device_tick(t), test_device_tick(t));
+    assert!(model.is_ready());
+    let error = model.compute_utc(query).unwrap() - (epoch + TimeDelta::seconds(30));
+    assert!(error.num_microseconds().unwrap().abs() <= 1);
+}
+
+#[test]
+fn test_max_residual_withholds_noisy_fit() {
+    let epoch = test_epoch();
+    let model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let query = test_device_tick(epoch + TimeDelta::seconds(30));
+
+    let mut model = model.with_max_residual(Some(0.0005));
+    let mut n_ready = None;
+    for i in 0..100 {
+        let t = epoch + TimeDelta::seconds(i);
+        // The first samples are 1 ms early or late on the host.
+        let noise = match i {
+            0..=5 if i % 2 == 0 => TimeDelta::milliseconds(1),
+            0..=5 => TimeDelta::milliseconds(-1),
+            _ => TimeDelta::zero(),
+        };
+        model.update(
+            t + noise,
+            t + noise,
+            test_device_tick(t),
+            test_device_tick(t),
+        );
+        assert!(model.residual_seconds().is_some() == (i >= 9));
+        if model.is_ready() {
+            n_ready.get_or_insert(i + 1);
+        } else {
+            assert_eq!(model.compute_utc(query), None);
+        }
+    }
+    // Ten samples suffice to fit, but not to trust the fit.
+    let n_ready = n_ready.unwrap();
+    assert!(n_ready > 10, "ready after {n_ready} samples");
+    assert!(model.residual_seconds().unwrap() <= 0.0005);
+    let error = model.compute_utc(query).unwrap() - (epoch + TimeDelta::seconds(30));
+    assert!(error.num_microseconds().unwrap().abs() < 500);
+}
+
+#[test]
+fn test_latest_error_measures_jitter() {
+    let epoch = test_epoch();
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    for i in 0..20 {
+        let t = epoch + TimeDelta::seconds(i);
+        model.update(t, t, test_device_tick(t), test_device_tick(t));
+        assert_eq!(model.latest_error_seconds().is_some(), i >= 9);
+    }
+    assert!(model.latest_error_seconds().unwrap().abs() < 1e-6);
+
+    // A measurement 5 ms late on the host is predicted early by the model.
+    let t = epoch + TimeDelta::seconds(20);
+    let late = TimeDelta::milliseconds(5);
+    model.update(t + late, t + late, test_device_tick(t), test_device_tick(t));
+    let error = model.latest_error_seconds().unwrap();
+    assert!(error < -0.004 && error > -0.005, "error {error}");
+}
+
+#[test]
+fn test_dump() {
+    let epoch = test_epoch();
+    let mut model = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let value = serde_json::to_value(model.dump()).unwrap();
+    assert_eq!(value["gain"], serde_json::Value::Null);
+
+    for i in 0..10 {
+        let t = epoch + TimeDelta::seconds(i);
+        let tick = 1000 + test_device_tick(t);
+        model.update(t, t, tick, tick);
+    }
+    let value = serde_json::to_value(model.dump()).unwrap();
+    assert_eq!(value["device_epoch"], 1000);
+    assert_eq!(value["host_units"], "microseconds");
+    assert!((value["gain"].as_f64().unwrap() - 1.0).abs() < 1e-9);
+    assert_eq!(value["samples"].as_array().unwrap().len(), 10);
+    assert_eq!(value["samples"][1], serde_json::json!([1e6, 1e6]));
+}
+
+#[test]
+fn test_transmit_time_after_send_is_more_accurate() {
+    let epoch = test_epoch();
+    let transmission = TimeDelta::microseconds(200);
+    let mut before_send = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let mut after_send = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+
+    // Each ping waits a varying time in the host's buffers after `send` is
+    // called and before it is written.
+    for i in 0..20 {
+        let send_called = epoch + TimeDelta::seconds(i);
+        let buffered = TimeDelta::microseconds(500 + 2_000 * (i % 3));
+        let written = send_called + buffered;
+        let ping_received = written + transmission;
+        let t1 = ping_received + transmission;
+        let tick = test_device_tick(ping_received);
+        before_send.update(send_called, t1, tick, tick);
+        after_send.update(written, t1, tick, tick);
+    }
+
+    let expected = epoch + TimeDelta::seconds(30);
+    let error = |model: &ClockModel| {
+        (model.compute_utc(test_device_tick(expected)).unwrap() - expected)
+            .num_microseconds()
+            .unwrap()
+            .abs()
+    };
+    assert!(error(&after_send) <= 1);
+    assert!(error(&before_send) > 500);
+}
+
+#[test]
+fn test_piecewise_linear_segments_are_recovered() {
+    let epoch = test_epoch();
+    // The device clock runs at nominal rate, then 200 ppm fast as it warms,
+    // then 100 ppm slow as it cools, for 40 seconds each.
+    let rates = [1.0, 1.0 + 200e-6, 1.0 - 100e-6];
+    let device_tick = |t: DateTime<Utc>| {
+        let mut seconds = (t - epoch).num_microseconds().unwrap() as f64 / 1e6;
+        let mut micros = 0.0;
+        for (i, rate) in rates.iter().enumerate() {
+            let in_segment = if i + 1 < rates.len() {
+                seconds.min(40.0)
+            } else {
+                seconds
+            };
+            micros += in_segment * 1e6 * rate;
+            seconds -= in_segment;
+        }
+        micros.round() as u64
+    };
+
+    let mut single = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20));
+    let mut piecewise = ClockModel::with_epoch(epoch, TimeDelta::milliseconds(20))
+        .with_segment_gain_change(Some(50e-6));
+    let mut new_segments = Vec::new();
+    for i in 0..120 {
+        let t = epoch + TimeDelta::seconds(i);
+        for model in [&mut single, &mut piecewise] {
+            model.update(t, t, device_tick(t), device_tick(t));
+        }
+        new_segments.extend(piecewise.take_new_segment());
+    }
+
+    // Each change in rate starts a segment at the sample at the change or,
+    // as that lies on both lines, the next.
+    assert_eq!(new_segments.len(), 2);
+    for (tick, s) in new_segments.iter().zip([40, 80]) {
+        let at = |s| device_tick(epoch + TimeDelta::seconds(s));
+        assert!([at(s), at(s + 1)].contains(tick), "segment start {tick}");
+    }
+    assert_eq!(piecewise.segments.len(), 2);
+    let gains: Vec<f64> = piecewise
+        .segments
+        .iter()
+        .map(|s| s.model.gain)
+        .chain(piecewise.gain())
+        .collect();
+    for (gain, rate) in gains.iter().zip(rates) {
+        assert!(
+            (gain * rate - 1.0).abs() < 1e-9,
+            "gain {gain} for rate {rate}"
+        );
+    }
+
+    // A trigger in each segment is timed by that segment's fit, where one
+    // line through all the samples is off by hundreds of microseconds.
+    for seconds in [20.5, 60.5, 100.5] {
+        let expected = epoch + TimeDelta::milliseconds((seconds * 1e3) as i64);
+        let error = |model: &ClockModel| {
+            (model.compute_utc(device_tick(expected)).unwrap() - expected)
+                .num_microseconds()
+                .unwrap()
+                .abs()
+        };
+        assert!(error(&piecewise) <= 1, "error at {seconds} s");
+        assert!(error(&single) > 100, "single line error at {seconds} s");
+    }
+
+    let value = serde_json::to_value(piecewise.dump()).unwrap();
+    assert_eq!(value["segments"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        value["segments"][1]["end_device_time"],
+        new_segments[1] as f64
+    );
+}
+
+#[test]
+fn test_precision_after_30_days() {
+    let epoch = test_epoch();
+    let days_30 = 30 * 86_400;
+    // A 100 MHz device clock running 20 ppm slow, so that each tick is
+    // 10.0002 ns, and a 1 MHz one running 20 ppm fast, both booted long
+    // before the session.
+    let cases = [
+        (TimeBasis::Nanos, 10, 5_000, 1),
+        (TimeBasis::Micros, 1, 50_000, -1),
+    ];
+    for (basis, ns_per_tick, ticks_per_ns_ppm, sign) in cases {
+        let boot_ticks = 1u64 << 40;
+        // Exact host nanoseconds since the epoch of a tick count since the
+        // epoch which is a multiple of `ticks_per_ns_ppm`.
+        let host_nanos =
+            |ticks: u64| (ticks * ns_per_tick) as i64 + sign * (ticks / ticks_per_ns_ppm) as i64;
+        let ticks_at = |seconds: u64| {
+            let ticks = seconds * 1_000_000_000 / ns_per_tick;
+            ticks - ticks % ticks_per_ns_ppm
+        };
+        let mut model = ClockModel::with_basis(epoch, TimeDelta::milliseconds(20), basis);
+        let mut sample = |ticks: u64| {
+            let t = epoch + TimeDelta::nanoseconds(host_nanos(ticks));
+            model.update(t, t, boot_ticks + ticks, boot_ticks + ticks);
+        };
+        // The first sample fixes the device epoch, and 30 days later only
+        // recent samples are kept.
+        sample(0);
+        for i in 0..200 {
+            sample(ticks_at(days_30 + i));
+        }
+
+        let ticks = ticks_at(days_30 + 150) + ticks_per_ns_ppm * 1_234;
+        let expected = epoch + TimeDelta::nanoseconds(host_nanos(ticks));
+        let error = model.compute_utc(boot_ticks + ticks).unwrap() - expected;
+        assert!(
+            error.num_nanoseconds().unwrap().abs() < 1_000,
+            "{basis:?}: error {error}"
+        );
     }
 }
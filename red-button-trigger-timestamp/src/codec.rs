@@ -0,0 +1,348 @@
+use red_button_trigger_timestamp_comms::{crc::Checked, FromDevice, ToDevice, MAX_FROM_DEVICE_LEN};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder, LinesCodec, LinesCodecError},
+};
+
+/// Newline delimited JSON framing of messages to and from the device, with or
+/// without a CRC (see [red_button_trigger_timestamp_comms::crc]).
+///
+/// Whether the firmware was built with the `crc` feature is detected from the
+/// first message it sends, e.g. its reply to the version request or a beacon.
+/// Until then, only version requests, which are harmless to receive twice, can
+/// be sent, and each is sent in both framings, of which the firmware decodes
+/// only its own (see [red_button_trigger_timestamp_comms::crc]). Messages from
+/// the device which fail their CRC check are logged and discarded.
+///
+/// Lines which are not messages at all, e.g. truncated by bytes lost in an
+/// overrun of the host's serial buffer, are counted and skipped, so decoding
+/// resumes at the next newline. So are lines longer than any message, e.g.
+/// noise in which no newline arrives, which are dropped as they are read
+/// rather than buffered.
+pub(crate) struct DeviceCodec {
+    lines: LinesCodec,
+    /// Whether the device frames messages with a CRC, once known
+    crc: Option<bool>,
+    received: ReceivedBytes,
+    discarded: DiscardedLines,
+    last_read: LastRead,
+    /// Bytes left in the source buffer by the previous decode
+    buffered: usize,
+}
+
+impl Default for DeviceCodec {
+    fn default() -> Self {
+        Self {
+            // The longest message, without its newline, fits.
+            lines: LinesCodec::new_with_max_length(MAX_FROM_DEVICE_LEN),
+            crc: None,
+            received: Default::default(),
+            discarded: Default::default(),
+            last_read: Default::default(),
+            buffered: 0,
+        }
+    }
+}
+
+/// Count of the bytes received from the device, whether or not they decode,
+/// which can still be read once the framed device is split.
+#[derive(Clone, Default)]
+pub(crate) struct ReceivedBytes(Arc<AtomicU64>);
+
+impl ReceivedBytes {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Count of the lines received from the device which were not messages and
+/// were skipped, which can still be read once the framed device is split.
+#[derive(Clone, Default)]
+pub(crate) struct DiscardedLines(Arc<AtomicU64>);
+
+impl DiscardedLines {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Time at which bytes were last read from the device, which can still be
+/// read once the framed device is split. Each message is decoded without
+/// reading further once its line is complete, so this is when the end of the
+/// latest decoded message was read.
+#[derive(Clone, Default)]
+pub(crate) struct LastRead(Arc<Mutex<Option<Instant>>>);
+
+impl LastRead {
+    pub(crate) fn get(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum CodecError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// A request other than a version request was sent before the device's
+    /// framing was known.
+    FramingUnknown(ToDevice),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Json(e) => write!(f, "could not encode message: {e}"),
+            Self::FramingUnknown(msg) => write!(
+                f,
+                "cannot send {msg:?} before the device's message framing is known"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Json(e) => Some(e),
+            Self::FramingUnknown(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl Decoder for DeviceCodec {
+    type Item = FromDevice;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<FromDevice>, CodecError> {
+        let new_bytes = src.len().saturating_sub(self.buffered);
+        self.received
+            .0
+            .fetch_add(new_bytes as u64, Ordering::Relaxed);
+        if new_bytes > 0 {
+            *self.last_read.0.lock().unwrap() = Some(Instant::now());
+        }
+        let result = self.decode_lines(src);
+        self.buffered = src.len();
+        result
+    }
+}
+
+impl DeviceCodec {
+    /// The count of bytes received, shared with this codec.
+    pub(crate) fn received_bytes(&self) -> ReceivedBytes {
+        self.received.clone()
+    }
+
+    /// The count of lines skipped, shared with this codec.
+    pub(crate) fn discarded_lines(&self) -> DiscardedLines {
+        self.discarded.clone()
+    }
+
+    /// The time of the latest read, shared with this codec.
+    pub(crate) fn last_read(&self) -> LastRead {
+        self.last_read.clone()
+    }
+
+    fn decode_lines(&mut self, src: &mut BytesMut) -> Result<Option<FromDevice>, CodecError> {
+        loop {
+            let line = match self.lines.decode(src) {
+                Ok(Some(line)) => line,
+                Ok(None) => return Ok(None),
+                // The line, which is consumed, is not UTF-8.
+                Err(LinesCodecError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    self.discard("invalid UTF-8");
+                    continue;
+                }
+                // The line so far is consumed, as is the rest of it up to the
+                // next newline once it arrives.
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    self.discard("line longer than any message");
+                    continue;
+                }
+                Err(LinesCodecError::Io(e)) => return Err(e.into()),
+            };
+            if let Some(msg) = self.decode_line(&line)? {
+                return Ok(Some(msg));
+            }
+        }
+    }
+
+    fn discard(&self, line: &str) {
+        tracing::debug!("Discarding line from device which is not a message: {line}");
+        self.discarded.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decode one line, returning `None` if it fails its CRC check.
+    fn decode_line(&mut self, line: &str) -> Result<Option<FromDevice>, CodecError> {
+        if let Ok(checked) = serde_json::from_str::<Checked<FromDevice>>(line) {
+            self.crc.get_or_insert(true);
+            let encoded = serde_json::to_vec(&checked.msg).map_err(CodecError::Json)?;
+            if !checked.matches(&encoded) {
+                tracing::warn!("Discarding message from device which fails its CRC check: {line}");
+                return Ok(None);
+            }
+            return Ok(Some(checked.msg));
+        }
+        let Ok(msg) = serde_json::from_str(line) else {
+            self.discard(line);
+            return Ok(None);
+        };
+        self.crc.get_or_insert(false);
+        Ok(Some(msg))
+    }
+}
+
+impl Encoder<ToDevice> for DeviceCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, msg: ToDevice, dst: &mut BytesMut) -> Result<(), CodecError> {
+        if self.crc.is_none() && msg != ToDevice::VersionRequest {
+            return Err(CodecError::FramingUnknown(msg));
+        }
+        let encoded = serde_json::to_vec(&msg).map_err(CodecError::Json)?;
+        if self.crc != Some(true) {
+            dst.extend_from_slice(&encoded);
+            dst.extend_from_slice(b"\n");
+        }
+        if self.crc != Some(false) {
+            let checked = Checked::new(&msg, &encoded);
+            dst.extend_from_slice(&serde_json::to_vec(&checked).map_err(CodecError::Json)?);
+            dst.extend_from_slice(b"\n");
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_crc_framing() {
+    let msg = FromDevice::Beacon(1234);
+    let encoded = serde_json::to_vec(&msg).unwrap();
+    let mut line = serde_json::to_vec(&Checked::new(&msg, &encoded)).unwrap();
+    line.push(b'\n');
+
+    let mut codec = DeviceCodec::default();
+    let mut src = BytesMut::from(&line[..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(msg));
+    assert_eq!(codec.crc, Some(true));
+
+    // A flipped bit which still decodes, changing 1234 to 1235, is discarded.
+    let mut corrupted = line.clone();
+    let i = corrupted.iter().position(|b| *b == b'4').unwrap();
+    corrupted[i] ^= 1;
+    let mut src = BytesMut::from(&corrupted[..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    assert!(src.is_empty());
+
+    // Requests are now sent only with a CRC.
+    let mut dst = BytesMut::new();
+    codec.encode(ToDevice::Ping, &mut dst).unwrap();
+    assert_eq!(&dst[..], &b"{\"msg\":\"Ping\",\"crc\":6456}\n"[..]);
+}
+
+#[test]
+fn test_received_bytes() {
+    let mut codec = DeviceCodec::default();
+    let received = codec.received_bytes();
+    // Bytes which are not a complete line are counted once.
+    let mut src = BytesMut::from(&b"\x00\xff{\"Beacon\""[..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    assert_eq!(received.get(), 11);
+    src.extend_from_slice(b":1234}\n");
+    // The line is noise, not a message.
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    assert_eq!(received.get(), 18);
+}
+
+#[test]
+fn test_discarded_lines() {
+    let mut codec = DeviceCodec::default();
+    let discarded = codec.discarded_lines();
+    // A line truncated by lost bytes, and one which is not UTF-8, are skipped
+    // and decoding resumes with the next line.
+    let mut src = BytesMut::from(&b"{\"Beac\n\xff\xfe\n{\"Beacon\":1234}\n"[..]);
+    assert_eq!(
+        codec.decode(&mut src).unwrap(),
+        Some(FromDevice::Beacon(1234))
+    );
+    assert_eq!(discarded.get(), 2);
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_overlong_line() {
+    let mut codec = DeviceCodec::default();
+    let discarded = codec.discarded_lines();
+    // Noise with no newline is dropped once it is longer than any message,
+    // rather than buffered without limit.
+    let mut src = BytesMut::from(&[b'x'; 2 * MAX_FROM_DEVICE_LEN][..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    assert_eq!(discarded.get(), 1);
+    assert!(src.len() <= MAX_FROM_DEVICE_LEN);
+    src.extend_from_slice(&[b'x'; 2 * MAX_FROM_DEVICE_LEN]);
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    assert!(src.len() <= MAX_FROM_DEVICE_LEN);
+    // Decoding resumes after the next newline.
+    src.extend_from_slice(b"xx\n{\"Beacon\":1234}\n");
+    assert_eq!(
+        codec.decode(&mut src).unwrap(),
+        Some(FromDevice::Beacon(1234))
+    );
+    assert_eq!(discarded.get(), 1);
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_last_read() {
+    let mut codec = DeviceCodec::default();
+    let last_read = codec.last_read();
+    assert_eq!(last_read.get(), None);
+    // Two messages read at once are both timed by that read.
+    let mut src = BytesMut::from(&b"{\"Beacon\":1}\n{\"Beacon\":2}\n"[..]);
+    assert!(codec.decode(&mut src).unwrap().is_some());
+    let read_at = last_read.get().unwrap();
+    assert!(codec.decode(&mut src).unwrap().is_some());
+    assert_eq!(last_read.get(), Some(read_at));
+    // A new read is timed afresh.
+    src.extend_from_slice(b"{\"Beacon\":3}\n");
+    assert!(codec.decode(&mut src).unwrap().is_some());
+    assert!(last_read.get().unwrap() >= read_at);
+}
+
+#[test]
+fn test_plain_framing() {
+    let mut codec = DeviceCodec::default();
+    // Until the framing is known, only version requests are sent, in both
+    // framings.
+    let mut dst = BytesMut::new();
+    codec.encode(ToDevice::VersionRequest, &mut dst).unwrap();
+    assert!(dst.starts_with(b"\"VersionRequest\"\n{\"msg\":\"VersionRequest\""));
+    assert!(matches!(
+        codec.encode(ToDevice::MarkEpoch, &mut dst),
+        Err(CodecError::FramingUnknown(ToDevice::MarkEpoch))
+    ));
+
+    let mut src = BytesMut::from(&b"{\"Beacon\":1234}\n"[..]);
+    assert_eq!(
+        codec.decode(&mut src).unwrap(),
+        Some(FromDevice::Beacon(1234))
+    );
+    assert_eq!(codec.crc, Some(false));
+    let mut dst = BytesMut::new();
+    codec.encode(ToDevice::Ping, &mut dst).unwrap();
+    assert_eq!(&dst[..], &b"\"Ping\"\n"[..]);
+}
@@ -0,0 +1,102 @@
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::ffi::OsString;
+
+/// Read options from a TOML file, or a JSON file if its extension is `.json`,
+/// as command line arguments. Each key is the name of a long option, with `_`
+/// or `-` between words, e.g. `output_dir = "/data"` for `--output-dir
+/// /data`. A `true` value gives a flag and `false` leaves it out, and an array
+/// repeats the option for each value.
+pub(crate) fn load_args(path: &std::path::Path) -> anyhow::Result<Vec<OsString>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading config file {}", path.display()))?;
+    let table: serde_json::Map<String, serde_json::Value> =
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text)?
+        } else {
+            toml::from_str(&text)?
+        };
+    to_args(&table).with_context(|| format!("in config file {}", path.display()))
+}
+
+fn to_args(table: &serde_json::Map<String, serde_json::Value>) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "config" {
+            anyhow::bail!("a config file cannot include another");
+        }
+        let option = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            serde_json::Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            match value {
+                serde_json::Value::Bool(true) => args.push(option.clone().into()),
+                serde_json::Value::Bool(false) => {}
+                serde_json::Value::String(s) => {
+                    args.extend([option.clone(), s.clone()].map(Into::into))
+                }
+                serde_json::Value::Number(n) => {
+                    args.extend([option.clone(), n.to_string()].map(Into::into))
+                }
+                _ => anyhow::bail!("unsupported value for {key}: {value}"),
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// The arguments to parse: those from the config file at `path` followed by
+/// the command line's, so that an option given on the command line overrides
+/// the file.
+pub(crate) fn merged_args(
+    cli_args: Vec<OsString>,
+    path: &std::path::Path,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut cli_args = cli_args.into_iter();
+    let mut args: Vec<OsString> = cli_args.next().into_iter().collect();
+    args.extend(load_args(path)?);
+    args.extend(cli_args);
+    Ok(args)
+}
+
+#[test]
+fn test_command_line_overrides_config_file() {
+    use clap::Parser;
+
+    let dir = std::env::temp_dir().join(format!("trigger-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("rig.toml");
+    std::fs::write(
+        &path,
+        "output_dir = \"/data/rig1\"\nbeacon-ms = 500\nquiet = true\nextra_column = [\"rig=1\", \"site=lab\"]\n",
+    )
+    .unwrap();
+
+    let cli_args = [
+        "trigger",
+        "--config",
+        path.to_str().unwrap(),
+        "--beacon-ms",
+        "250",
+        "/dev/ttyACM0",
+    ]
+    .map(OsString::from)
+    .to_vec();
+    let args = merged_args(cli_args, &path).unwrap();
+    let opt = crate::Cli::try_parse_from(args).unwrap();
+    // From the file
+    assert_eq!(opt.output_dir, "/data/rig1");
+    assert!(opt.quiet);
+    assert_eq!(opt.extra_column.len(), 2);
+    // From the command line, overriding the file
+    assert_eq!(opt.beacon_ms, Some(250));
+    assert_eq!(opt.device_path.as_deref(), Some("/dev/ttyACM0"));
+
+    // Unknown keys are rejected like unknown options.
+    std::fs::write(&path, "no_such_option = 1\n").unwrap();
+    let args = merged_args(vec!["trigger".into()], &path).unwrap();
+    assert!(crate::Cli::try_parse_from(args).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
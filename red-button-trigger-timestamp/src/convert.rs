@@ -0,0 +1,235 @@
+use crate::trigger_log::{TriggerRow, TRIGGER_ROW_COLUMNS};
+use arrow_array::{
+    ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray, TimestampNanosecondArray,
+    UInt16Array, UInt64Array, UInt8Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::{path::Path, sync::Arc};
+
+/// Range of times, each end inclusive and optional, of rows to convert.
+#[derive(Default)]
+pub(crate) struct TimeWindow {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl TimeWindow {
+    pub(crate) fn new(
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Self> {
+        if let (Some(since), Some(until)) = (since, until) {
+            if since > until {
+                anyhow::bail!("--since {since} is later than --until {until}");
+            }
+        }
+        let to_nanos = |t: DateTime<Utc>| {
+            crate::trigger_log::to_epoch_nanos(t)
+                .ok_or_else(|| anyhow::anyhow!("time {t} is out of range"))
+        };
+        Ok(Self {
+            since: since.map(to_nanos).transpose()?,
+            until: until.map(to_nanos).transpose()?,
+        })
+    }
+
+    fn contains(&self, epoch_nanos_utc: i64) -> bool {
+        self.since.is_none_or(|since| since <= epoch_nanos_utc)
+            && self.until.is_none_or(|until| epoch_nanos_utc <= until)
+    }
+}
+
+/// Convert a trigger CSV file to Apache Parquet, keeping only rows whose
+/// `epoch_nanos_utc` is within `window`.
+///
+/// `timestamp_local` is stored as a timezone-less nanosecond timestamp of the
+/// local wall clock time at which the trigger was recorded. Columns added with
+/// `--extra-column` are kept as string columns after the others. Malformed
+/// rows are skipped with a warning.
+pub(crate) fn csv_to_parquet(
+    input: &Path,
+    output: &Path,
+    window: &TimeWindow,
+) -> anyhow::Result<()> {
+    let rdr = csv::Reader::from_path(input)
+        .with_context(|| format!("opening file {}", input.display()))?;
+    let batch = read_batch(rdr, window)?;
+
+    let fd = std::fs::File::create(output)
+        .with_context(|| format!("creating file {}", output.display()))?;
+    let mut writer = parquet::arrow::ArrowWriter::try_new(fd, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    tracing::info!("Wrote {} rows to {}", batch.num_rows(), output.display());
+    Ok(())
+}
+
+/// Read the rows of a trigger CSV file within `window`.
+fn read_batch<R: std::io::Read>(
+    mut rdr: csv::Reader<R>,
+    window: &TimeWindow,
+) -> anyhow::Result<RecordBatch> {
+    let headers = rdr.headers()?.clone();
+    // Columns added with --extra-column, by index and name
+    let extra_columns: Vec<(usize, String)> = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| !TRIGGER_ROW_COLUMNS.contains(name))
+        .map(|(i, name)| (i, name.to_string()))
+        .collect();
+    let mut extra_values: Vec<Vec<Option<String>>> = vec![Vec::new(); extra_columns.len()];
+
+    let mut timestamp_local = Vec::new();
+    let mut epoch_nanos_utc = Vec::new();
+    let mut interval_seconds = Vec::new();
+    let mut hold_seconds = Vec::new();
+    let mut device_timestamp = Vec::new();
+    let mut received_epoch_nanos_utc = Vec::new();
+    let mut clock_drift_seconds = Vec::new();
+    let mut adc_level = Vec::new();
+    let mut first_channel = Vec::new();
+    let mut channel_delta_seconds = Vec::new();
+    let mut host_delay_seconds = Vec::new();
+    let mut annotation = Vec::new();
+    let mut n_skipped = 0;
+    let mut n_outside = 0;
+    for result in rdr.records() {
+        let parsed = result.and_then(|record| {
+            let row = record.deserialize::<TriggerRow>(Some(&headers))?;
+            Ok((row, record))
+        });
+        let (row, record) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                tracing::warn!("Skipping malformed row: {e}");
+                n_skipped += 1;
+                continue;
+            }
+        };
+        if !window.contains(row.epoch_nanos_utc) {
+            n_outside += 1;
+            continue;
+        }
+        let Some(local_nanos) = row
+            .timestamp_local
+            .naive_local()
+            .and_utc()
+            .timestamp_nanos_opt()
+        else {
+            tracing::warn!(
+                "Skipping row with out of range time {}",
+                row.timestamp_local
+            );
+            n_skipped += 1;
+            continue;
+        };
+        timestamp_local.push(local_nanos);
+        epoch_nanos_utc.push(row.epoch_nanos_utc);
+        interval_seconds.push(row.interval_seconds);
+        hold_seconds.push(row.hold_seconds);
+        device_timestamp.push(row.device_timestamp);
+        received_epoch_nanos_utc.push(row.received_epoch_nanos_utc);
+        clock_drift_seconds.push(row.clock_drift_seconds);
+        adc_level.push(row.adc_level);
+        first_channel.push(row.first_channel);
+        channel_delta_seconds.push(row.channel_delta_seconds);
+        host_delay_seconds.push(row.host_delay_seconds);
+        annotation.push(row.annotation);
+        for ((i, _), values) in extra_columns.iter().zip(&mut extra_values) {
+            values.push(record.get(*i).map(str::to_string));
+        }
+    }
+
+    if n_outside > 0 {
+        tracing::info!("Left out {} rows outside the time window.", n_outside);
+    }
+    if n_skipped > 0 {
+        tracing::warn!("Skipped {} malformed rows.", n_skipped);
+    }
+
+    let mut fields = vec![
+        Field::new(
+            "timestamp_local",
+            DataType::Timestamp(TimeUnit::Nanosecond, None),
+            false,
+        ),
+        Field::new("epoch_nanos_utc", DataType::Int64, false),
+        Field::new("interval_seconds", DataType::Float64, true),
+        Field::new("hold_seconds", DataType::Float64, true),
+        Field::new("device_timestamp", DataType::UInt64, true),
+        Field::new("received_epoch_nanos_utc", DataType::Int64, true),
+        Field::new("clock_drift_seconds", DataType::Float64, true),
+        Field::new("adc_level", DataType::UInt16, true),
+        Field::new("first_channel", DataType::UInt8, true),
+        Field::new("channel_delta_seconds", DataType::Float64, true),
+        Field::new("host_delay_seconds", DataType::Float64, true),
+        Field::new("annotation", DataType::Utf8, true),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(TimestampNanosecondArray::from(timestamp_local)),
+        Arc::new(Int64Array::from(epoch_nanos_utc)),
+        Arc::new(Float64Array::from(interval_seconds)),
+        Arc::new(Float64Array::from(hold_seconds)),
+        Arc::new(UInt64Array::from(device_timestamp)),
+        Arc::new(Int64Array::from(received_epoch_nanos_utc)),
+        Arc::new(Float64Array::from(clock_drift_seconds)),
+        Arc::new(UInt16Array::from(adc_level)),
+        Arc::new(UInt8Array::from(first_channel)),
+        Arc::new(Float64Array::from(channel_delta_seconds)),
+        Arc::new(Float64Array::from(host_delay_seconds)),
+        Arc::new(StringArray::from(annotation)),
+    ];
+    for ((_, name), values) in extra_columns.into_iter().zip(extra_values) {
+        fields.push(Field::new(name, DataType::Utf8, true));
+        columns.push(Arc::new(StringArray::from(values)));
+    }
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns,
+    )?)
+}
+
+#[test]
+fn test_extra_columns_are_kept() {
+    let csv = format!(
+        "{},subject\n\
+         1970-01-01T00:00:01Z,1000000000,,,1,,,,,,,,s01\n\
+         1970-01-01T00:00:02Z,2000000000,,,2,,,,,,,,s02\n",
+        TRIGGER_ROW_COLUMNS.join(",")
+    );
+    let batch = read_batch(
+        csv::Reader::from_reader(csv.as_bytes()),
+        &TimeWindow::default(),
+    )
+    .unwrap();
+    assert_eq!(batch.num_rows(), 2);
+    let subject = batch
+        .column_by_name("subject")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(subject.value(0), "s01");
+    assert_eq!(subject.value(1), "s02");
+}
+
+#[test]
+fn test_time_window() {
+    let t = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let since = t("2024-01-01T00:00:00Z");
+    let until = t("2024-01-01T00:00:01Z");
+    let nanos = |t| crate::trigger_log::to_epoch_nanos(t).unwrap();
+
+    let window = TimeWindow::new(Some(since), Some(until)).unwrap();
+    assert!(window.contains(nanos(since)));
+    assert!(window.contains(nanos(until)));
+    assert!(!window.contains(nanos(since) - 1));
+    assert!(!window.contains(nanos(until) + 1));
+    let window = TimeWindow::new(None, Some(until)).unwrap();
+    assert!(window.contains(i64::MIN));
+    assert!(TimeWindow::default().contains(i64::MAX));
+    assert!(TimeWindow::new(Some(until), Some(since)).is_err());
+}
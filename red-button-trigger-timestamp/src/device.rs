@@ -0,0 +1,659 @@
+use crate::{codec::DeviceCodec, error::TriggerLoggerError};
+use chrono::Timelike;
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use futures::{SinkExt, StreamExt};
+use red_button_trigger_timestamp_comms::{
+    capabilities, rtc::DailyWindow, DeviceConfig, FromDevice, ToDevice, VersionResponse,
+    USB_VID_PID,
+};
+use tokio_serial::SerialPortBuilderExt;
+
+fn to_device_name(spi: &tokio_serial::SerialPortInfo) -> String {
+    let name = spi.port_name.clone();
+    // Ports may be listed by their sysfs path on Linux.
+    #[cfg(target_os = "linux")]
+    let name = name.replace("/sys/class/tty/", "/dev/");
+    name
+}
+
+/// A serial port which may be the device, as printed by the list subcommand.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct PortInfo {
+    pub(crate) path: String,
+    /// USB metadata, all `None` for ports which are not USB.
+    pub(crate) vid: Option<u16>,
+    pub(crate) pid: Option<u16>,
+    pub(crate) serial_number: Option<String>,
+    pub(crate) manufacturer: Option<String>,
+    pub(crate) product: Option<String>,
+    /// Whether the USB IDs are those of this firmware.
+    pub(crate) is_trigger_device: bool,
+}
+
+impl From<&tokio_serial::SerialPortInfo> for PortInfo {
+    fn from(spi: &tokio_serial::SerialPortInfo) -> Self {
+        let usb = match &spi.port_type {
+            tokio_serial::SerialPortType::UsbPort(usb) => Some(usb),
+            _ => None,
+        };
+        Self {
+            path: to_device_name(spi),
+            vid: usb.map(|u| u.vid),
+            pid: usb.map(|u| u.pid),
+            serial_number: usb.and_then(|u| u.serial_number.clone()),
+            manufacturer: usb.and_then(|u| u.manufacturer.clone()),
+            product: usb.and_then(|u| u.product.clone()),
+            is_trigger_device: usb.is_some_and(|u| (u.vid, u.pid) == USB_VID_PID),
+        }
+    }
+}
+
+pub(crate) fn available_ports() -> anyhow::Result<Vec<PortInfo>> {
+    Ok(tokio_serial::available_ports()?
+        .iter()
+        .map(PortInfo::from)
+        // The built-in serial port of most Linux machines is never the device.
+        .filter(|x| !cfg!(target_os = "linux") || x.path != "/dev/ttyS0")
+        .collect())
+}
+
+pub(crate) fn available_port_names() -> anyhow::Result<Vec<String>> {
+    Ok(available_ports()?.into_iter().map(|p| p.path).collect())
+}
+
+/// Reads the count of bytes which the operating system lost to overruns of a
+/// serial port's receive buffers, as happens when the host does not read from
+/// the port fast enough. Only Linux reports this, for drivers which count
+/// overruns, including USB CDC ACM. It must not outlive the port.
+pub(crate) struct OverrunCounter {
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+}
+
+impl OverrunCounter {
+    pub(crate) fn new(framed: &DeviceFramed) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        let _ = framed;
+        Self {
+            #[cfg(target_os = "linux")]
+            fd: std::os::unix::io::AsRawFd::as_raw_fd(framed.get_ref()),
+        }
+    }
+
+    /// The count of bytes lost since the port was opened, or `None` where
+    /// it is not reported.
+    pub(crate) fn get(&self) -> Option<u64> {
+        #[cfg(target_os = "linux")]
+        {
+            // struct serial_icounter_struct of <linux/serial.h>
+            #[repr(C)]
+            #[derive(Default)]
+            struct SerialIcounter {
+                cts: i32,
+                dsr: i32,
+                rng: i32,
+                dcd: i32,
+                rx: i32,
+                tx: i32,
+                frame: i32,
+                overrun: i32,
+                parity: i32,
+                brk: i32,
+                buf_overrun: i32,
+                reserved: [i32; 9],
+            }
+            let mut counts = SerialIcounter::default();
+            if unsafe { libc::ioctl(self.fd, libc::TIOCGICOUNT, &mut counts) } != 0 {
+                return None;
+            }
+            Some(counts.overrun as u64 + counts.buf_overrun as u64)
+        }
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+}
+
+/// Check that `device_path` plausibly names a serial port before opening it.
+///
+/// `list_ports` enumerates the serial ports. Enumeration can fail where
+/// opening a known path works, e.g. in a container or with restricted
+/// permissions, so its failure is not an error here.
+fn check_is_serial_port(
+    device_path: &str,
+    list_ports: impl Fn() -> anyhow::Result<Vec<String>>,
+) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    let is_serial_port = {
+        use std::os::unix::fs::FileTypeExt;
+        match std::fs::metadata(device_path) {
+            Ok(metadata) => metadata.file_type().is_char_device(),
+            // A missing path is reported by the attempt to open it.
+            Err(_) => return Ok(()),
+        }
+    };
+    #[cfg(not(unix))]
+    let is_serial_port = match list_ports() {
+        Ok(ports) => ports.iter().any(|p| p == device_path),
+        Err(e) => {
+            // The attempt to open the path reports any problem.
+            tracing::debug!("Not checking {device_path} against serial ports: {e:#}");
+            return Ok(());
+        }
+    };
+
+    if !is_serial_port {
+        match list_ports() {
+            Ok(candidates) if candidates.is_empty() => {
+                anyhow::bail!("{device_path} is not a serial port and no serial ports were found")
+            }
+            Ok(candidates) => anyhow::bail!(
+                "{device_path} is not a serial port; did you mean one of: {}",
+                candidates.join(", ")
+            ),
+            Err(_) => anyhow::bail!("{device_path} is not a serial port"),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) type DeviceFramed = tokio_util::codec::Framed<tokio_serial::SerialStream, DeviceCodec>;
+
+pub(crate) fn open_device(device_path: &str) -> anyhow::Result<DeviceFramed> {
+    let baud_rate = 115_200;
+    tracing::info!("Opening device at path {}", device_path);
+    check_is_serial_port(device_path, available_port_names)?;
+
+    #[allow(unused_mut)]
+    let mut serial_device = tokio_serial::new(device_path, baud_rate)
+        .open_native_async()
+        .with_context(|| format!("opening device {device_path}"))?;
+    tracing::info!("Device opened");
+
+    #[cfg(unix)]
+    serial_device
+        .set_exclusive(false)
+        .expect("Unable to set serial port exclusive to false");
+
+    Ok(tokio_util::codec::Framed::new(
+        serial_device,
+        DeviceCodec::default(),
+    ))
+}
+
+pub(crate) fn check_version(
+    info: &VersionResponse,
+    no_version_check: bool,
+) -> Result<(), TriggerLoggerError> {
+    let my_info = VersionResponse::default();
+    // Capabilities may differ between builds of the same protocol version.
+    let matches = info.name == my_info.name && info.version == my_info.version;
+    if !matches && no_version_check {
+        tracing::warn!(
+            "IGNORING VERSION MISMATCH: firmware has version {:?}, but program has version {:?}",
+            info,
+            my_info
+        );
+    } else if !matches {
+        return Err(TriggerLoggerError::VersionMismatch {
+            firmware: info.clone(),
+            expected: my_info,
+        });
+    }
+    tracing::info!(
+        "Connected to firmware \"{}\" v{} (capabilities {:#x})",
+        String::from_utf8_lossy(&info.name),
+        info.version,
+        info.capabilities
+    );
+    Ok(())
+}
+
+/// Parse a daily window of UTC time of day given as `HH:MM-HH:MM`.
+pub(crate) fn parse_daily_window(spec: &str) -> Result<DailyWindow, String> {
+    let (start, end) = spec
+        .split_once('-')
+        .ok_or_else(|| format!("expected HH:MM-HH:MM, got \"{spec}\""))?;
+    let seconds = |time: &str| {
+        chrono::NaiveTime::parse_from_str(time, "%H:%M")
+            .map(|time| time.num_seconds_from_midnight())
+            .map_err(|e| format!("invalid time of day \"{time}\": {e}"))
+    };
+    Ok(DailyWindow {
+        start_seconds: seconds(start)?,
+        end_seconds: seconds(end)?,
+    })
+}
+
+/// Describe a daily window for the log.
+pub(crate) fn describe_daily_window(window: &DailyWindow) -> String {
+    let time = |seconds| {
+        chrono::NaiveTime::from_num_seconds_from_midnight_opt(seconds, 0).map_or_else(
+            || format!("{seconds} s"),
+            |time| time.format("%H:%M").to_string(),
+        )
+    };
+    format!(
+        "from {} to {} UTC",
+        time(window.start_seconds),
+        time(window.end_seconds)
+    )
+}
+
+/// The feature required by `request` which the firmware described by `info`
+/// lacks, if any.
+pub(crate) fn missing_capability(
+    info: &VersionResponse,
+    request: &ToDevice,
+) -> Option<&'static str> {
+    let (capability, name) = match request {
+        ToDevice::QueryConfig => (
+            capabilities::BUILD_CONFIG,
+            "querying the build configuration",
+        ),
+        ToDevice::StatusRequest => (capabilities::STATUS, "status requests"),
+        ToDevice::GetConfig => (capabilities::GET_CONFIG, "reading back the configuration"),
+        ToDevice::Echo(_) => (capabilities::ECHO, "echo"),
+        ToDevice::SetTriggerOffset(_) => (capabilities::CALIBRATION, "calibration"),
+        ToDevice::TestPattern { .. } => (capabilities::TEST_PATTERN, "test patterns"),
+        ToDevice::Arm(_) => (capabilities::ARM, "arming"),
+        ToDevice::History { .. } => (capabilities::HISTORY, "trigger history"),
+        ToDevice::SetRtc(_) => (capabilities::RTC, "a real-time clock"),
+        ToDevice::EnterBootloader { .. } => (capabilities::BOOTLOADER, "entering the bootloader"),
+        ToDevice::MarkEpoch => (capabilities::EPOCH_MARK, "epoch marks"),
+        ToDevice::LoopbackPulse => (capabilities::LOOPBACK, "the loopback output"),
+        ToDevice::Configure(DeviceConfig { adc: Some(_), .. })
+            if !info.supports(capabilities::ANALOG) =>
+        {
+            (capabilities::ANALOG, "the analog trigger input")
+        }
+        ToDevice::Configure(DeviceConfig {
+            pair_edges: true, ..
+        }) if !info.supports(capabilities::PAIR_EDGES) => {
+            (capabilities::PAIR_EDGES, "pairing edges")
+        }
+        ToDevice::Configure(DeviceConfig { buzzer_ticks, .. })
+            if *buzzer_ticks > 0 && !info.supports(capabilities::BUZZER) =>
+        {
+            (capabilities::BUZZER, "the buzzer output")
+        }
+        ToDevice::Configure(DeviceConfig {
+            differential: true, ..
+        }) if !info.supports(capabilities::DIFFERENTIAL) => {
+            (capabilities::DIFFERENTIAL, "differential triggers")
+        }
+        ToDevice::Configure(DeviceConfig {
+            beacon_interval_ticks,
+            ..
+        }) if *beacon_interval_ticks > 0 && !info.supports(capabilities::BEACON) => {
+            (capabilities::BEACON, "beacons")
+        }
+        ToDevice::Configure(DeviceConfig {
+            min_interval_ticks, ..
+        }) if *min_interval_ticks > 0 && !info.supports(capabilities::MIN_INTERVAL) => {
+            (capabilities::MIN_INTERVAL, "the minimum trigger interval")
+        }
+        ToDevice::Configure(DeviceConfig {
+            daily_window: Some(_),
+            ..
+        }) if !info.supports(capabilities::RTC) => (capabilities::RTC, "a daily trigger window"),
+        ToDevice::Configure(DeviceConfig {
+            debounce: Some(_), ..
+        }) => (capabilities::DEBOUNCE, "configuring the debounce windows"),
+        _ => return None,
+    };
+    (!info.supports(capability)).then_some(name)
+}
+
+/// Options for connecting to the device and exchanging requests and replies
+/// with it, as the subcommands do.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectOptions {
+    /// Continue even if the firmware protocol version does not match
+    pub(crate) no_version_check: bool,
+    /// Time to wait for each reply
+    pub(crate) reply_timeout: std::time::Duration,
+    /// Number of times a request which is not answered in time is resent
+    pub(crate) retries: u32,
+}
+
+/// Open the device and complete the version handshake, returning the
+/// firmware's version information.
+pub(crate) async fn connect(
+    device_path: &str,
+    options: ConnectOptions,
+) -> anyhow::Result<(DeviceFramed, VersionResponse)> {
+    let mut framed = open_device(device_path)?;
+    let received = framed.codec().received_bytes();
+    let info = send_request(
+        &mut framed,
+        ToDevice::VersionRequest,
+        options,
+        |msg| match msg {
+            FromDevice::VersionResponse(info) => Some(info),
+            _ => None,
+        },
+    )
+    .await
+    .map_err(|e| match e {
+        TriggerLoggerError::ReplyTimeout => TriggerLoggerError::HandshakeTimeout {
+            bytes_received: received.get(),
+        },
+        e => e,
+    })?;
+    check_version(&info, options.no_version_check)?;
+    Ok((framed, info))
+}
+
+/// Wait up to `timeout` for the first message from the device for which
+/// `reply` returns `Some`, ignoring other messages.
+///
+/// Triggers and beacons which arrive meanwhile are among the messages
+/// ignored, so this is only for the subcommands, which do not record. A
+/// recording session never waits for a reply: it sends its requests from its
+/// loop and handles each reply there as it arrives among the triggers, so
+/// that no trigger waits behind a slow reply.
+pub(crate) async fn wait_for_reply<T>(
+    framed: &mut DeviceFramed,
+    timeout: std::time::Duration,
+    mut reply: impl FnMut(FromDevice) -> Option<T>,
+) -> Result<T, TriggerLoggerError> {
+    loop {
+        let from_device = tokio::time::timeout(timeout, framed.next())
+            .await
+            .map_err(|_| TriggerLoggerError::ReplyTimeout)?
+            .ok_or_else(TriggerLoggerError::closed)?
+            .map_err(|e| TriggerLoggerError::Transport(e.into()))?;
+        if let Some(value) = reply(from_device) {
+            return Ok(value);
+        }
+    }
+}
+
+/// Send `request` and wait for the first message for which `reply` returns
+/// `Some`, as in [wait_for_reply], resending the request up to
+/// [ConnectOptions::retries] times if no reply comes in time. A reply to an
+/// earlier send which arrives late is accepted, so this is only for requests
+/// which can safely be repeated.
+pub(crate) async fn send_request<T>(
+    framed: &mut DeviceFramed,
+    request: ToDevice,
+    options: ConnectOptions,
+    mut reply: impl FnMut(FromDevice) -> Option<T>,
+) -> Result<T, TriggerLoggerError> {
+    let mut attempt = 0;
+    loop {
+        framed
+            .send(request.clone())
+            .await
+            .map_err(|e| TriggerLoggerError::Transport(e.into()))?;
+        match wait_for_reply(framed, options.reply_timeout, &mut reply).await {
+            Err(TriggerLoggerError::ReplyTimeout) if attempt < options.retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "No reply to {request:?}. Resending ({attempt} of {}).",
+                    options.retries
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Connect to the device, send `request` and wait for the first message for
+/// which `reply` returns `Some`, as in [send_request]. Fails without sending
+/// if the firmware does not support `request`.
+pub(crate) async fn request_reply<T>(
+    device_path: &str,
+    request: ToDevice,
+    options: ConnectOptions,
+    reply: impl FnMut(FromDevice) -> Option<T>,
+) -> anyhow::Result<T> {
+    let (mut framed, info) = connect(device_path, options).await?;
+    if let Some(name) = missing_capability(&info, &request) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    Ok(send_request(&mut framed, request, options, reply).await?)
+}
+
+#[test]
+fn test_port_info_json() {
+    let usb = tokio_serial::SerialPortInfo {
+        port_name: "/dev/ttyACM0".into(),
+        port_type: tokio_serial::SerialPortType::UsbPort(tokio_serial::UsbPortInfo {
+            vid: USB_VID_PID.0,
+            pid: USB_VID_PID.1,
+            serial_number: Some("TEST".into()),
+            manufacturer: Some("Straw Lab".into()),
+            product: None,
+        }),
+    };
+    let other = tokio_serial::SerialPortInfo {
+        port_name: "/dev/ttyS1".into(),
+        port_type: tokio_serial::SerialPortType::Unknown,
+    };
+    let ports: Vec<PortInfo> = [usb, other].iter().map(PortInfo::from).collect();
+    let json = serde_json::to_value(&ports).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!([
+            {
+                "path": "/dev/ttyACM0",
+                "vid": 0x16c0,
+                "pid": 0x27dd,
+                "serial_number": "TEST",
+                "manufacturer": "Straw Lab",
+                "product": null,
+                "is_trigger_device": true,
+            },
+            {
+                "path": "/dev/ttyS1",
+                "vid": null,
+                "pid": null,
+                "serial_number": null,
+                "manufacturer": null,
+                "product": null,
+                "is_trigger_device": false,
+            },
+        ])
+    );
+}
+
+#[test]
+fn test_check_is_serial_port_without_enumeration() {
+    let fail = || -> anyhow::Result<Vec<String>> { anyhow::bail!("enumeration not permitted") };
+    #[cfg(unix)]
+    {
+        let unused = || -> anyhow::Result<Vec<String>> { unreachable!() };
+        assert!(check_is_serial_port("/dev/null", unused).is_ok());
+        let e = check_is_serial_port("Cargo.toml", fail).unwrap_err();
+        assert_eq!(e.to_string(), "Cargo.toml is not a serial port");
+    }
+    #[cfg(not(unix))]
+    assert!(check_is_serial_port("COM3", fail).is_ok());
+}
+
+#[test]
+fn test_to_device_fits_max_len() {
+    use red_button_trigger_timestamp_comms::{
+        AdcConfig, Debounce, DeviceConfig, ECHO_LEN, MAX_TO_DEVICE_LEN,
+    };
+
+    // Variants with the longest encoding of their fields.
+    let messages = [
+        ToDevice::Ping,
+        ToDevice::VersionRequest,
+        ToDevice::ReadPin,
+        ToDevice::SetLed(false),
+        ToDevice::Configure(DeviceConfig {
+            active_high: false,
+            report_last_ping: false,
+            pair_edges: false,
+            adc: Some(AdcConfig {
+                channel: 255,
+                threshold: u16::MAX,
+                hysteresis: u16::MAX,
+            }),
+            buzzer_ticks: u32::MAX,
+            differential: false,
+            beacon_interval_ticks: u32::MAX,
+            min_interval_ticks: u32::MAX,
+            daily_window: Some(DailyWindow {
+                start_seconds: u32::MAX,
+                end_seconds: u32::MAX,
+            }),
+            debounce: Some(Debounce {
+                press_ticks: u32::MAX,
+                release_ticks: u32::MAX,
+            }),
+        }),
+        ToDevice::QueryConfig,
+        ToDevice::GetConfig,
+        ToDevice::Echo([255; ECHO_LEN]),
+        ToDevice::StatusRequest,
+        ToDevice::SetTriggerOffset(u32::MAX),
+        ToDevice::TestPattern {
+            period_us: u32::MAX,
+            count: u32::MAX,
+        },
+        ToDevice::Arm(false),
+        ToDevice::History { max: u16::MAX },
+        ToDevice::SetRtc(i64::MIN),
+        ToDevice::EnterBootloader { confirm: u32::MAX },
+        ToDevice::MarkEpoch,
+        ToDevice::LoopbackPulse,
+    ];
+    for msg in messages {
+        // The CRC framing is the longer, plus one for the newline delimiter.
+        let encoded = serde_json::to_vec(&msg).unwrap();
+        let checked = red_button_trigger_timestamp_comms::crc::Checked::new(&msg, &encoded);
+        let len = serde_json::to_vec(&checked).unwrap().len() + 1;
+        assert!(len <= MAX_TO_DEVICE_LEN, "{msg:?} encodes to {len} bytes");
+    }
+}
+
+#[test]
+fn test_config_state_fits_max_len() {
+    use red_button_trigger_timestamp_comms::{
+        AdcConfig, ConfigState, Debounce, DeviceConfig, MAX_FROM_DEVICE_LEN,
+    };
+
+    // The longest message from the device
+    let msg = FromDevice::ConfigState(ConfigState {
+        config: DeviceConfig {
+            active_high: false,
+            report_last_ping: false,
+            pair_edges: false,
+            adc: Some(AdcConfig {
+                channel: 255,
+                threshold: u16::MAX,
+                hysteresis: u16::MAX,
+            }),
+            buzzer_ticks: u32::MAX,
+            differential: false,
+            beacon_interval_ticks: u32::MAX,
+            min_interval_ticks: u32::MAX,
+            daily_window: Some(DailyWindow {
+                start_seconds: u32::MAX,
+                end_seconds: u32::MAX,
+            }),
+            debounce: Some(Debounce {
+                press_ticks: u32::MAX,
+                release_ticks: u32::MAX,
+            }),
+        },
+        debounce: Debounce {
+            press_ticks: u32::MAX,
+            release_ticks: u32::MAX,
+        },
+        armed: false,
+    });
+    let encoded = serde_json::to_vec(&msg).unwrap();
+    let checked = red_button_trigger_timestamp_comms::crc::Checked::new(&msg, &encoded);
+    let len = serde_json::to_vec(&checked).unwrap().len() + 1;
+    assert!(len <= MAX_FROM_DEVICE_LEN, "{msg:?} encodes to {len} bytes");
+}
+
+#[test]
+fn test_status_fits_max_len() {
+    use red_button_trigger_timestamp_comms::{
+        DeviceStatus, LineState, ResetReason, MAX_FROM_DEVICE_LEN,
+    };
+
+    let msg = FromDevice::Status(DeviceStatus {
+        uptime_ticks: u64::MAX,
+        idle_loops_per_second: u32::MAX,
+        reset_reason: ResetReason::WatchdogForced,
+        stack_free_bytes: u32::MAX,
+        rx_queue_high_water: u8::MAX,
+        rx_queue_capacity: u8::MAX,
+        armed: false,
+        suppressed_triggers: u32::MAX,
+        outside_window_triggers: u32::MAX,
+        line_state: Some(LineState {
+            baud: u32::MAX,
+            data_bits: u8::MAX,
+            parity: u8::MAX,
+            stop_bits: u8::MAX,
+            dtr: false,
+            rts: false,
+        }),
+    });
+    let encoded = serde_json::to_vec(&msg).unwrap();
+    let checked = red_button_trigger_timestamp_comms::crc::Checked::new(&msg, &encoded);
+    let len = serde_json::to_vec(&checked).unwrap().len() + 1;
+    assert!(len <= MAX_FROM_DEVICE_LEN, "{msg:?} encodes to {len} bytes");
+}
+
+#[test]
+fn test_parse_daily_window() {
+    let window = parse_daily_window("22:00-06:30").unwrap();
+    assert_eq!(
+        window,
+        DailyWindow {
+            start_seconds: 22 * 3600,
+            end_seconds: 6 * 3600 + 30 * 60,
+        }
+    );
+    assert_eq!(describe_daily_window(&window), "from 22:00 to 06:30 UTC");
+    assert!(parse_daily_window("22:00").is_err());
+    assert!(parse_daily_window("22:00-24:00").is_err());
+}
+
+#[test]
+fn test_missing_capability() {
+    let info = VersionResponse {
+        capabilities: capabilities::STATUS | capabilities::PAIR_EDGES,
+        ..Default::default()
+    };
+    assert_eq!(missing_capability(&info, &ToDevice::Ping), None);
+    assert_eq!(missing_capability(&info, &ToDevice::StatusRequest), None);
+    assert_eq!(
+        missing_capability(&info, &ToDevice::QueryConfig),
+        Some("querying the build configuration")
+    );
+    let config = DeviceConfig {
+        pair_edges: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        missing_capability(&info, &ToDevice::Configure(config.clone())),
+        None
+    );
+    let config = DeviceConfig {
+        adc: Some(red_button_trigger_timestamp_comms::AdcConfig {
+            channel: 0,
+            threshold: 1000,
+            hysteresis: 0,
+        }),
+        ..config
+    };
+    assert_eq!(
+        missing_capability(&info, &ToDevice::Configure(config)),
+        Some("the analog trigger input")
+    );
+    // Old firmware advertises nothing.
+    assert_eq!(
+        missing_capability(&VersionResponse::default(), &ToDevice::Echo([0; 16])),
+        Some("echo")
+    );
+}
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use red_button_trigger_timestamp_comms::{BuildConfig, ResetReason, VersionResponse};
+use serde::Serialize;
+
+/// Record of the device and firmware which produced a dataset, for
+/// provenance.
+#[derive(Debug, Serialize)]
+pub(crate) struct DeviceInfo {
+    /// Path of the serial device
+    pub(crate) device_path: String,
+    /// Host time at which the version handshake completed
+    pub(crate) connected_utc: DateTime<Utc>,
+    pub(crate) version: VersionResponse,
+    /// Settings the firmware was built with, once reported
+    pub(crate) build_config: Option<BuildConfig>,
+    /// Cause of the device's most recent reset, once reported
+    pub(crate) reset_reason: Option<ResetReason>,
+    /// Starts of the segments of a piecewise clock model after the first,
+    /// with `--clock-model-segment-ppm`
+    pub(crate) clock_segment_starts: Vec<ClockSegmentStart>,
+}
+
+/// Device tick at which the device clock's rate changed, and a new segment of
+/// the clock model started, with its estimated time.
+#[derive(Debug, Serialize)]
+pub(crate) struct ClockSegmentStart {
+    pub(crate) device_tick: u64,
+    pub(crate) utc: Option<DateTime<Utc>>,
+}
+
+impl DeviceInfo {
+    /// Write as JSON to `path`, replacing it.
+    pub(crate) fn write(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let buf = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, buf).with_context(|| format!("writing file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_device_info_json() {
+    let info = DeviceInfo {
+        device_path: "/dev/ttyACM0".into(),
+        connected_utc: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc),
+        version: VersionResponse {
+            capabilities: 3,
+            ..Default::default()
+        },
+        build_config: None,
+        reset_reason: Some(ResetReason::Watchdog),
+        clock_segment_starts: vec![ClockSegmentStart {
+            device_tick: 40_000_000,
+            utc: None,
+        }],
+    };
+    let value = serde_json::to_value(&info).unwrap();
+    assert_eq!(value["connected_utc"], "2024-01-01T00:00:00Z");
+    assert_eq!(value["version"]["capabilities"], 3);
+    assert!(value["build_config"].is_null());
+    assert_eq!(value["reset_reason"], "Watchdog");
+    assert_eq!(value["clock_segment_starts"][0]["device_tick"], 40_000_000);
+}
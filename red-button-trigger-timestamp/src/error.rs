@@ -0,0 +1,86 @@
+use red_button_trigger_timestamp_comms::VersionResponse;
+
+/// Failures which a caller may want to handle specifically, e.g. by retrying
+/// after a timeout, rather than only display.
+#[derive(Debug)]
+pub(crate) enum TriggerLoggerError {
+    /// The firmware implements a different protocol than this program.
+    VersionMismatch {
+        firmware: VersionResponse,
+        expected: VersionResponse,
+    },
+    /// The device was opened but did not answer the version request in time,
+    /// having sent this many bytes, e.g. line noise or another protocol.
+    HandshakeTimeout { bytes_received: u64 },
+    /// The device did not send an expected reply in time.
+    ReplyTimeout,
+    /// Reading from or writing to the device failed.
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+    /// The clock model was not ready after the given number of seconds.
+    ClockWarmupTimeout { seconds: f64 },
+}
+
+impl TriggerLoggerError {
+    /// A transport error for the device closing the connection.
+    pub(crate) fn closed() -> Self {
+        Self::Transport(
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "device closed").into(),
+        )
+    }
+}
+
+impl std::fmt::Display for TriggerLoggerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::VersionMismatch { firmware, expected } => write!(
+                f,
+                "firmware has version {firmware:?}, but program has version {expected:?}"
+            ),
+            Self::HandshakeTimeout { bytes_received: 0 } => write!(
+                f,
+                "The device was opened but sent nothing in reply to the version request. \
+                 It may be the wrong serial port, or not running this firmware."
+            ),
+            Self::HandshakeTimeout { bytes_received } => write!(
+                f,
+                "The device was opened but sent no valid version response, although \
+                 {bytes_received} bytes were received. It may be the wrong serial port, or \
+                 not running this firmware, or the link may be corrupting messages."
+            ),
+            Self::ReplyTimeout => write!(f, "No reply received from device."),
+            Self::Transport(e) => write!(f, "Device communication failed: {e}"),
+            Self::ClockWarmupTimeout { seconds } => write!(
+                f,
+                "Clock model not ready after {seconds} seconds. Round trip times may all \
+                 exceed the clock model maximum; check the link, e.g. with the probe \
+                 subcommand, or increase --clock-warmup-timeout. With \
+                 --clock-model-max-residual-ms, the fit may not have been close enough."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TriggerLoggerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error;
+
+    let e = TriggerLoggerError::closed();
+    assert!(e.source().is_some());
+    let timeout = TriggerLoggerError::HandshakeTimeout { bytes_received: 0 };
+    assert!(timeout.source().is_none());
+    // Converts to a report for display, keeping the error for matching.
+    let report = color_eyre::eyre::Report::from(TriggerLoggerError::ReplyTimeout);
+    assert!(matches!(
+        report.downcast_ref::<TriggerLoggerError>(),
+        Some(TriggerLoggerError::ReplyTimeout)
+    ));
+}
@@ -0,0 +1,101 @@
+use crate::{clock_model::ClockModel, device, probe, trigger_log};
+use chrono::Utc;
+use color_eyre::eyre as anyhow;
+use futures::SinkExt;
+use red_button_trigger_timestamp_comms::{FromDevice, ToDevice};
+
+/// Fetch up to `max` of the most recent triggers kept by the device and write
+/// them as CSV to stdout, oldest first.
+///
+/// Trigger times are computed with a clock model fitted now, so for triggers
+/// long before the connection they include the model's clock rate error
+/// multiplied by the time elapsed since.
+pub(crate) async fn history(
+    device_path: &str,
+    max: u16,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    let request = ToDevice::History { max };
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &request) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    let clock_model = probe::warm_up_clock_model(&mut framed, options.reply_timeout).await?;
+
+    framed.send(request).await?;
+    let mut timestamps = Vec::new();
+    // Entries are followed by an acknowledgement.
+    while let Some(timestamp) =
+        device::wait_for_reply(&mut framed, options.reply_timeout, |msg| match msg {
+            FromDevice::HistoryEntry { timestamp, .. } => Some(Some(timestamp)),
+            FromDevice::Ack => Some(None),
+            _ => None,
+        })
+        .await?
+    {
+        timestamps.push(timestamp);
+    }
+    let recv_time = Utc::now();
+
+    let sink = trigger_log::CsvSink::new(Box::new(std::io::stdout()), &[])?;
+    let mut trigger_log = trigger_log::TriggerLog::new(vec![Box::new(sink)], 0);
+    trigger_log.quiet = true;
+    write_entries(&clock_model, &timestamps, recv_time, &mut trigger_log)?;
+    tracing::info!(
+        "{} triggers fetched from the device.",
+        trigger_log.n_written
+    );
+    Ok(())
+}
+
+/// Write the triggers at device ticks `timestamps`, skipping with a warning
+/// any whose time the clock model cannot compute.
+fn write_entries(
+    clock_model: &ClockModel,
+    timestamps: &[u64],
+    recv_time: chrono::DateTime<Utc>,
+    trigger_log: &mut trigger_log::TriggerLog,
+) -> anyhow::Result<()> {
+    for &timestamp in timestamps {
+        let Some(trigger_utc) = clock_model.compute_utc(timestamp) else {
+            tracing::warn!(
+                "Skipping trigger at device tick {timestamp}, whose time cannot be computed."
+            );
+            continue;
+        };
+        trigger_log.write(
+            trigger_utc,
+            timestamp,
+            recv_time,
+            clock_model.latest_error_seconds(),
+            Default::default(),
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write_entries_from_before_connection() {
+    let epoch = Utc::now();
+    let device_tick = |t: chrono::DateTime<Utc>| {
+        (10_000_000_000 + (t - epoch).num_microseconds().unwrap()) as u64
+    };
+    let mut clock_model = ClockModel::with_epoch(epoch, chrono::TimeDelta::milliseconds(20));
+    for i in 0..20 {
+        let t = epoch + chrono::TimeDelta::seconds(i);
+        clock_model.update(t, t, device_tick(t), device_tick(t));
+    }
+
+    let sink = trigger_log::CsvSink::new(Box::new(std::io::sink()), &[]).unwrap();
+    let mut trigger_log = trigger_log::TriggerLog::new(vec![Box::new(sink)], 0);
+    trigger_log.quiet = true;
+    // Triggers kept by the device from an hour and a second before the host
+    // connected, and one after.
+    let timestamps = [
+        device_tick(epoch - chrono::TimeDelta::hours(1)),
+        device_tick(epoch - chrono::TimeDelta::seconds(1)),
+        device_tick(epoch + chrono::TimeDelta::seconds(30)),
+    ];
+    write_entries(&clock_model, &timestamps, Utc::now(), &mut trigger_log).unwrap();
+    assert_eq!(trigger_log.n_written, 3);
+}
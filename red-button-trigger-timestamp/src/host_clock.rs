@@ -0,0 +1,222 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::time::Instant;
+
+/// Source of host timestamps for clock measurements, i.e. the host time axis
+/// of the clock model and so the time scale of the recorded trigger times.
+///
+/// The accuracy of recorded times relative to true UTC can be no better than
+/// that of this clock. A wall clock kept by NTP is typically within a
+/// millisecond or so of UTC on a local network, whereas a PTP hardware clock
+/// is typically within a microsecond of the lab's grandmaster, which is also
+/// the time reference of other PTP synchronized instruments such as cameras.
+/// The time taken to read the clock adds to the measured round trip time, so
+/// it should be small compared to the round trip time over USB.
+pub(crate) trait HostClockSource: Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The host's wall clock, which follows NTP and manual adjustments. A step in
+/// the wall clock mid-session produces a discontinuity in the clock model fit.
+pub(crate) struct SystemClock;
+
+impl HostClockSource for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// The host's monotonic clock, mapped to UTC once on creation. This is immune
+/// to wall clock steps, but over long sessions the host crystal's drift
+/// relative to true UTC accumulates in the recorded times.
+pub(crate) struct MonotonicClock {
+    utc_anchor: DateTime<Utc>,
+    instant_anchor: Instant,
+}
+
+impl MonotonicClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            utc_anchor: Utc::now(),
+            instant_anchor: Instant::now(),
+        }
+    }
+}
+
+impl HostClockSource for MonotonicClock {
+    fn now(&self) -> DateTime<Utc> {
+        anchored_utc(self.utc_anchor, self.instant_anchor, Instant::now())
+    }
+}
+
+/// A PTP hardware clock, such as `/dev/ptp0`, read with `clock_gettime`.
+///
+/// The hardware clock is assumed to keep the PTP timescale (TAI), as it does
+/// when synchronized by `ptp4l` to a grandmaster using the default timescale,
+/// and is converted to UTC by subtracting [TAI_UTC_OFFSET_SECONDS].
+#[cfg(target_os = "linux")]
+pub(crate) struct PtpClock {
+    // Kept open for the clock id to remain valid.
+    _device: std::fs::File,
+    clock_id: libc::clockid_t,
+}
+
+/// TAI - UTC, unchanged since the leap second at the end of 2016.
+#[cfg(target_os = "linux")]
+const TAI_UTC_OFFSET_SECONDS: i64 = 37;
+
+#[cfg(target_os = "linux")]
+impl PtpClock {
+    pub(crate) fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let device = std::fs::File::open(path)?;
+        // The dynamic clock id of an open clock device, as `FD_TO_CLOCKID` in
+        // the kernel's `posix-timers.h`.
+        let clock_id = ((!device.as_raw_fd()) << 3) | 3;
+        let clock = Self {
+            _device: device,
+            clock_id,
+        };
+        // Fail now, rather than on first use, if this is not a clock device.
+        clock.read()?;
+        Ok(clock)
+    }
+
+    fn read(&self) -> std::io::Result<DateTime<Utc>> {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `ts` is a valid timespec to write to and `clock_id` refers
+        // to the clock device held open by `self`.
+        if unsafe { libc::clock_gettime(self.clock_id, &mut ts) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let tai = DateTime::from_timestamp(ts.tv_sec, ts.tv_nsec as u32).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "clock out of range")
+        })?;
+        Ok(tai - TimeDelta::seconds(TAI_UTC_OFFSET_SECONDS))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl HostClockSource for PtpClock {
+    fn now(&self) -> DateTime<Utc> {
+        // The clock was read successfully when opened, so this fails only if
+        // the device goes away, in which case there is no time to record.
+        self.read().expect("reading PTP hardware clock")
+    }
+}
+
+/// The host clock selected on the command line.
+pub(crate) fn from_options(
+    monotonic: bool,
+    ptp_device: Option<&std::path::Path>,
+) -> anyhow::Result<Box<dyn HostClockSource>> {
+    if let Some(path) = ptp_device {
+        #[cfg(target_os = "linux")]
+        {
+            let clock = PtpClock::open(path)
+                .with_context(|| format!("opening PTP hardware clock {}", path.display()))?;
+            return Ok(Box::new(clock));
+        }
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!(
+            "PTP hardware clock {} is not supported on this platform",
+            path.display()
+        );
+    }
+    if monotonic {
+        Ok(Box::new(MonotonicClock::new()))
+    } else {
+        Ok(Box::new(SystemClock))
+    }
+}
+
+/// UTC time of `instant` given that `instant_anchor` occurred at `utc_anchor`.
+fn anchored_utc(
+    utc_anchor: DateTime<Utc>,
+    instant_anchor: Instant,
+    instant: Instant,
+) -> DateTime<Utc> {
+    let elapsed = instant.saturating_duration_since(instant_anchor);
+    utc_anchor + TimeDelta::from_std(elapsed).unwrap()
+}
+
+#[test]
+fn test_monotonic_clock_ignores_wall_clock_step() {
+    use crate::clock_model::ClockModel;
+    use std::time::Duration;
+
+    let utc_anchor = Utc::now();
+    let instant_anchor = Instant::now();
+    let step = TimeDelta::seconds(1);
+
+    let mut wall_model = ClockModel::default();
+    let mut monotonic_model = ClockModel::default();
+    for i in 0..30 {
+        let instant = instant_anchor + Duration::from_secs(i);
+        let device_timestamp = i * 1_000_000;
+        let monotonic_time = anchored_utc(utc_anchor, instant_anchor, instant);
+        // The wall clock is stepped forward after 20 seconds.
+        let wall_time = if i < 20 {
+            monotonic_time
+        } else {
+            monotonic_time + step
+        };
+        wall_model.update(wall_time, wall_time, device_timestamp, device_timestamp);
+        monotonic_model.update(
+            monotonic_time,
+            monotonic_time,
+            device_timestamp,
+            device_timestamp,
+        );
+    }
+
+    let device_timestamp = 40 * 1_000_000;
+    let expected = utc_anchor + TimeDelta::seconds(40);
+    let monotonic_error = monotonic_model.compute_utc(device_timestamp).unwrap() - expected;
+    let wall_error = wall_model.compute_utc(device_timestamp).unwrap() - expected;
+    assert!(monotonic_error.num_microseconds().unwrap().abs() <= 1);
+    assert!(wall_error.num_milliseconds().abs() > 100);
+}
+
+/// Example clock source which reads another clock with a fixed offset, e.g.
+/// to stand in for a lab time reference which differs from the system clock.
+#[cfg(test)]
+struct OffsetClock<C> {
+    inner: C,
+    offset: TimeDelta,
+}
+
+#[cfg(test)]
+impl<C: HostClockSource> HostClockSource for OffsetClock<C> {
+    fn now(&self) -> DateTime<Utc> {
+        self.inner.now() + self.offset
+    }
+}
+
+#[test]
+fn test_clock_source_drives_clock_model() {
+    use crate::clock_model::ClockModel;
+
+    let offset = TimeDelta::hours(1);
+    let clock = OffsetClock {
+        inner: SystemClock,
+        offset,
+    };
+    let mut model = ClockModel::with_epoch(clock.now(), ClockModel::default().max_rtt());
+    let start = Utc::now();
+    for i in 0..30 {
+        let host_time = clock.now();
+        let device_timestamp = i * 1_000_000;
+        // Take the samples a simulated second apart.
+        let host_time = host_time + TimeDelta::seconds(i as i64);
+        model.update(host_time, host_time, device_timestamp, device_timestamp);
+    }
+
+    // Trigger times are on the time scale of the clock source.
+    let error = model.compute_utc(0).unwrap() - (start + offset);
+    assert!(error.num_milliseconds().abs() < 100);
+}
@@ -0,0 +1,73 @@
+/// Running mean and standard deviation of the intervals between triggers,
+/// which quantify the regularity of a periodic source such as a metronome or
+/// the device's test pattern. Computed with Welford's algorithm, which does
+/// not lose precision over long recordings.
+#[derive(Debug, Default)]
+pub(crate) struct IntervalStats {
+    count: u64,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+}
+
+impl IntervalStats {
+    pub(crate) fn add(&mut self, interval_seconds: f64) {
+        self.count += 1;
+        let delta = interval_seconds - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (interval_seconds - self.mean);
+    }
+
+    /// Mean interval in seconds, once there is one.
+    pub(crate) fn mean(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.mean)
+    }
+
+    /// Sample standard deviation of the intervals in seconds, once there are
+    /// two.
+    pub(crate) fn std_dev(&self) -> Option<f64> {
+        (self.count > 1).then(|| (self.m2 / (self.count - 1) as f64).sqrt())
+    }
+
+    /// A one line summary for the log, or `None` before there are two
+    /// intervals.
+    pub(crate) fn summary(&self) -> Option<String> {
+        let (mean, std_dev) = self.mean().zip(self.std_dev())?;
+        Some(format!(
+            "inter-trigger interval over {} intervals: mean {:.6} s, standard deviation {:.3} ms",
+            self.count,
+            mean,
+            std_dev * 1e3
+        ))
+    }
+}
+
+#[test]
+fn test_interval_stats() {
+    let mut stats = IntervalStats::default();
+    assert_eq!(stats.mean(), None);
+    assert_eq!(stats.summary(), None);
+
+    // A regular source, e.g. 100 Hz
+    for _ in 0..1000 {
+        stats.add(0.01);
+    }
+    assert_eq!(stats.count, 1000);
+    assert!((stats.mean().unwrap() - 0.01).abs() < 1e-12);
+    assert!(stats.std_dev().unwrap() < 1e-12);
+
+    // An irregular one, alternating 9 and 11 ms
+    let mut stats = IntervalStats::default();
+    for i in 0..1000 {
+        stats.add(if i % 2 == 0 { 0.009 } else { 0.011 });
+    }
+    assert!((stats.mean().unwrap() - 0.01).abs() < 1e-12);
+    // The sample standard deviation of n values of +-1 ms is
+    // 1 ms * sqrt(n / (n - 1)).
+    let expected = 0.001 * (1000.0f64 / 999.0).sqrt();
+    assert!((stats.std_dev().unwrap() - expected).abs() < 1e-12);
+    assert_eq!(
+        stats.summary().unwrap(),
+        "inter-trigger interval over 1000 intervals: mean 0.010000 s, standard deviation 1.001 ms"
+    );
+}
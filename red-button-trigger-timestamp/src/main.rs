@@ -1,34 +1,988 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{self as anyhow, WrapErr};
 use futures::{SinkExt, StreamExt};
-use json_lines::codec::JsonLinesCodec;
-use red_button_trigger_timestamp_comms::{FromDevice, ToDevice, VersionResponse};
-use serde::Serialize;
-use tokio_serial::SerialPortBuilderExt;
+use red_button_trigger_timestamp_comms::{
+    capabilities, AdcConfig, Debounce, DeviceConfig, DeviceStatus, FromDevice, LineState,
+    ResetReason, ToDevice, BOOTLOADER_CONFIRM,
+};
+use std::io::IsTerminal;
 use tracing_subscriber::{fmt, layer::SubscriberExt};
 
+mod backoff;
 mod clock_model;
-
-#[derive(Serialize)]
-struct TriggerRow {
-    timestamp_local: chrono::DateTime<chrono::Local>,
-    epoch_nanos_utc: i64,
-}
+mod codec;
+mod config;
+mod convert;
+mod device;
+mod device_info;
+mod error;
+mod history;
+mod host_clock;
+mod interval_stats;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod paths;
+mod probe;
+mod rate_limiter;
+mod trigger_log;
+#[cfg(unix)]
+mod unix_socket;
+mod validate;
 
 #[derive(Parser)]
+// Options from a config file are given first, so those on the command line
+// override them.
+#[command(args_override_self = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read options from this TOML file (or JSON, with a `.json` extension),
+    /// e.g. to keep the configuration of a rig in version control. Keys are
+    /// long option names, e.g. `output_dir = "/data"` or `quiet = true`, and
+    /// arrays give repeatable options several times. Options on the command
+    /// line override those in the file, except that repeatable options are
+    /// combined, and an option in the file which conflicts with one on the
+    /// command line is an error.
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
     /// Serial device to open
     device_path: Option<String>,
 
     /// Output directory
-    #[arg(short, long, default_value = "~/TRIGGER_DATA")]
+    #[arg(short, long, default_value = paths::DEFAULT_OUTPUT_DIR)]
     output_dir: String,
+
+    /// Write the CSV to stdout instead of to a file in the output directory
+    #[arg(long, conflicts_with = "output_dir")]
+    output_stdout_csv: bool,
+
+    /// Write the CSV to exactly this path, e.g. a named pipe or a file which
+    /// another program watches, instead of to a timestamped file in the
+    /// output directory. Missing parent directories are created and an
+    /// existing file is replaced.
+    #[arg(long, conflicts_with_all = ["output_dir", "output_stdout_csv"])]
+    output_file: Option<std::path::PathBuf>,
+
+    /// On shutdown, rewrite the output file with rows sorted by
+    /// `epoch_nanos_utc`
+    #[arg(long, conflicts_with = "output_stdout_csv")]
+    sort_output: bool,
+
+    /// Keep only the most recent this many rows in the output file, to bound
+    /// its size when recording indefinitely. The file is rewritten for each
+    /// row, so that it always holds a complete window including the latest
+    /// trigger, and older triggers are lost. Without this, every trigger is
+    /// kept in a file which grows without bound.
+    #[arg(long, conflicts_with = "output_stdout_csv", value_parser = clap::value_parser!(u64).range(1..))]
+    ring_capacity: Option<u64>,
+
+    /// Compress the output file with gzip, adding `.gz` to its name unless
+    /// given with `--output-file`. Sparse CSV rows compress to a fraction of
+    /// their size. Each row is still flushed as it is written, so a file cut
+    /// short decompresses, e.g. with `zcat`, up to its last row, with an error
+    /// about its missing end; at high trigger rates flushing every row costs
+    /// some compression.
+    #[cfg(feature = "compress")]
+    #[arg(long, conflicts_with_all = ["output_stdout_csv", "ring_capacity", "sort_output"])]
+    compress: bool,
+
+    /// Also stream each row, as a line of JSON with the CSV columns as keys,
+    /// to every process connected to a Unix domain socket at this path, e.g.
+    /// for another program to react to triggers with low latency. A socket
+    /// left at the path by a previous run is replaced. A consumer which
+    /// disconnects or falls behind is dropped, and the others are unaffected.
+    #[cfg(unix)]
+    #[arg(long)]
+    unix_socket: Option<std::path::PathBuf>,
+
+    /// Maximum delay, in seconds, between attempts to reconnect to the device
+    #[arg(long, default_value_t = 30.0, value_parser = parse_seconds)]
+    reconnect_max_delay: f64,
+
+    /// Weight given to each new round trip time in its exponential moving
+    /// average (between 0 and 1)
+    #[arg(long, default_value_t = 0.1)]
+    rtt_smoothing: f64,
+
+    /// Number of consecutive unanswered pings before warning about the link
+    #[arg(long, default_value_t = 5)]
+    max_missed_pongs: u32,
+
+    /// Drop triggers arriving faster than this rate (per second)
+    #[arg(long, value_parser = parse_positive)]
+    max_triggers_per_second: Option<f64>,
+
+    /// Continue even if the firmware protocol version does not match (for
+    /// firmware development only)
+    #[arg(long, global = true)]
+    no_version_check: bool,
+
+    /// Milliseconds to wait for each reply from the device, including the
+    /// version handshake on connecting
+    #[arg(long, global = true, default_value_t = 5000)]
+    reply_timeout_ms: u64,
+
+    /// Number of times a subcommand resends a request which is not answered
+    /// within `--reply-timeout-ms`, e.g. over a lossy link. Requests which
+    /// cannot safely be repeated, such as setting the real-time clock,
+    /// marking an epoch or entering the bootloader, are not resent.
+    #[arg(long, global = true, default_value_t = 0)]
+    reply_retries: u32,
+
+    /// Time clock measurements with the host's monotonic clock, mapped to UTC
+    /// once per connection. This makes the clock model immune to wall clock
+    /// steps (e.g. from NTP) at the cost of accumulating host clock drift
+    /// over long sessions.
+    #[arg(long)]
+    monotonic_host_clock: bool,
+
+    /// Time clock measurements with a PTP hardware clock, e.g. /dev/ptp0,
+    /// aligning the recorded times with other PTP synchronized instruments
+    /// (Linux only). The hardware clock is assumed to keep TAI.
+    #[arg(long, conflicts_with = "monotonic_host_clock")]
+    ptp_device: Option<std::path::PathBuf>,
+
+    /// Treat a transition to high, rather than to low, as a trigger
+    #[arg(long)]
+    active_high: bool,
+
+    /// Compute each trigger time relative to the most recent ping, using the
+    /// clock model only for the clock rate
+    #[arg(long)]
+    anchor_to_last_ping: bool,
+
+    /// Pair each press with the following release on the device and record
+    /// how long the button was held
+    #[arg(long)]
+    pair_edges: bool,
+
+    /// Combine presses on the device's two trigger inputs and record which
+    /// fired first and by how long
+    #[arg(long, conflicts_with = "pair_edges")]
+    differential: bool,
+
+    /// Exit with an error if the clock model is not ready within this many
+    /// seconds, e.g. because every round trip time is too long
+    #[arg(long, default_value_t = 60.0)]
+    clock_warmup_timeout: f64,
+
+    /// Do not ping the device. The clock model is instead fitted to the time
+    /// at which each trigger message is received, which keeps the link
+    /// read-only after connecting but makes trigger times late by the
+    /// transport delay, with its jitter added to their error. Until the model
+    /// is ready, the receive time itself is recorded.
+    #[arg(long)]
+    no_ping: bool,
+
+    /// Have the device send a beacon with its clock every this many
+    /// milliseconds. With `--no-ping`, beacons are added to the clock model
+    /// like triggers, keeping it current between triggers, so that several
+    /// programs can listen to one device without any of them pinging it. A
+    /// beacon is a one-way measurement, so times are late by the transport
+    /// delay and its jitter adds to their error, whereas a ping measures and
+    /// removes the delay. With pings, beacons are only logged.
+    #[arg(long)]
+    beacon_ms: Option<u32>,
+
+    /// ADC channel (0 to 2, on GPIO26 to GPIO28) of an analog trigger input,
+    /// used in addition to the digital input. Requires firmware built with the
+    /// `adc` feature.
+    #[arg(long, requires = "adc_threshold")]
+    adc_channel: Option<u8>,
+
+    /// ADC reading at or above which the analog input triggers
+    #[arg(long, requires = "adc_channel")]
+    adc_threshold: Option<u16>,
+
+    /// Amount by which the ADC reading must fall below the threshold before
+    /// the analog input can trigger again
+    #[arg(long, default_value_t = 0)]
+    adc_hysteresis: u16,
+
+    /// Pulse the device's buzzer output for this many milliseconds at each
+    /// trigger. Requires firmware built with the `buzzer` feature.
+    #[arg(long)]
+    buzzer_ms: Option<u32>,
+
+    /// Have the device suppress triggers within this many milliseconds of the
+    /// previous one, whatever their cause. Unlike --max-triggers-per-second,
+    /// this caps the rate at the source, and unlike debouncing it rejects
+    /// whole triggers rather than short pulses.
+    #[arg(long)]
+    device_min_interval_ms: Option<u32>,
+
+    /// Have the device report triggers only within this daily window of UTC
+    /// time of day, given as `HH:MM-HH:MM`, e.g. `22:00-06:00` for overnight,
+    /// suppressing and counting others. Requires firmware built with the
+    /// `rtc` feature and a set real-time clock.
+    #[arg(long, value_parser = device::parse_daily_window)]
+    device_window: Option<red_button_trigger_timestamp_comms::rtc::DailyWindow>,
+
+    /// Have the device report a press only once the input has held its new
+    /// level for this many microseconds, rather than the window the firmware
+    /// was built with. Given with --debounce-release-us, so that a button
+    /// which bounces longer on release than on press can use a short press
+    /// window.
+    #[arg(long, requires = "debounce_release_us")]
+    debounce_press_us: Option<u32>,
+
+    /// As --debounce-press-us, for releases.
+    #[arg(long, requires = "debounce_press_us")]
+    debounce_release_us: Option<u32>,
+
+    /// Unit of host time in the clock model fit. Nanoseconds give finer
+    /// resolution for device clocks faster than 1 MHz; `auto` chooses based on
+    /// the device's reported clock frequency.
+    #[arg(long, value_enum, default_value_t = ClockModelBasis::Auto)]
+    clock_model_basis: ClockModelBasis,
+
+    /// Estimator of the clock model. `robust` (Theil–Sen regression) is
+    /// unaffected by occasional delayed clock measurements on a jittery link
+    /// without tuning a threshold, but costs more to compute.
+    #[arg(long, value_enum, default_value_t = ClockModelFit::LeastSquares)]
+    clock_model: ClockModelFit,
+
+    /// Do not timestamp triggers with the clock model until the root mean
+    /// square residual of its fit is at most this many milliseconds. By
+    /// default the model is used as soon as it has enough samples, even if
+    /// the first of them were noisy.
+    #[arg(long)]
+    clock_model_max_residual_ms: Option<f64>,
+
+    /// Fit the clock model piecewise, starting a new linear segment when the
+    /// device clock's rate changes by more than this many parts per million,
+    /// e.g. as its oscillator warms or cools, and timing each trigger with the
+    /// segment it falls in. A change is located once ten clock measurements
+    /// follow it, so the latest triggers are timed as before until then. A
+    /// crystal oscillator's rate changes by up to tens of ppm over its
+    /// temperature range; too small a value splits the model on jitter in the
+    /// measurements. Segment boundaries are recorded in --device-info.
+    #[arg(long)]
+    clock_model_segment_ppm: Option<f64>,
+
+    /// Add a column with a constant value to every row, given as
+    /// `name=value` (may be repeated)
+    #[arg(long, value_parser = trigger_log::parse_extra_column)]
+    extra_column: Vec<(String, String)>,
+
+    /// On clean shutdown, append an annotation row recording the end of the
+    /// session and the number of triggers, so that complete files can be
+    /// distinguished from ones truncated by a crash
+    #[arg(long)]
+    mark_end_of_session: bool,
+
+    /// Number of consecutive failed writes to an output (e.g. because the disk
+    /// is full) which are logged and tolerated before exiting with an error
+    #[arg(long, default_value_t = 0)]
+    tolerate_write_failures: u32,
+
+    /// Periodically write the clock model's samples and fitted parameters to
+    /// this JSON file, e.g. to check the linearity of the device clock offline
+    #[arg(long)]
+    dump_samples: Option<std::path::PathBuf>,
+
+    /// Write the firmware's version and capabilities, its build configuration
+    /// and the device's reset reason to this JSON file on connecting, as a
+    /// record of the device which produced the data. The file is rewritten on
+    /// each reconnection.
+    #[arg(long)]
+    device_info: Option<std::path::PathBuf>,
+
+    /// Log the mean and standard deviation of the intervals between triggers
+    /// every this many seconds, e.g. to check the regularity of a periodic
+    /// source. They are logged at exit in any case.
+    #[arg(long)]
+    log_interval_stats: Option<f64>,
+
+    /// Seconds between writes of `--dump-samples`
+    #[arg(long, default_value_t = 10.0, requires = "dump_samples")]
+    dump_samples_interval: f64,
+
+    /// Push the session's metrics (triggers written, clock model readiness,
+    /// round trip time, clock drift and missed pings) to the Prometheus
+    /// Pushgateway at this URL, e.g. `http://localhost:9091`. Metrics are
+    /// grouped under the labels `job="red_button_trigger_timestamp"` and
+    /// `instance` set to the device path with characters other than letters
+    /// and digits replaced by `_`, e.g. `_dev_ttyACM0`. Failed pushes are
+    /// logged and do not stop recording.
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_push: Option<String>,
+
+    /// Seconds between pushes of `--metrics-push`
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t = 15.0, requires = "metrics_push")]
+    metrics_push_interval: f64,
+
+    /// Request the device's status every this many seconds and record its
+    /// memory usage in an annotation row, e.g. to confirm over a long session
+    /// that the firmware is not close to running out of stack
+    #[arg(long, value_parser = parse_positive)]
+    record_status_interval: Option<f64>,
+
+    /// Record in the `host_delay_seconds` column how long each trigger message
+    /// waited between being read from the serial port and being processed.
+    /// Consistently large values show that the host is too busy to timestamp
+    /// accurately, and that pinning this process to a CPU or reducing the
+    /// system's load would help.
+    #[arg(long)]
+    record_host_delay: bool,
+
+    /// Arm the device on connecting, so that it reports triggers. Firmware
+    /// built with the `require-arm` feature reports none until armed, so that
+    /// none is recorded before the experiment is deliberately started. The
+    /// armed state is not stored, so the device is disarmed again after a
+    /// reset, and is armed again when this program reconnects.
+    #[arg(long)]
+    arm: bool,
+
+    /// Exit with an error, rather than logging and continuing, if a trigger
+    /// may have been lost or mistimed: on a gap in the trigger sequence, an
+    /// unreadable message from the device, a trigger whose time cannot be
+    /// computed, any failure to write to an output, or a device clock frequency
+    /// which disagrees with the clock model
+    #[arg(long)]
+    strict: bool,
+
+    /// Exit with an error during the handshake if the firmware does not
+    /// support this feature (may be repeated). Options which use a feature
+    /// only warn if the firmware lacks it, unless `--strict` is given.
+    #[arg(long, value_enum)]
+    require: Vec<RequiredCapability>,
+
+    /// Do not log each trigger to the console. Triggers are still written to
+    /// the output, and warnings and other messages are still logged.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Time zone in which each trigger is logged to the console. The output
+    /// is unaffected.
+    #[arg(long, value_enum, default_value_t = DisplayTz::Local)]
+    display_tz: DisplayTz,
+
+    /// Run unattended, e.g. as a systemd service or in a container: without a
+    /// device path, exit with an error rather than listing the serial ports,
+    /// and log without terminal colors. Give a stable device path such as
+    /// /dev/serial/by-id/..., an absolute --output-dir and --quiet, and have
+    /// the supervisor restart the program when it exits with an error
+    /// (`Restart=on-failure`). Logs go to stderr, which systemd sends to the
+    /// journal.
+    #[arg(long)]
+    service: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the instantaneous level of the trigger input and exit
+    Readpin {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Turn the green LED on or off and exit
+    Led {
+        /// Serial device to open
+        device_path: String,
+        state: LedState,
+    },
+    /// Print the settings the firmware was built with and exit
+    Config {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Print the health of the device and exit
+    Status {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Print the runtime configuration in effect on the device, as last set
+    /// by a host, and exit
+    Getconfig {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Measure round trip times to the device, print statistics and exit
+    Probe {
+        /// Serial device to open
+        device_path: String,
+        /// Number of pings to send
+        #[arg(short = 'n', long, default_value_t = 100)]
+        count: usize,
+    },
+    /// Measure the latency from a trigger edge to its detection with the
+    /// device's loopback output, store it in the device, which the firmware
+    /// then subtracts from each trigger, and exit
+    Calibrate {
+        /// Serial device to open
+        device_path: String,
+        /// Latency in device ticks (microseconds) to store instead of
+        /// measuring it, e.g. as measured externally with an oscilloscope.
+        /// Zero removes the correction.
+        offset_ticks: Option<u32>,
+        /// Number of loopback pulses whose latencies are averaged
+        #[arg(short = 'n', long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+    },
+    /// Have the device send an epoch mark, a reference instant which every
+    /// program recording from it logs against its own clock, and exit
+    Markepoch {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Set the device's real-time clock to the host's UTC time and exit
+    Setrtc {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Reboot the device into its USB bootloader, as if BOOTSEL were held, so
+    /// that new firmware can be copied to the drive which then appears, and
+    /// exit. Any recording from the device ends.
+    Bootloader {
+        /// Serial device to open
+        device_path: String,
+    },
+    /// Have the device send synthetic triggers at a fixed period, print
+    /// statistics of their timing and exit
+    Testpattern {
+        /// Serial device to open
+        device_path: String,
+        /// Period of the triggers in microseconds
+        #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u32).range(100..=1_000_000))]
+        period_us: u32,
+        /// Number of triggers
+        #[arg(short = 'n', long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(2..))]
+        count: u32,
+    },
+    /// Fetch the most recent triggers kept by the device, e.g. from before a
+    /// host was connected, write them as CSV to stdout and exit
+    History {
+        /// Serial device to open
+        device_path: String,
+        /// Most triggers to fetch
+        #[arg(short = 'n', long, default_value_t = 64)]
+        max: u16,
+    },
+    /// Send payloads for the device to echo back, check them and exit
+    Echo {
+        /// Serial device to open
+        device_path: String,
+        /// Number of payloads to send
+        #[arg(short = 'n', long, default_value_t = 100)]
+        count: usize,
+    },
+    /// Convert a recorded trigger CSV file to Apache Parquet and exit
+    Convert {
+        /// CSV file to read
+        input: std::path::PathBuf,
+        /// Parquet file to write (defaults to the input with a `.parquet`
+        /// extension)
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+        /// Leave out rows before this time (RFC 3339, e.g.
+        /// 2024-01-01T12:00:00Z)
+        #[arg(long)]
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        /// Leave out rows after this time (RFC 3339)
+        #[arg(long)]
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// Check a recorded trigger CSV file for a malformed header or rows,
+    /// triggers out of time order, local times which disagree with UTC and
+    /// recorded gaps in the trigger sequence, print any found and exit,
+    /// unsuccessfully if there are any
+    Validate {
+        /// CSV file to read
+        input: std::path::PathBuf,
+    },
+    /// Print the serial ports which may be the device and exit
+    List {
+        /// Print the ports with their USB metadata as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ClockModelBasis {
+    Auto,
+    Micros,
+    Nanos,
+}
+
+/// Firmware features which `--require` can check for.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum RequiredCapability {
+    /// Reporting uptime and reset reason (the `status` subcommand)
+    Status,
+    /// Reporting the build configuration, needed to check the device clock rate
+    BuildConfig,
+    /// Pairing presses with releases (`--pair-edges`)
+    PairEdges,
+    /// Two trigger inputs combined on the device (`--differential`)
+    Differential,
+    /// The analog trigger input (`--adc-channel`), built with the `adc` feature
+    Analog,
+    /// The buzzer output (`--buzzer-ms`), built with the `buzzer` feature
+    Buzzer,
+    /// A stored trigger latency (the `calibrate` subcommand)
+    Calibration,
+    /// Beacons sent by the device (`--beacon-ms`)
+    Beacon,
+    /// A CRC on each message, built with the `crc` feature
+    Crc,
+    /// Arming and disarming the device (`--arm`)
+    Arm,
+    /// Triggers kept by the device (the `history` subcommand)
+    History,
+    /// A minimum interval between triggers on the device
+    /// (`--device-min-interval-ms`)
+    MinInterval,
+    /// A real-time clock on the device, built with the `rtc` feature
+    Rtc,
+    /// Rebooting into the bootloader (the `bootloader` subcommand)
+    Bootloader,
+}
+
+impl RequiredCapability {
+    fn bit(self) -> u32 {
+        match self {
+            Self::Status => capabilities::STATUS,
+            Self::BuildConfig => capabilities::BUILD_CONFIG,
+            Self::PairEdges => capabilities::PAIR_EDGES,
+            Self::Differential => capabilities::DIFFERENTIAL,
+            Self::Analog => capabilities::ANALOG,
+            Self::Buzzer => capabilities::BUZZER,
+            Self::Calibration => capabilities::CALIBRATION,
+            Self::Beacon => capabilities::BEACON,
+            Self::Crc => capabilities::CRC,
+            Self::Arm => capabilities::ARM,
+            Self::History => capabilities::HISTORY,
+            Self::MinInterval => capabilities::MIN_INTERVAL,
+            Self::Rtc => capabilities::RTC,
+            Self::Bootloader => capabilities::BOOTLOADER,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Status => "status requests",
+            Self::BuildConfig => "querying the build configuration",
+            Self::PairEdges => "pairing edges",
+            Self::Differential => "differential triggers",
+            Self::Analog => "the analog trigger input",
+            Self::Buzzer => "the buzzer output",
+            Self::Calibration => "calibration",
+            Self::Beacon => "beacons",
+            Self::Crc => "message CRCs",
+            Self::Arm => "arming",
+            Self::History => "trigger history",
+            Self::MinInterval => "the minimum trigger interval",
+            Self::Rtc => "a real-time clock",
+            Self::Bootloader => "entering the bootloader",
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DisplayTz {
+    Local,
+    Utc,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ClockModelFit {
+    LeastSquares,
+    Robust,
 }
 
-fn to_device_name(spi: &tokio_serial::SerialPortInfo) -> String {
-    let name = spi.port_name.clone();
-    // This is necessary on linux:
-    name.replace("/sys/class/tty/", "/dev/")
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum LedState {
+    On,
+    Off,
+}
+
+/// Parse a number of seconds which can be converted to a duration, i.e. is
+/// finite, not negative and not too large.
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    std::time::Duration::try_from_secs_f64(seconds).map_err(|e| format!("{e}"))?;
+    Ok(seconds)
+}
+
+/// Parse a finite number greater than zero.
+fn parse_positive(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if !(value.is_finite() && value > 0.0) {
+        return Err("must be a finite number greater than 0".into());
+    }
+    Ok(value)
+}
+
+async fn read_pin(device_path: &str, options: device::ConnectOptions) -> anyhow::Result<()> {
+    let (channel, high) =
+        device::request_reply(device_path, ToDevice::ReadPin, options, |msg| match msg {
+            FromDevice::PinState { channel, high } => Some((channel, high)),
+            _ => None,
+        })
+        .await?;
+    let level = if high { "high" } else { "low" };
+    println!("channel {channel}: {level}");
+    Ok(())
+}
+
+async fn query_config(device_path: &str, options: device::ConnectOptions) -> anyhow::Result<()> {
+    let config = device::request_reply(
+        device_path,
+        ToDevice::QueryConfig,
+        options,
+        |msg| match msg {
+            FromDevice::BuildConfig(config) => Some(config),
+            _ => None,
+        },
+    )
+    .await?;
+    let polarity = if config.active_high {
+        "active high"
+    } else {
+        "active low"
+    };
+    println!("trigger pin: GPIO{}", config.trigger_pin);
+    println!("pull: {:?}", config.pull);
+    println!("polarity: {polarity}");
+    println!("debounce: {} ticks", config.debounce_ticks);
+    println!("transport: {:?}", config.transport);
+    println!("tick frequency: {} Hz", config.tick_hz);
+    println!("trigger offset: {} ticks", config.trigger_offset_ticks);
+    println!("boots disarmed: {}", config.boots_disarmed);
+    Ok(())
+}
+
+async fn get_config(device_path: &str, options: device::ConnectOptions) -> anyhow::Result<()> {
+    let state = device::request_reply(device_path, ToDevice::GetConfig, options, |msg| match msg {
+        FromDevice::ConfigState(state) => Some(state),
+        _ => None,
+    })
+    .await?;
+    let config = &state.config;
+    let polarity = if config.active_high {
+        "active high"
+    } else {
+        "active low"
+    };
+    let ticks_or_off = |ticks: u32| {
+        if ticks == 0 {
+            "off".to_string()
+        } else {
+            format!("{ticks} ticks")
+        }
+    };
+    println!("polarity: {polarity}");
+    println!("armed: {}", state.armed);
+    println!(
+        "debounce: press {} ticks, release {} ticks{}",
+        state.debounce.press_ticks,
+        state.debounce.release_ticks,
+        if config.debounce.is_some() {
+            ""
+        } else {
+            " (built in)"
+        }
+    );
+    println!("report last ping: {}", config.report_last_ping);
+    println!("pair edges: {}", config.pair_edges);
+    println!("differential: {}", config.differential);
+    match &config.adc {
+        Some(adc) => println!(
+            "analog input: channel {}, threshold {}, hysteresis {}",
+            adc.channel, adc.threshold, adc.hysteresis
+        ),
+        None => println!("analog input: off"),
+    }
+    println!("buzzer: {}", ticks_or_off(config.buzzer_ticks));
+    println!(
+        "beacon interval: {}",
+        ticks_or_off(config.beacon_interval_ticks)
+    );
+    println!(
+        "minimum interval: {}",
+        ticks_or_off(config.min_interval_ticks)
+    );
+    match &config.daily_window {
+        Some(window) => println!("daily window: {}", device::describe_daily_window(window)),
+        None => println!("daily window: off"),
+    }
+    Ok(())
+}
+
+async fn query_status(device_path: &str, options: device::ConnectOptions) -> anyhow::Result<()> {
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &ToDevice::StatusRequest) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    let status = device::send_request(
+        &mut framed,
+        ToDevice::StatusRequest,
+        options,
+        |msg| match msg {
+            FromDevice::Status(status) => Some(status),
+            _ => None,
+        },
+    )
+    .await?;
+    // The uptime is in device ticks, whose rate is in the build
+    // configuration.
+    if info.supports(capabilities::BUILD_CONFIG) {
+        let config =
+            device::send_request(
+                &mut framed,
+                ToDevice::QueryConfig,
+                options,
+                |msg| match msg {
+                    FromDevice::BuildConfig(config) => Some(config),
+                    _ => None,
+                },
+            )
+            .await?;
+        println!(
+            "uptime: {:.3} s",
+            status.uptime_ticks as f64 / f64::from(config.tick_hz)
+        );
+    } else {
+        println!("uptime: {} ticks", status.uptime_ticks);
+    }
+    println!(
+        "idle loop rate: {} iterations per second since the previous status",
+        status.idle_loops_per_second
+    );
+    println!("reset reason: {:?}", status.reset_reason);
+    println!("armed: {}", status.armed);
+    println!(
+        "triggers suppressed by the minimum interval: {}",
+        status.suppressed_triggers
+    );
+    println!(
+        "triggers suppressed outside the daily window: {}",
+        status.outside_window_triggers
+    );
+    println!("{}", memory_usage(&status));
+    if let Some(line_state) = &status.line_state {
+        println!("{}", describe_line_state(line_state));
+    }
+    Ok(())
+}
+
+/// Describe the host's serial settings as seen by the device, e.g.
+/// "115200 baud 8N1".
+fn describe_line_state(line_state: &LineState) -> String {
+    let parity = match line_state.parity {
+        0 => "N",
+        1 => "O",
+        2 => "E",
+        3 => "M",
+        4 => "S",
+        _ => "?",
+    };
+    let stop_bits = match line_state.stop_bits {
+        0 => "1",
+        1 => "1.5",
+        2 => "2",
+        _ => "?",
+    };
+    let on_off = |on| if on { "on" } else { "off" };
+    format!(
+        "host serial settings: {} baud {}{parity}{stop_bits}, DTR {}, RTS {}",
+        line_state.baud,
+        line_state.data_bits,
+        on_off(line_state.dtr),
+        on_off(line_state.rts)
+    )
+}
+
+/// Describe the memory usage in a device status.
+fn memory_usage(status: &DeviceStatus) -> String {
+    format!(
+        "device memory: {} bytes of stack never used, at most {} of {} receive frames queued",
+        status.stack_free_bytes, status.rx_queue_high_water, status.rx_queue_capacity
+    )
+}
+
+/// Difference, in seconds, between the time of a trigger according to the
+/// device's real-time clock and the clock model above which it is warned
+/// about. The RTC is set to a whole second and drifts by up to a few seconds
+/// per month.
+const RTC_MISMATCH_SECONDS: f64 = 1.0;
+
+/// Free stack, in bytes, below which the device status is warned about.
+const LOW_STACK_BYTES: u32 = 4096;
+
+/// Minimum interval between logs of the progress of the clock model warm-up.
+const WARMUP_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+async fn set_led(
+    device_path: &str,
+    on: bool,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    device::request_reply(device_path, ToDevice::SetLed(on), options, |msg| {
+        (msg == FromDevice::Ack).then_some(())
+    })
+    .await
+}
+
+/// Store the trigger offset `offset_ticks` in the device or, if none is
+/// given, the latency measured with [measure_trigger_latency].
+async fn calibrate(
+    device_path: &str,
+    offset_ticks: Option<u32>,
+    count: u32,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &ToDevice::SetTriggerOffset(0)) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    let offset_ticks = match offset_ticks {
+        Some(offset_ticks) => offset_ticks,
+        None => {
+            if let Some(name) = device::missing_capability(&info, &ToDevice::LoopbackPulse) {
+                anyhow::bail!(
+                    "The firmware does not support {name}, so the latency to store must be given."
+                );
+            }
+            measure_trigger_latency(&mut framed, count, options).await?
+        }
+    };
+    device::send_request(
+        &mut framed,
+        ToDevice::SetTriggerOffset(offset_ticks),
+        options,
+        |msg| (msg == FromDevice::Ack).then_some(()),
+    )
+    .await?;
+    println!("Stored trigger offset of {offset_ticks} ticks.");
+    Ok(())
+}
+
+/// Pulse the device's loopback output `count` times and return the mean
+/// latency, in device ticks, from the tick at which the device drove each
+/// edge to the tick at which its trigger input detected it.
+async fn measure_trigger_latency(
+    framed: &mut device::DeviceFramed,
+    count: u32,
+    options: device::ConnectOptions,
+) -> anyhow::Result<u32> {
+    let mut latencies = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        // Sent once, as a resent request would pulse the output again.
+        framed.send(ToDevice::LoopbackPulse).await?;
+        let edge = device::wait_for_reply(framed, options.reply_timeout, |msg| match msg {
+            FromDevice::LoopbackEdge { driven, detected } => Some(Ok((driven, detected))),
+            FromDevice::Rejected => Some(Err(())),
+            _ => None,
+        })
+        .await?;
+        let Ok((driven, detected)) = edge else {
+            anyhow::bail!("The device rejected the loopback pulse.");
+        };
+        let Some(detected) = detected else {
+            anyhow::bail!(
+                "The trigger input did not see the loopback edge. Is the loopback output wired to it?"
+            );
+        };
+        latencies.push(detected.saturating_sub(driven));
+    }
+    let mean = latencies.iter().sum::<u64>() as f64 / latencies.len() as f64;
+    println!(
+        "Latency over {count} pulses: mean {mean:.1}, min {}, max {} ticks.",
+        latencies.iter().min().unwrap(),
+        latencies.iter().max().unwrap()
+    );
+    u32::try_from(mean.round() as u64).wrap_err("The measured latency is too large to store.")
+}
+
+/// Print the serial ports, marking those with this firmware's USB IDs.
+fn list_ports(json: bool) -> anyhow::Result<()> {
+    let ports = device::available_ports().wrap_err("Serial ports could not be listed.")?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&ports)?);
+        return Ok(());
+    }
+    for port in ports.iter() {
+        let mut line = port.path.clone();
+        if let Some(product) = &port.product {
+            line.push_str(&format!(" ({product})"));
+        }
+        if port.is_trigger_device {
+            line.push_str(" [trigger device]");
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Have the device send an epoch mark. Sent once, without retries, as each
+/// send marks another epoch for every program recording from the device.
+async fn mark_epoch(device_path: &str, options: device::ConnectOptions) -> anyhow::Result<()> {
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &ToDevice::MarkEpoch) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    framed.send(ToDevice::MarkEpoch).await?;
+    let tick = device::wait_for_reply(&mut framed, options.reply_timeout, |msg| match msg {
+        FromDevice::EpochMark(tick) => Some(tick),
+        _ => None,
+    })
+    .await?;
+    println!("Marked epoch at device tick {tick}.");
+    Ok(())
+}
+
+/// Set the device's real-time clock to the host's UTC time.
+async fn set_rtc(device_path: &str, options: device::ConnectOptions) -> anyhow::Result<()> {
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &ToDevice::SetRtc(0)) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    // Setting the RTC restarts its current second, so set it at the start of
+    // a second. It is then late by the transport delay.
+    let now = chrono::Utc::now();
+    let next_second = now.timestamp() + 1;
+    let start = chrono::DateTime::from_timestamp(next_second, 0).unwrap();
+    tokio::time::sleep((start - now).to_std()?).await;
+    framed.send(ToDevice::SetRtc(next_second)).await?;
+    device::wait_for_reply(&mut framed, options.reply_timeout, |msg| {
+        (msg == FromDevice::Ack).then_some(())
+    })
+    .await?;
+    println!("Set the real-time clock to {start}.");
+    Ok(())
+}
+
+async fn enter_bootloader(
+    device_path: &str,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    let request = ToDevice::EnterBootloader {
+        confirm: BOOTLOADER_CONFIRM,
+    };
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &request) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+    // Sent once, without retries, as the device reboots on receiving it.
+    framed.send(request).await?;
+    device::wait_for_reply(&mut framed, options.reply_timeout, |msg| match msg {
+        FromDevice::Ack => Some(Ok(())),
+        FromDevice::CrcMismatch => Some(Err(anyhow::anyhow!("The device discarded the request."))),
+        FromDevice::Rejected => Some(Err(anyhow::anyhow!("The device rejected the request."))),
+        _ => None,
+    })
+    .await??;
+    println!(
+        "The device is rebooting into its bootloader. Copy the firmware to the RPI-RP2 drive."
+    );
+    Ok(())
 }
 
 #[tokio::main]
@@ -36,19 +990,112 @@ async fn main() -> anyhow::Result<()> {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
     }
+
+    let mut opt = Cli::parse();
+    if let Some(path) = &opt.config {
+        opt = Cli::parse_from(config::merged_args(std::env::args_os().collect(), path)?);
+    }
+
+    // Terminal colors would be escape codes in a log file or the journal.
+    let ansi = !opt.service && std::io::stderr().is_terminal();
     let collector = tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_writer(std::io::stderr).with_ansi(ansi))
         .with(tracing_subscriber::filter::EnvFilter::from_default_env());
     tracing::subscriber::set_global_default(collector)?;
 
-    let opt = Cli::parse();
-    let device_path = match opt.device_path {
+    if opt.no_version_check {
+        tracing::warn!("FIRMWARE VERSION CHECKING IS DISABLED. Use for development only.");
+    }
+    let options = device::ConnectOptions {
+        no_version_check: opt.no_version_check,
+        reply_timeout: std::time::Duration::from_millis(opt.reply_timeout_ms),
+        retries: opt.reply_retries,
+    };
+    match &opt.command {
+        Some(Command::Readpin { device_path }) => {
+            return read_pin(device_path, options).await;
+        }
+        Some(Command::Led { device_path, state }) => {
+            let on = matches!(state, LedState::On);
+            return set_led(device_path, on, options).await;
+        }
+        Some(Command::Config { device_path }) => {
+            return query_config(device_path, options).await;
+        }
+        Some(Command::Status { device_path }) => {
+            return query_status(device_path, options).await;
+        }
+        Some(Command::Getconfig { device_path }) => {
+            return get_config(device_path, options).await;
+        }
+        Some(Command::Probe { device_path, count }) => {
+            return probe::probe(device_path, *count, options).await;
+        }
+        Some(Command::Calibrate {
+            device_path,
+            offset_ticks,
+            count,
+        }) => {
+            return calibrate(device_path, *offset_ticks, *count, options).await;
+        }
+        Some(Command::Testpattern {
+            device_path,
+            period_us,
+            count,
+        }) => {
+            return probe::test_pattern(device_path, *period_us, *count, options).await;
+        }
+        Some(Command::Markepoch { device_path }) => {
+            return mark_epoch(device_path, options).await;
+        }
+        Some(Command::Setrtc { device_path }) => {
+            return set_rtc(device_path, options).await;
+        }
+        Some(Command::Bootloader { device_path }) => {
+            return enter_bootloader(device_path, options).await;
+        }
+        Some(Command::History { device_path, max }) => {
+            return history::history(device_path, *max, options).await;
+        }
+        Some(Command::Echo { device_path, count }) => {
+            return probe::echo(device_path, *count, options).await;
+        }
+        Some(Command::Convert {
+            input,
+            output,
+            since,
+            until,
+        }) => {
+            let window = convert::TimeWindow::new(*since, *until)?;
+            let output = output
+                .clone()
+                .unwrap_or_else(|| input.with_extension("parquet"));
+            return convert::csv_to_parquet(input, &output, &window);
+        }
+        Some(Command::Validate { input }) => {
+            return validate::validate_file(input);
+        }
+        Some(Command::List { json }) => {
+            return list_ports(*json);
+        }
+        None => {}
+    }
+    if !(0.0..=1.0).contains(&opt.rtt_smoothing) {
+        anyhow::bail!("--rtt-smoothing must be between 0 and 1");
+    }
+    let device_path = match opt.device_path.clone() {
+        None if opt.service => {
+            anyhow::bail!(
+                "No device path was given (--service). Pass the path of the device, \
+                 preferably one which does not change between boots, e.g. under \
+                 /dev/serial/by-id/ on Linux."
+            );
+        }
         None => {
-            let available_ports: Vec<_> = tokio_serial::available_ports()?
-                .iter()
-                .map(to_device_name)
-                .filter(|x| x != "/dev/ttyS0")
-                .collect();
+            let available_ports = device::available_port_names().wrap_err(
+                "No device path was given and serial ports could not be listed. \
+                 Pass the path of the device, e.g. /dev/ttyACM0 or COM3.",
+            )?;
             println!("No device path was given. Available options:");
             for p in available_ports.iter() {
                 println!("{p}");
@@ -57,101 +1104,781 @@ async fn main() -> anyhow::Result<()> {
         }
         Some(p) => p,
     };
-    let baud_rate = 115_200;
-    tracing::info!("Opening device at path {}", device_path);
+    let framed = device::open_device(&device_path)?;
 
-    #[allow(unused_mut)]
-    let mut serial_device = tokio_serial::new(&device_path, baud_rate)
-        .open_native_async()
-        .with_context(|| format!("opening device {device_path}"))?;
-    tracing::info!("Device opened");
+    let mut sinks: Vec<Box<dyn trigger_log::TriggerSink>> = Vec::new();
+    let mut output_path = None;
+    if opt.output_stdout_csv {
+        let stdout = Box::new(std::io::stdout());
+        sinks.push(Box::new(trigger_log::CsvSink::new(
+            stdout,
+            &opt.extra_column,
+        )?));
+    } else {
+        let full_path = match &opt.output_file {
+            Some(path) => {
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!("ensuring existence of directory {}", parent.display())
+                    })?;
+                }
+                path.clone()
+            }
+            None => {
+                let local = chrono::Local::now();
+                let output_filename_template = "triggers_%Y%m%d_%H%M%S.csv".to_string();
+                let filename = local.format(&output_filename_template).to_string();
+                #[cfg(feature = "compress")]
+                let filename = if opt.compress {
+                    filename + ".gz"
+                } else {
+                    filename
+                };
 
+                let output_dir = paths::expand_output_dir(&opt.output_dir)?;
+                std::fs::create_dir_all(&output_dir).with_context(|| {
+                    format!("ensuring existence of directory {}", output_dir.display())
+                })?;
+                output_dir.join(filename)
+            }
+        };
+        tracing::info!("Saving data to {}", full_path.display());
+        if let Some(capacity) = opt.ring_capacity {
+            sinks.push(Box::new(trigger_log::RingCsvSink::new(
+                full_path.clone(),
+                capacity.try_into()?,
+                &opt.extra_column,
+            )?));
+        } else {
+            let fd = std::fs::File::create(&full_path)
+                .with_context(|| format!("creating file {}", full_path.display()))?;
+            let output: Box<dyn std::io::Write> = Box::new(fd);
+            #[cfg(feature = "compress")]
+            let output = if opt.compress {
+                trigger_log::gzip(output)
+            } else {
+                output
+            };
+            sinks.push(Box::new(trigger_log::CsvSink::new(
+                output,
+                &opt.extra_column,
+            )?));
+        }
+        output_path = Some(full_path);
+    }
     #[cfg(unix)]
-    serial_device
-        .set_exclusive(false)
-        .expect("Unable to set serial port exclusive to false");
-
-    let local = chrono::Local::now();
-    let output_filename_template = "triggers_%Y%m%d_%H%M%S.csv".to_string();
-    let filename = local.format(&output_filename_template).to_string();
-
-    let output_dir = std::path::PathBuf::from(shellexpand::full(&opt.output_dir)?.to_string());
-    std::fs::create_dir_all(&output_dir)
-        .with_context(|| format!("ensuring existence of directory {}", output_dir.display()))?;
-
-    let full_path = output_dir.join(filename);
-    let fd = std::fs::File::create(&full_path)
-        .with_context(|| format!("creating file {}", full_path.display()))?;
-    tracing::info!("Saving data to {}", full_path.display());
-    let mut csv_wtr = csv::Writer::from_writer(fd);
-
-    let framed = tokio_util::codec::Framed::new(
-        serial_device,
-        JsonLinesCodec::<FromDevice, ToDevice>::default(),
+    if let Some(path) = &opt.unix_socket {
+        tracing::info!("Streaming rows to consumers of {}", path.display());
+        sinks.push(Box::new(unix_socket::UnixSocketSink::bind(path.clone())?));
+    }
+    let max_write_failures = if opt.strict {
+        0
+    } else {
+        opt.tolerate_write_failures
+    };
+    let mut trigger_log = trigger_log::TriggerLog::new(sinks, max_write_failures);
+    trigger_log.quiet = opt.quiet;
+    trigger_log.display_utc = matches!(opt.display_tz, DisplayTz::Utc);
+
+    let mut rate_limiter = opt
+        .max_triggers_per_second
+        .map(|rate| rate_limiter::RateLimiter::new(rate, std::time::Instant::now()));
+
+    let result = tokio::select! {
+        result = run_with_reconnect(framed, &device_path, &mut trigger_log, &mut rate_limiter, &opt) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    if result.is_ok() && opt.mark_end_of_session {
+        let annotation = format!("end of session: {} triggers", trigger_log.n_written);
+        trigger_log.write_annotation(chrono::Utc::now(), &annotation)?;
+    }
+
+    tracing::info!("Recorded {} triggers.", trigger_log.n_written);
+    if let Some(summary) = trigger_log.interval_stats.summary() {
+        tracing::info!("{summary}");
+    }
+    if trigger_log.n_discarded_lines > 0 {
+        tracing::info!(
+            "Discarded {} malformed lines from the device. {} bytes were lost to overruns \
+             of the host's serial buffer.",
+            trigger_log.n_discarded_lines,
+            trigger_log.n_overrun_bytes
+        );
+    }
+    if let Some(rate_limiter) = &rate_limiter {
+        tracing::info!(
+            "Dropped {} triggers exceeding --max-triggers-per-second.",
+            rate_limiter.n_dropped()
+        );
+    }
+    if let Some(output_path) = output_path.filter(|_| opt.sort_output) {
+        trigger_log::sort_csv_file(&output_path)?;
+    }
+    result
+}
+
+/// Run sessions with the device, reconnecting whenever the connection is lost.
+async fn run_with_reconnect(
+    mut framed: device::DeviceFramed,
+    device_path: &str,
+    trigger_log: &mut trigger_log::TriggerLog,
+    rate_limiter: &mut Option<rate_limiter::RateLimiter>,
+    opt: &Cli,
+) -> anyhow::Result<()> {
+    let mut backoff = backoff::Backoff::new(
+        std::time::Duration::from_millis(500),
+        std::time::Duration::from_secs_f64(opt.reconnect_max_delay),
     );
+    loop {
+        backoff.reset();
+        run_session(framed, trigger_log, rate_limiter, opt).await?;
+        tracing::warn!("Device disconnected.");
+        framed = loop {
+            let delay = backoff.next_delay();
+            tracing::info!(
+                "Attempting to reconnect in {:.1} seconds.",
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+            match device::open_device(device_path) {
+                Ok(framed) => break framed,
+                Err(e) => tracing::warn!("{e:#}"),
+            }
+        };
+    }
+}
+
+/// Count and log the lines which the codec discarded since last called.
+///
+/// Lines are lost either to the host not reading fast enough, when the
+/// operating system counts overruns of its serial buffer, or to corruption
+/// on the link or on the device. Telling these apart points to the fix.
+fn check_discarded_lines(
+    discarded_lines: &codec::DiscardedLines,
+    overrun_counter: &device::OverrunCounter,
+    prev: &mut (u64, Option<u64>),
+    trigger_log: &mut trigger_log::TriggerLog,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let discarded = discarded_lines.get();
+    if discarded == prev.0 {
+        return Ok(());
+    }
+    let n = discarded - prev.0;
+    let overruns = overrun_counter.get();
+    let lost_bytes = overruns
+        .zip(prev.1)
+        .map_or(0, |(now, then)| now.saturating_sub(then));
+    *prev = (discarded, overruns);
+    trigger_log.n_discarded_lines += n;
+    trigger_log.n_overrun_bytes += lost_bytes;
+    if lost_bytes > 0 {
+        tracing::warn!(
+            "Discarded {n} line(s) from the device after {lost_bytes} bytes were lost to an \
+             overrun of the host's serial buffer. The host is not reading from the device \
+             fast enough, e.g. because it is overloaded."
+        );
+    } else {
+        tracing::warn!("Discarded {n} malformed line(s) from the device.");
+    }
+    if strict {
+        anyhow::bail!("Malformed lines from the device (--strict)");
+    }
+    Ok(())
+}
+
+/// Detect the device clock going backwards, as happens when the device resets.
+///
+/// Timestamps before and after a reset are on different device clock epochs,
+/// so the clock model is restarted and the discontinuity is recorded in the
+/// trigger log.
+fn detect_device_clock_reset(
+    device_timestamp: u64,
+    last_device_timestamp: &mut Option<u64>,
+    clock_model: &mut clock_model::ClockModel,
+    trigger_log: &mut trigger_log::TriggerLog,
+    host_time: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<()> {
+    // Messages are not strictly ordered by device timestamp (a trigger is
+    // sent only after its edge is confirmed), so small steps back are normal.
+    // After a reset the device clock restarts from zero.
+    const RESET_THRESHOLD_TICKS: u64 = 1_000_000;
+    match *last_device_timestamp {
+        Some(last) if device_timestamp + RESET_THRESHOLD_TICKS < last => {
+            tracing::warn!("Device clock went backwards. Restarting clock model.");
+            trigger_log.write_annotation(host_time, "device clock reset")?;
+            *clock_model = clock_model::ClockModel::with_basis(
+                chrono::Utc::now(),
+                clock_model.max_rtt(),
+                clock_model.basis(),
+            )
+            .with_options_of(clock_model);
+            *last_device_timestamp = Some(device_timestamp);
+        }
+        Some(last) => *last_device_timestamp = Some(last.max(device_timestamp)),
+        None => *last_device_timestamp = Some(device_timestamp),
+    }
+    Ok(())
+}
+
+/// Write the samples and fit of `clock_model` to `path`, replacing it.
+fn write_samples_dump(
+    path: &std::path::Path,
+    clock_model: &clock_model::ClockModel,
+) -> anyhow::Result<()> {
+    let buf = serde_json::to_vec_pretty(&clock_model.dump())?;
+    std::fs::write(path, buf).with_context(|| format!("writing file {}", path.display()))?;
+    Ok(())
+}
+
+/// A trigger of any kind, as reported by the device.
+struct DeviceTrigger {
+    tick: u64,
+    release_tick: Option<u64>,
+    last_ping: Option<u64>,
+    sequence: u32,
+    level: Option<u16>,
+    /// For a differential trigger, the channel which fired first, or `None`
+    /// if both fired at once, and the ticks until the other fired
+    diff: Option<(Option<u8>, Option<u64>)>,
+    /// Time of the trigger according to the device's real-time clock
+    rtc_unix_micros: Option<i64>,
+}
 
+/// Communicate with an opened device, writing triggers to `trigger_log`.
+///
+/// Returns `Ok(())` when the connection to the device is lost and an error
+/// for failures which reconnecting would not fix.
+async fn run_session(
+    framed: device::DeviceFramed,
+    trigger_log: &mut trigger_log::TriggerLog,
+    rate_limiter: &mut Option<rate_limiter::RateLimiter>,
+    opt: &Cli,
+) -> anyhow::Result<()> {
+    let received = framed.codec().received_bytes();
+    let last_read = framed.codec().last_read();
+    let discarded_lines = framed.codec().discarded_lines();
+    let overrun_counter = device::OverrunCounter::new(&framed);
+    // Lines discarded and bytes lost to overruns as last checked
+    let mut prev_discarded = (discarded_lines.get(), overrun_counter.get());
     let (mut device_tx, mut device_rx) = framed.split();
 
-    device_tx.send(ToDevice::VersionRequest).await?;
+    let device_config = DeviceConfig {
+        active_high: opt.active_high,
+        report_last_ping: opt.anchor_to_last_ping,
+        pair_edges: opt.pair_edges,
+        differential: opt.differential,
+        adc: opt
+            .adc_channel
+            .zip(opt.adc_threshold)
+            .map(|(channel, threshold)| AdcConfig {
+                channel,
+                threshold,
+                hysteresis: opt.adc_hysteresis,
+            }),
+        buzzer_ticks: opt.buzzer_ms.map_or(0, |ms| ms.saturating_mul(1000)),
+        beacon_interval_ticks: opt.beacon_ms.map_or(0, |ms| ms.saturating_mul(1000)),
+        min_interval_ticks: opt
+            .device_min_interval_ms
+            .map_or(0, |ms| ms.saturating_mul(1000)),
+        daily_window: opt.device_window,
+        debounce: opt.debounce_press_us.zip(opt.debounce_release_us).map(
+            |(press_ticks, release_ticks)| Debounce {
+                press_ticks,
+                release_ticks,
+            },
+        ),
+    };
+    if let Err(e) = device_tx.send(ToDevice::VersionRequest).await {
+        tracing::error!("Writing to device: {e}");
+        return Ok(());
+    }
     let version_request_sent = std::time::Instant::now();
     let mut did_receive_version_response = false;
 
-    let mut last_ping = chrono::Utc::now();
-    let mut last_pong = chrono::Utc::now();
+    let host_clock = host_clock::from_options(opt.monotonic_host_clock, opt.ptp_device.as_deref())?;
+    let mut last_ping = host_clock.now();
+    let mut pong_since_ping = true;
+    let mut missed_pongs = 0;
+    let mut smoothed_rtt: Option<f64> = None;
 
+    // Ping at multiples of the interval from the start of the session, and
+    // skip pings which are due while the host is busy rather than sending
+    // them late in a burst. The least-squares clock model weights every sample
+    // equally, so evenly spaced samples spread that weight evenly over time,
+    // whereas a cluster of samples would pull the fit towards the moment it
+    // was taken and make the estimated clock rate less stable.
     let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-    let mut clock_model = clock_model::ClockModel::default();
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let basis = match opt.clock_model_basis {
+        ClockModelBasis::Auto | ClockModelBasis::Micros => clock_model::TimeBasis::Micros,
+        ClockModelBasis::Nanos => clock_model::TimeBasis::Nanos,
+    };
+    let max_rtt = clock_model::ClockModel::default().max_rtt();
+    let fit = match opt.clock_model {
+        ClockModelFit::LeastSquares => clock_model::FitMethod::LeastSquares,
+        ClockModelFit::Robust => clock_model::FitMethod::Robust,
+    };
+    let mut clock_model = clock_model::ClockModel::with_basis(host_clock.now(), max_rtt, basis)
+        .with_fit(fit)
+        .with_max_residual(opt.clock_model_max_residual_ms.map(|ms| ms / 1e3))
+        .with_segment_gain_change(opt.clock_model_segment_ppm.map(|ppm| ppm * 1e-6));
+    let mut last_device_timestamp = None;
+    // When the clock model was last seen not ready, for the warm-up timeout.
+    let mut not_ready_since: Option<std::time::Instant> = None;
+    // Device clock frequency, and whether the clock model has been checked
+    // against it.
+    let mut tick_hz = None;
+    let mut tick_rate_checked = false;
+    let mut last_samples_dump = std::time::Instant::now();
+    let mut last_interval_stats_log = std::time::Instant::now();
+    // When warm-up progress was last logged, and the sample count then
+    let mut last_warmup_log: Option<(std::time::Instant, usize)> = None;
+    #[cfg(feature = "metrics")]
+    let metrics_pusher = opt
+        .metrics_push
+        .as_deref()
+        .map(|url| metrics::Pusher::new(url, opt.device_path.as_deref().unwrap_or_default()))
+        .transpose()?;
+    #[cfg(feature = "metrics")]
+    let mut last_metrics_push = std::time::Instant::now();
+    let mut status_supported = false;
+    // The configuration sent to the device, without what it does not support
+    let mut sent_config: Option<DeviceConfig> = None;
+    // Record of the device for --device-info, from the handshake on, and
+    // whether it has changed since it was written.
+    let mut device_info: Option<device_info::DeviceInfo> = None;
+    let mut device_info_changed = false;
+    // Whether a difference between the device's real-time clock and the
+    // clock model has been warned about.
+    let mut rtc_mismatch_warned = false;
+    // Triggers suppressed by the device as of its latest status, within its
+    // minimum interval and outside its daily window.
+    let mut suppressed_triggers = 0;
+    let mut outside_window_triggers = 0;
+    // The host's serial settings as of the device's latest status
+    let mut line_state = None;
+    let mut last_status_request = std::time::Instant::now();
     loop {
         tokio::select! {
-            Some(from_device) = device_rx.next() => {
-                let recv_time = chrono::Utc::now();
-                match from_device? {
-                    FromDevice::Pong(device_timestamp) => {
-                        last_pong = chrono::Utc::now();
-                        clock_model.update(last_ping,recv_time,device_timestamp);
-                        tracing::debug!("pong utc: {:?}", clock_model.compute_utc(device_timestamp));
-                    }
-                    FromDevice::Trigger(device_timestamp) => {
-                        if let Some(trigger_utc) = clock_model.compute_utc(device_timestamp) {
-                            let timestamp_local: chrono::DateTime<chrono::Local> =
-                            trigger_utc.with_timezone(&chrono::Local);
-                            tracing::info!("trigger: {}", timestamp_local);
-                            let delta_epoch = trigger_utc - chrono::DateTime::UNIX_EPOCH;
-                            let epoch_nanos_utc = delta_epoch.num_nanoseconds().unwrap();
-                            let trig_row = TriggerRow {
-                                timestamp_local,
-                                epoch_nanos_utc,
-                            };
-                            csv_wtr.serialize(trig_row)?;
-                            csv_wtr.flush()?;
-                        } else {
-                            tracing::error!("Could not compute trigger time.");
+            from_device = device_rx.next() => {
+                let recv_time = host_clock.now();
+                let host_delay = last_read.get().map(|read_at| read_at.elapsed());
+                let from_device = match from_device {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        if opt.strict {
+                            return Err(error::TriggerLoggerError::Transport(e.into())).wrap_err("Reading from device (--strict)");
                         }
+                        tracing::error!("Reading from device: {e}");
+                        return Ok(());
                     }
-                    FromDevice::VersionResponse(info) => {
-                        let my_info = VersionResponse::default();
-                        if info != my_info {
-                            anyhow::bail!("firmware has version {:?}, but program has version {:?}", info,my_info);
+                    None => return Ok(()),
+                };
+                check_discarded_lines(&discarded_lines, &overrun_counter, &mut prev_discarded, trigger_log, opt.strict)?;
+                let mut trigger = None;
+                match from_device {
+                    FromDevice::Pong { ping_received, sent } => {
+                        detect_device_clock_reset(ping_received, &mut last_device_timestamp, &mut clock_model, trigger_log, recv_time)?;
+                        pong_since_ping = true;
+                        let device_processing = sent.saturating_sub(ping_received) as f64 / 1e6;
+                        let rtt = (recv_time - last_ping).num_microseconds().unwrap() as f64 / 1e6
+                            - device_processing;
+                        smoothed_rtt = Some(match smoothed_rtt {
+                            None => rtt,
+                            Some(prev) => opt.rtt_smoothing * rtt + (1.0 - opt.rtt_smoothing) * prev,
+                        });
+                        clock_model.update(last_ping, recv_time, ping_received, sent);
+                        if let Some(tick) = clock_model.take_new_segment() {
+                            if let Some(device_info) = device_info.as_mut() {
+                                device_info.clock_segment_starts.push(device_info::ClockSegmentStart { device_tick: tick, utc: clock_model.compute_utc(tick) });
+                                device_info_changed = true;
+                            }
+                        }
+                        if let (false, Some(gain), Some(tick_hz)) = (tick_rate_checked, clock_model.gain(), tick_hz) {
+                            tick_rate_checked = true;
+                            if let Err(msg) = clock_model::check_tick_rate(gain, tick_hz) {
+                                if opt.strict {
+                                    anyhow::bail!("{msg} (--strict)");
+                                }
+                                tracing::warn!("{msg}. Timestamps are probably wrong.");
+                            }
+                        }
+                        let n_samples = clock_model.num_samples();
+                        if n_samples < clock_model.min_samples() && last_warmup_log.is_none_or(|(at, n)| n != n_samples && at.elapsed() >= WARMUP_LOG_INTERVAL) {
+                            tracing::info!("clock warmup: {n_samples}/{} samples", clock_model.min_samples());
+                            last_warmup_log = Some((std::time::Instant::now(), n_samples));
                         }
-                        tracing::info!("Connected to firmware \"{}\" v{}", String::from_utf8_lossy(&info.name), info.version);
+                        tracing::debug!("pong utc: {:?}", clock_model.compute_utc(sent));
+                    }
+                    FromDevice::Trigger { test: true, timestamp, .. } => {
+                        tracing::warn!("Ignoring test trigger at device tick {timestamp}.");
+                    }
+                    FromDevice::Trigger { timestamp, last_ping, sequence, level, test: false, rtc_unix_micros } => {
+                        trigger = Some(DeviceTrigger { tick: timestamp, release_tick: None, last_ping, sequence, level, diff: None, rtc_unix_micros });
+                    }
+                    FromDevice::TriggerPair { press_tick, release_tick, last_ping, sequence, rtc_unix_micros } => {
+                        trigger = Some(DeviceTrigger { tick: press_tick, release_tick, last_ping, sequence, level: None, diff: None, rtc_unix_micros });
+                    }
+                    FromDevice::TriggerDiff { first_channel, timestamp, delta_ticks, simultaneous, last_ping, sequence, rtc_unix_micros } => {
+                        let first_channel = (!simultaneous).then_some(first_channel);
+                        trigger = Some(DeviceTrigger { tick: timestamp, release_tick: None, last_ping, sequence, level: None, diff: Some((first_channel, delta_ticks)), rtc_unix_micros });
+                    }
+                    FromDevice::VersionResponse(_) if did_receive_version_response => {
+                        // Firmware built with announce-version sends its
+                        // version on connecting as well as in reply.
+                    }
+                    FromDevice::VersionResponse(info) => {
+                        device::check_version(&info, opt.no_version_check)?;
                         did_receive_version_response = true;
+                        if opt.device_info.is_some() {
+                            device_info = Some(device_info::DeviceInfo {
+                                device_path: opt.device_path.clone().unwrap_or_default(),
+                                connected_utc: recv_time,
+                                version: info.clone(),
+                                build_config: None,
+                                reset_reason: None,
+                                clock_segment_starts: Vec::new(),
+                            });
+                            device_info_changed = true;
+                        }
+                        for required in &opt.require {
+                            if !info.supports(required.bit()) {
+                                let value = required.to_possible_value().unwrap();
+                                anyhow::bail!("The firmware does not support {} (--require {}).", required.name(), value.get_name());
+                            }
+                        }
+                        // Configure the device, leaving out what the firmware
+                        // does not support.
+                        let mut device_config = device_config.clone();
+                        if let Some(name) = device::missing_capability(&info, &ToDevice::Configure(device_config.clone())) {
+                            if opt.strict {
+                                anyhow::bail!("The firmware does not support {name} (--strict)");
+                            }
+                            tracing::warn!("The firmware does not support {name}. Continuing without it.");
+                            device_config.pair_edges &= info.supports(capabilities::PAIR_EDGES);
+                            device_config.differential &= info.supports(capabilities::DIFFERENTIAL);
+                            if !info.supports(capabilities::BEACON) {
+                                device_config.beacon_interval_ticks = 0;
+                            }
+                            if !info.supports(capabilities::ANALOG) {
+                                device_config.adc = None;
+                            }
+                            if !info.supports(capabilities::BUZZER) {
+                                device_config.buzzer_ticks = 0;
+                            }
+                            if !info.supports(capabilities::MIN_INTERVAL) {
+                                device_config.min_interval_ticks = 0;
+                            }
+                            if !info.supports(capabilities::RTC) {
+                                device_config.daily_window = None;
+                            }
+                            if !info.supports(capabilities::DEBOUNCE) {
+                                device_config.debounce = None;
+                            }
+                        }
+                        if let Some(window) = &device_config.daily_window {
+                            tracing::info!("The device reports triggers only {}.", device::describe_daily_window(window));
+                        }
+                        sent_config = Some(device_config.clone());
+                        let mut requests = vec![ToDevice::Configure(device_config)];
+                        if opt.arm {
+                            if let Some(name) = device::missing_capability(&info, &ToDevice::Arm(true)) {
+                                anyhow::bail!("The firmware does not support {name} (--arm)");
+                            }
+                            requests.push(ToDevice::Arm(true));
+                        }
+                        status_supported = device::missing_capability(&info, &ToDevice::StatusRequest).is_none();
+                        if status_supported {
+                            // For the reset reason.
+                            requests.push(ToDevice::StatusRequest);
+                        } else if opt.record_status_interval.is_some() {
+                            tracing::warn!("The firmware does not support status requests. Its memory usage will not be recorded.");
+                        }
+                        if device::missing_capability(&info, &ToDevice::QueryConfig).is_none() {
+                            requests.push(ToDevice::QueryConfig);
+                        } else {
+                            tracing::info!("The firmware does not report its build configuration. The device clock rate will not be checked.");
+                        }
+                        if device::missing_capability(&info, &ToDevice::GetConfig).is_none() {
+                            // To confirm that the configuration took effect.
+                            requests.push(ToDevice::GetConfig);
+                        }
+                        for request in requests {
+                            if let Err(e) = device_tx.send(request).await {
+                                tracing::error!("Writing to device: {e}");
+                                return Ok(());
+                            }
+                        }
+                    }
+                    FromDevice::PinState { channel, high } => {
+                        tracing::debug!("pin state channel {channel}: {high}");
+                    }
+                    FromDevice::Resumed(tick) => {
+                        tracing::info!("Device USB resumed from suspend at tick {tick}.");
+                        detect_device_clock_reset(tick, &mut last_device_timestamp, &mut clock_model, trigger_log, recv_time)?;
+                        // Ping now rather than waiting for the next tick so that
+                        // the clock model catches up promptly. Later pings are
+                        // at multiples of the interval from now.
+                        interval.reset_immediately();
+                    }
+                    FromDevice::BuildConfig(config) => {
+                        tick_hz = Some(config.tick_hz);
+                        if let Some(device_info) = device_info.as_mut() {
+                            device_info.build_config = Some(config.clone());
+                            device_info_changed = true;
+                        }
+                        if matches!(opt.clock_model_basis, ClockModelBasis::Auto) && config.tick_hz > 1_000_000 {
+                            tracing::info!("Fitting clock model in nanoseconds for {} Hz device clock.", config.tick_hz);
+                            clock_model = clock_model::ClockModel::with_basis(
+                                chrono::Utc::now(),
+                                clock_model.max_rtt(),
+                                clock_model::TimeBasis::Nanos,
+                            )
+                            .with_options_of(&clock_model);
+                        }
+                    }
+                    FromDevice::ConfigState(state) => {
+                        tracing::debug!("device configuration: {state:?}");
+                        if let Some(sent) = sent_config.as_ref().filter(|sent| state.config != **sent) {
+                            tracing::warn!("The device's configuration {:?} differs from that sent, {:?}.", state.config, sent);
+                        }
+                    }
+                    FromDevice::Status(status) => {
+                        tracing::debug!("device status: {status:?}");
+                        if let Some(device_info) = device_info.as_mut().filter(|info| info.reset_reason.is_none()) {
+                            device_info.reset_reason = Some(status.reset_reason);
+                            device_info_changed = true;
+                        }
+                        if let Some(state) = status.line_state.filter(|state| Some(*state) != line_state) {
+                            tracing::info!("{}", describe_line_state(&state));
+                        }
+                        line_state = status.line_state;
+                        if !status.armed {
+                            tracing::warn!("The device is disarmed and reports no triggers. Use --arm to arm it.");
+                        }
+                        if status.stack_free_bytes < LOW_STACK_BYTES {
+                            tracing::warn!("The device is close to running out of stack: {}.", memory_usage(&status));
+                        }
+                        if opt.record_status_interval.is_some() {
+                            trigger_log.write_annotation(recv_time, &memory_usage(&status))?;
+                        }
+                        // The count restarts when the device resets.
+                        if status.suppressed_triggers > suppressed_triggers {
+                            let n = status.suppressed_triggers - suppressed_triggers;
+                            tracing::warn!("The device suppressed {n} trigger(s) within its minimum interval.");
+                            trigger_log.write_annotation(recv_time, &format!("{n} triggers suppressed on the device"))?;
+                        }
+                        suppressed_triggers = status.suppressed_triggers;
+                        if status.outside_window_triggers > outside_window_triggers {
+                            let n = status.outside_window_triggers - outside_window_triggers;
+                            tracing::info!("The device suppressed {n} trigger(s) outside its daily window.");
+                        }
+                        outside_window_triggers = status.outside_window_triggers;
+                        if matches!(status.reset_reason, ResetReason::Watchdog) {
+                            tracing::warn!("The device was last reset by its watchdog, so the firmware hung.");
+                        } else {
+                            tracing::info!("Device reset reason: {:?}", status.reset_reason);
+                        }
+                    }
+                    FromDevice::Beacon(tick) => {
+                        detect_device_clock_reset(tick, &mut last_device_timestamp, &mut clock_model, trigger_log, recv_time)?;
+                        if opt.no_ping {
+                            clock_model.update_one_way(recv_time, tick);
+                        } else {
+                            tracing::trace!("beacon at device tick {tick}");
+                        }
+                    }
+                    FromDevice::EpochMark(tick) => {
+                        detect_device_clock_reset(tick, &mut last_device_timestamp, &mut clock_model, trigger_log, recv_time)?;
+                        if opt.no_ping {
+                            clock_model.update_one_way(recv_time, tick);
+                        }
+                        // Until the clock model is ready, the receive time
+                        // is the best estimate.
+                        let utc = clock_model.compute_utc(tick).unwrap_or(recv_time);
+                        tracing::info!("Epoch mark at device tick {tick}.");
+                        trigger_log.write_annotation(utc, &format!("epoch mark at device tick {tick}"))?;
+                    }
+                    FromDevice::RawEdge { channel, level, raw_tick, tick } => {
+                        let bounce_ticks = tick.saturating_sub(raw_tick);
+                        let edge = if level == opt.active_high { "press" } else { "release" };
+                        tracing::debug!("{edge} on channel {channel} bounced for {bounce_ticks} ticks");
+                        if bounce_ticks > 0 {
+                            let utc = clock_model.compute_utc(raw_tick).unwrap_or(recv_time);
+                            trigger_log.write_annotation(utc, &format!("{edge} on channel {channel} bounced for {bounce_ticks} ticks"))?;
+                        }
+                    }
+                    FromDevice::CrcMismatch => {
+                        if opt.strict {
+                            anyhow::bail!("The device discarded a corrupted message (--strict)");
+                        }
+                        tracing::warn!("The device discarded a corrupted message.");
+                    }
+                    // Loopback edges are only requested by the calibrate
+                    // subcommand.
+                    FromDevice::Ack | FromDevice::Echo(_) | FromDevice::HistoryEntry { .. } | FromDevice::LoopbackEdge { .. } => {}
+                    FromDevice::Rejected => {
+                        tracing::warn!("The device rejected a request.");
+                    }
+                }
+                if let Some(DeviceTrigger { tick: device_timestamp, release_tick, last_ping, sequence, level, diff, rtc_unix_micros }) = trigger {
+                    let n_missing = trigger_log.check_sequence(sequence, recv_time)?;
+                    if opt.strict && n_missing > 0 {
+                        anyhow::bail!("{n_missing} triggers missing (--strict)");
+                    }
+                    detect_device_clock_reset(device_timestamp, &mut last_device_timestamp, &mut clock_model, trigger_log, recv_time)?;
+                    if opt.no_ping {
+                        clock_model.update_one_way(recv_time, device_timestamp);
+                    }
+                    let rtc_utc = rtc_unix_micros.and_then(chrono::DateTime::from_timestamp_micros);
+                    if let (Some(rtc_utc), Some(model_utc)) = (rtc_utc, clock_model.compute_utc(device_timestamp)) {
+                        let difference = (rtc_utc - model_utc).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6;
+                        if difference.abs() > RTC_MISMATCH_SECONDS && !rtc_mismatch_warned {
+                            tracing::warn!("The device's real-time clock differs from the clock model by {difference:.3} s. Set it with the setrtc subcommand.");
+                            rtc_mismatch_warned = true;
+                        }
+                    }
+                    let now = std::time::Instant::now();
+                    if rate_limiter.as_mut().is_some_and(|r| !r.check(now)) {
+                        // Dropped. The rate limiter counts it for reporting.
+                    } else if let Some(trigger_utc) = last_ping
+                        .and_then(|last_ping| clock_model.compute_utc_anchored(device_timestamp, last_ping))
+                        .or_else(|| clock_model.compute_utc(device_timestamp))
+                        // Until the clock model is ready.
+                        .or(rtc_utc)
+                        .or_else(|| opt.no_ping.then_some(recv_time))
+                    {
+                        let ticks_to_seconds = |ticks: u64| clock_model.gain().map(|gain| ticks as f64 * gain / 1e6);
+                        let details = trigger_log::TriggerDetails {
+                            hold_seconds: release_tick.and_then(|release_tick| ticks_to_seconds(release_tick.saturating_sub(device_timestamp))),
+                            adc_level: level,
+                            first_channel: diff.and_then(|(first_channel, _)| first_channel),
+                            channel_delta_seconds: diff.and_then(|(_, delta_ticks)| ticks_to_seconds(delta_ticks?)),
+                            host_delay_seconds: host_delay.filter(|_| opt.record_host_delay).map(|delay| delay.as_secs_f64()),
+                        };
+                        trigger_log.write(trigger_utc, device_timestamp, recv_time, clock_model.latest_error_seconds(), details)?;
+                    } else if opt.strict {
+                        anyhow::bail!("Could not compute trigger time (--strict).");
+                    } else {
+                        tracing::error!("Could not compute trigger time.");
                     }
                 }
             }
             _ = interval.tick() => {
-                last_ping = chrono::Utc::now();
-                device_tx.send(ToDevice::Ping).await?;
-                let delta = last_ping - last_pong;
-                if delta > chrono::TimeDelta::seconds(5) {
-                    tracing::error!("No communication with device in {} seconds.", delta.num_milliseconds()as f64/1000.0);
+                if let Some(rate_limiter) = rate_limiter.as_mut() {
+                    let n_dropped = rate_limiter.take_unreported();
+                    if n_dropped > 0 {
+                        tracing::warn!("Dropped {} triggers exceeding --max-triggers-per-second.", n_dropped);
+                    }
+                }
+                // Until the device replies, its message framing is unknown
+                // and only the version request can be sent.
+                if !opt.no_ping && did_receive_version_response {
+                    if pong_since_ping {
+                        missed_pongs = 0;
+                    } else {
+                        missed_pongs += 1;
+                    }
+                    if missed_pongs >= opt.max_missed_pongs {
+                        tracing::error!("No communication with device for {} pings.", missed_pongs);
+                    }
+                    if let Some(smoothed_rtt) = smoothed_rtt {
+                        tracing::debug!("smoothed round trip time: {:.3} msecs", smoothed_rtt * 1000.0);
+                        if smoothed_rtt > clock_model.max_rtt().num_microseconds().unwrap() as f64 / 1e6 {
+                            tracing::warn!(
+                                "Smoothed round trip time of {:.3} msecs exceeds clock model maximum.",
+                                smoothed_rtt * 1000.0
+                            );
+                        }
+                    }
+                    pong_since_ping = false;
+                    if let Err(e) = device_tx.send(ToDevice::Ping).await {
+                        tracing::error!("Writing to device: {e}");
+                        return Ok(());
+                    }
+                    // Timed once written, since the ping may wait in the
+                    // sink's buffer before it is sent, which would otherwise
+                    // count towards the round trip and bias its midpoint.
+                    last_ping = host_clock.now();
+                }
+            }
+        }
+
+        if let (Some(path), Some(info)) = (&opt.device_info, &device_info) {
+            if device_info_changed {
+                device_info_changed = false;
+                if let Err(e) = info.write(path) {
+                    tracing::warn!("Writing device info: {e:#}");
+                }
+            }
+        }
+
+        if let Some(path) = &opt.dump_samples {
+            if last_samples_dump.elapsed().as_secs_f64() >= opt.dump_samples_interval {
+                last_samples_dump = std::time::Instant::now();
+                if let Err(e) = write_samples_dump(path, &clock_model) {
+                    tracing::warn!("Writing clock model samples: {e:#}");
+                }
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(pusher) = &metrics_pusher {
+            if last_metrics_push.elapsed().as_secs_f64() >= opt.metrics_push_interval {
+                last_metrics_push = std::time::Instant::now();
+                pusher.push(&metrics::Metrics {
+                    triggers_written: trigger_log.n_written,
+                    clock_model_ready: clock_model.is_ready(),
+                    smoothed_rtt_seconds: smoothed_rtt,
+                    clock_drift_seconds: clock_model.latest_error_seconds(),
+                    missed_pongs,
+                });
+            }
+        }
+
+        if let Some(interval) = opt.log_interval_stats {
+            if last_interval_stats_log.elapsed().as_secs_f64() >= interval {
+                last_interval_stats_log = std::time::Instant::now();
+                if let Some(summary) = trigger_log.interval_stats.summary() {
+                    tracing::info!("{summary}");
+                }
+            }
+        }
+
+        if let Some(interval) = opt.record_status_interval {
+            if status_supported && last_status_request.elapsed().as_secs_f64() >= interval {
+                last_status_request = std::time::Instant::now();
+                if let Err(e) = device_tx.send(ToDevice::StatusRequest).await {
+                    tracing::error!("Writing to device: {e}");
+                    return Ok(());
                 }
             }
         }
 
         if !did_receive_version_response
-            && version_request_sent.elapsed() > std::time::Duration::from_secs(5)
+            && version_request_sent.elapsed()
+                > std::time::Duration::from_millis(opt.reply_timeout_ms)
         {
-            anyhow::bail!("No version response received.");
+            return Err(error::TriggerLoggerError::HandshakeTimeout {
+                bytes_received: received.get(),
+            }
+            .into());
+        }
+
+        if clock_model.is_ready() || opt.no_ping {
+            not_ready_since = None;
+        } else if not_ready_since
+            .get_or_insert_with(std::time::Instant::now)
+            .elapsed()
+            .as_secs_f64()
+            > opt.clock_warmup_timeout
+        {
+            return Err(error::TriggerLoggerError::ClockWarmupTimeout {
+                seconds: opt.clock_warmup_timeout,
+            }
+            .into());
         }
     }
 }
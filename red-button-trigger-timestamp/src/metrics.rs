@@ -0,0 +1,222 @@
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Value of the `job` label of pushed metrics.
+const JOB: &str = "red_button_trigger_timestamp";
+
+/// Longest time a push may take, from connecting to reading the response.
+const PUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// State of a session exported to Prometheus.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) triggers_written: u64,
+    pub(crate) clock_model_ready: bool,
+    pub(crate) smoothed_rtt_seconds: Option<f64>,
+    pub(crate) clock_drift_seconds: Option<f64>,
+    pub(crate) missed_pongs: u32,
+}
+
+impl Metrics {
+    /// The metrics in the Prometheus text exposition format. Unknown values
+    /// are left out.
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        let mut add = |name: &str, kind: &str, help: &str, value: Option<f64>| {
+            if let Some(value) = value {
+                text.push_str(&format!(
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+                ));
+            }
+        };
+        add(
+            "trigger_triggers_written_total",
+            "counter",
+            "Triggers written to the output",
+            Some(self.triggers_written as f64),
+        );
+        add(
+            "trigger_clock_model_ready",
+            "gauge",
+            "Whether the clock model can convert device ticks to UTC",
+            Some(if self.clock_model_ready { 1.0 } else { 0.0 }),
+        );
+        add(
+            "trigger_smoothed_rtt_seconds",
+            "gauge",
+            "Moving average of ping round trip times",
+            self.smoothed_rtt_seconds,
+        );
+        add(
+            "trigger_clock_drift_seconds",
+            "gauge",
+            "Clock model prediction minus host time of the latest sample",
+            self.clock_drift_seconds,
+        );
+        add(
+            "trigger_missed_pongs",
+            "gauge",
+            "Consecutive unanswered pings",
+            Some(self.missed_pongs.into()),
+        );
+        text
+    }
+}
+
+/// Pushes metrics to a Prometheus Pushgateway, under the labels
+/// `job="red_button_trigger_timestamp"` and `instance` as given, replacing
+/// the previous push. At most one push is in progress at a time, so a
+/// stalled Pushgateway delays metrics but does not accumulate connections.
+pub(crate) struct Pusher {
+    /// Host and port to connect to
+    address: String,
+    /// Host header, as given in the URL
+    host: String,
+    path: String,
+    timeout: std::time::Duration,
+    /// Whether a push is in progress
+    in_flight: Arc<AtomicBool>,
+}
+
+impl Pusher {
+    /// Push to the Pushgateway at `url`, e.g. `http://localhost:9091`. Only
+    /// plain HTTP is supported.
+    pub(crate) fn new(url: &str, instance: &str) -> anyhow::Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            anyhow::bail!("Pushgateway URL {url} does not start with http://");
+        };
+        let (host, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            anyhow::bail!("Pushgateway URL {url} has no host");
+        }
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:9091")
+        };
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: format!(
+                "{prefix}/metrics/job/{JOB}/instance/{}",
+                label_value(instance)
+            ),
+            timeout: PUSH_TIMEOUT,
+            in_flight: Default::default(),
+        })
+    }
+
+    /// Push `metrics` in the background, logging any failure. Skipped if the
+    /// previous push has not finished.
+    pub(crate) fn push(&self, metrics: &Metrics) {
+        if self.in_flight.swap(true, Ordering::AcqRel) {
+            tracing::warn!("Skipping metrics push as the previous one has not finished.");
+            return;
+        }
+        let request = self.request(&metrics.to_text());
+        let address = self.address.clone();
+        let timeout = self.timeout;
+        let in_flight = self.in_flight.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(timeout, send(&address, &request)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::warn!("Pushing metrics to {address}: {e:#}"),
+                Err(_) => tracing::warn!("Pushing metrics to {address}: timed out"),
+            }
+            in_flight.store(false, Ordering::Release);
+        });
+    }
+
+    fn request(&self, body: &str) -> String {
+        format!(
+            "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            body.len()
+        )
+    }
+}
+
+/// A label value which can be given in a Pushgateway URL path, e.g. for the
+/// device path `/dev/ttyACM0`, `_dev_ttyACM0`.
+fn label_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+async fn send(address: &str, request: &str) -> anyhow::Result<()> {
+    let mut stream = tokio::net::TcpStream::connect(address)
+        .await
+        .wrap_err("connecting")?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    if !status
+        .split(' ')
+        .nth(1)
+        .is_some_and(|code| code.starts_with('2'))
+    {
+        anyhow::bail!("unexpected response: {status}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_pushgateway_request() {
+    let pusher = Pusher::new("http://gateway/prefix/", "/dev/ttyACM0").unwrap();
+    assert_eq!(pusher.address, "gateway:9091");
+    let metrics = Metrics {
+        triggers_written: 3,
+        missed_pongs: 1,
+        ..Default::default()
+    };
+    let request = pusher.request(&metrics.to_text());
+    assert!(request.starts_with(
+        "PUT /prefix/metrics/job/red_button_trigger_timestamp/instance/_dev_ttyACM0 HTTP/1.1\r\n\
+         Host: gateway\r\n"
+    ));
+    assert!(request.contains("\ntrigger_triggers_written_total 3\n"));
+    assert!(request.contains("\ntrigger_clock_model_ready 0\n"));
+    // Unknown values are left out.
+    assert!(!request.contains("trigger_smoothed_rtt_seconds"));
+
+    assert_eq!(
+        Pusher::new("http://localhost:9092", "COM3")
+            .unwrap()
+            .address,
+        "localhost:9092"
+    );
+    assert!(Pusher::new("https://gateway", "COM3").is_err());
+}
+
+#[test]
+fn test_stalled_push_times_out() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        // A Pushgateway which accepts connections but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let mut pusher = Pusher::new(&url, "COM3").unwrap();
+        pusher.timeout = std::time::Duration::from_millis(100);
+
+        pusher.push(&Metrics::default());
+        assert!(pusher.in_flight.load(Ordering::Acquire));
+        // A second push while the first is stalled is skipped.
+        pusher.push(&Metrics::default());
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(!pusher.in_flight.load(Ordering::Acquire));
+        drop(listener);
+    });
+}
@@ -0,0 +1,78 @@
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::path::PathBuf;
+
+/// Default output directory, in the user's home directory on Unix and in
+/// their documents folder on Windows.
+#[cfg(not(windows))]
+pub(crate) const DEFAULT_OUTPUT_DIR: &str = "~/TRIGGER_DATA";
+#[cfg(windows)]
+pub(crate) const DEFAULT_OUTPUT_DIR: &str = r"~\Documents\TRIGGER_DATA";
+
+/// Expand `~` and environment variables in an output directory given on the
+/// command line. On Windows, `%NAME%` variables are expanded as well as
+/// `$NAME`.
+pub(crate) fn expand_output_dir(spec: &str) -> anyhow::Result<PathBuf> {
+    let spec = if cfg!(windows) {
+        expand_percent_vars(spec, |name| std::env::var(name).ok())
+    } else {
+        spec.to_string()
+    };
+    let expanded =
+        shellexpand::full(&spec).with_context(|| format!("expanding output directory {spec}"))?;
+    Ok(PathBuf::from(expanded.as_ref()))
+}
+
+/// Expand Windows style `%NAME%` variables using `lookup`. Unknown variables
+/// and unpaired `%` are left unchanged.
+fn expand_percent_vars(spec: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut rest = spec;
+    while let Some(start) = rest.find('%') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(len) = after.find('%') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &after[..len];
+        match lookup(name).filter(|_| !name.is_empty()) {
+            Some(value) => {
+                result.push_str(&value);
+                rest = &after[len + 1..];
+            }
+            None => {
+                // Keep the first `%`; the second may open a variable.
+                result.push('%');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[test]
+fn test_expand_percent_vars() {
+    let lookup = |name: &str| (name == "USERPROFILE").then(|| r"C:\Users\me".to_string());
+    assert_eq!(
+        expand_percent_vars(r"%USERPROFILE%\Documents\TRIGGER_DATA", lookup),
+        r"C:\Users\me\Documents\TRIGGER_DATA"
+    );
+    assert_eq!(expand_percent_vars("100%", lookup), "100%");
+    assert_eq!(expand_percent_vars("%NOPE%x", lookup), "%NOPE%x");
+    assert_eq!(
+        expand_percent_vars("5% of %USERPROFILE%", lookup),
+        r"5% of C:\Users\me"
+    );
+    assert_eq!(expand_percent_vars("%%", lookup), "%%");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_default_output_dir_is_in_home() {
+    let home = std::env::var("HOME").unwrap();
+    assert_eq!(
+        expand_output_dir(DEFAULT_OUTPUT_DIR).unwrap(),
+        PathBuf::from(home).join("TRIGGER_DATA")
+    );
+}
@@ -0,0 +1,247 @@
+use crate::{clock_model::ClockModel, device};
+use chrono::Utc;
+use color_eyre::eyre as anyhow;
+use futures::SinkExt;
+use rand::Rng;
+use red_button_trigger_timestamp_comms::{FromDevice, ToDevice, ECHO_LEN};
+
+/// Summary statistics of round trip times, in milliseconds.
+#[derive(Debug, PartialEq)]
+struct RttSummary {
+    min: f64,
+    median: f64,
+    p95: f64,
+    max: f64,
+    /// standard deviation
+    jitter: f64,
+}
+
+impl RttSummary {
+    fn new(mut rtts: Vec<f64>) -> Option<Self> {
+        if rtts.is_empty() {
+            return None;
+        }
+        rtts.sort_by(f64::total_cmp);
+        let n = rtts.len();
+        let percentile = |p: f64| rtts[((n - 1) as f64 * p).round() as usize];
+        let mean = rtts.iter().sum::<f64>() / n as f64;
+        let variance = rtts.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+        Some(Self {
+            min: rtts[0],
+            median: percentile(0.5),
+            p95: percentile(0.95),
+            max: rtts[n - 1],
+            jitter: variance.sqrt(),
+        })
+    }
+}
+
+/// Ping the device `count` times, one after another, and print statistics
+/// of the round trip times.
+pub(crate) async fn probe(
+    device_path: &str,
+    count: usize,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    let (mut framed, _info) = device::connect(device_path, options).await?;
+
+    let mut rtts = Vec::with_capacity(count);
+    for _ in 0..count {
+        framed.send(ToDevice::Ping).await?;
+        let t0 = std::time::Instant::now();
+        let device_processing =
+            device::wait_for_reply(&mut framed, options.reply_timeout, |msg| match msg {
+                FromDevice::Pong {
+                    ping_received,
+                    sent,
+                } => Some(sent.saturating_sub(ping_received)),
+                _ => None,
+            })
+            .await?;
+        let rtt = t0.elapsed().as_secs_f64() * 1000.0 - device_processing as f64 / 1000.0;
+        rtts.push(rtt);
+    }
+
+    let max_rtt = crate::clock_model::ClockModel::default()
+        .max_rtt()
+        .num_microseconds()
+        .unwrap() as f64
+        / 1000.0;
+    let n_over = rtts.iter().filter(|rtt| **rtt > max_rtt).count();
+    let Some(summary) = RttSummary::new(rtts) else {
+        anyhow::bail!("No pings were sent.");
+    };
+    println!("round trip times of {count} pings (msecs):");
+    println!("  min:    {:.3}", summary.min);
+    println!("  median: {:.3}", summary.median);
+    println!("  p95:    {:.3}", summary.p95);
+    println!("  max:    {:.3}", summary.max);
+    println!("  jitter: {:.3} (standard deviation)", summary.jitter);
+    println!("{n_over} of {count} exceed the clock model maximum of {max_rtt:.3} msecs.");
+    Ok(())
+}
+
+/// Payload of the `i`th echo: edge-case byte patterns first, then random.
+fn echo_payload(i: usize) -> [u8; ECHO_LEN] {
+    match i {
+        0 => [0; ECHO_LEN],
+        1 => [0xff; ECHO_LEN],
+        2 => [b'\n'; ECHO_LEN],
+        3 => [b'"'; ECHO_LEN],
+        _ => rand::thread_rng().gen(),
+    }
+}
+
+/// Send `count` echo requests and check that each payload comes back
+/// unchanged, reporting corrupted and lost replies.
+pub(crate) async fn echo(
+    device_path: &str,
+    count: usize,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &ToDevice::Echo([0; ECHO_LEN])) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+
+    let mut n_corrupted = 0;
+    let mut n_lost = 0;
+    for i in 0..count {
+        let payload = echo_payload(i);
+        framed.send(ToDevice::Echo(payload)).await?;
+        let reply = device::wait_for_reply(&mut framed, options.reply_timeout, |msg| match msg {
+            FromDevice::Echo(reply) => Some(reply),
+            _ => None,
+        })
+        .await;
+        match reply {
+            Ok(reply) if reply == payload => {}
+            Ok(reply) => {
+                tracing::warn!("Sent {payload:?} but received {reply:?}.");
+                n_corrupted += 1;
+            }
+            Err(e) => {
+                tracing::warn!("No echo of {payload:?}: {e}");
+                n_lost += 1;
+            }
+        }
+    }
+
+    println!("{count} echoes sent: {n_corrupted} corrupted, {n_lost} lost.");
+    if n_corrupted + n_lost > 0 {
+        anyhow::bail!("Echo test failed.");
+    }
+    Ok(())
+}
+
+/// Most pings sent to get a clock model ready.
+const MAX_WARMUP_PINGS: usize = 100;
+
+/// Ping the device until a clock model is ready, for converting device ticks
+/// to host time.
+pub(crate) async fn warm_up_clock_model(
+    framed: &mut device::DeviceFramed,
+    reply_timeout: std::time::Duration,
+) -> anyhow::Result<ClockModel> {
+    let mut clock_model = ClockModel::default();
+    for _ in 0..MAX_WARMUP_PINGS {
+        if clock_model.is_ready() {
+            return Ok(clock_model);
+        }
+        framed.send(ToDevice::Ping).await?;
+        // Timed once written, as in the logger.
+        let t0 = Utc::now();
+        let (ping_received, sent) =
+            device::wait_for_reply(framed, reply_timeout, |msg| match msg {
+                FromDevice::Pong {
+                    ping_received,
+                    sent,
+                } => Some((ping_received, sent)),
+                _ => None,
+            })
+            .await?;
+        clock_model.update(t0, Utc::now(), ping_received, sent);
+    }
+    if !clock_model.is_ready() {
+        anyhow::bail!("Clock model not ready after {MAX_WARMUP_PINGS} pings.");
+    }
+    Ok(clock_model)
+}
+
+/// Have the device emit `count` synthetic triggers every `period_us`
+/// microseconds and print statistics of their intervals, converted to host
+/// time by a clock model, and of their latency to the host.
+pub(crate) async fn test_pattern(
+    device_path: &str,
+    period_us: u32,
+    count: u32,
+    options: device::ConnectOptions,
+) -> anyhow::Result<()> {
+    let request = ToDevice::TestPattern { period_us, count };
+    let (mut framed, info) = device::connect(device_path, options).await?;
+    if let Some(name) = device::missing_capability(&info, &request) {
+        anyhow::bail!("The firmware does not support {name}.");
+    }
+
+    let clock_model = warm_up_clock_model(&mut framed, options.reply_timeout).await?;
+
+    framed.send(request).await?;
+    device::wait_for_reply(&mut framed, options.reply_timeout, |msg| {
+        matches!(msg, FromDevice::Ack).then_some(())
+    })
+    .await?;
+    let mut times = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let timestamp =
+            device::wait_for_reply(&mut framed, options.reply_timeout, |msg| match msg {
+                FromDevice::Trigger {
+                    timestamp,
+                    test: true,
+                    ..
+                } => Some(timestamp),
+                _ => None,
+            })
+            .await?;
+        let recv_time = Utc::now();
+        let utc = clock_model.compute_utc(timestamp).unwrap();
+        times.push((utc, recv_time));
+    }
+
+    let period_ms = f64::from(period_us) / 1000.0;
+    let interval_errors = times
+        .windows(2)
+        .map(|w| (w[1].0 - w[0].0).num_nanoseconds().unwrap() as f64 / 1e6 - period_ms)
+        .collect();
+    let latencies = times
+        .iter()
+        .map(|(utc, recv_time)| (*recv_time - *utc).num_nanoseconds().unwrap() as f64 / 1e6)
+        .collect();
+    let Some(interval_errors) = RttSummary::new(interval_errors) else {
+        anyhow::bail!("At least two triggers are needed to measure intervals.");
+    };
+    let latencies = RttSummary::new(latencies).unwrap();
+    println!("{count} test triggers every {period_ms:.3} msecs.");
+    for (title, summary) in [
+        ("interval minus period", interval_errors),
+        ("latency to host", latencies),
+    ] {
+        println!("{title} (msecs):");
+        println!("  min:    {:.6}", summary.min);
+        println!("  median: {:.6}", summary.median);
+        println!("  max:    {:.6}", summary.max);
+        println!("  jitter: {:.6} (standard deviation)", summary.jitter);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rtt_summary() {
+    let rtts = (1..=100).rev().map(|x| x as f64).collect();
+    let summary = RttSummary::new(rtts).unwrap();
+    assert_eq!(summary.min, 1.0);
+    assert_eq!(summary.median, 51.0);
+    assert_eq!(summary.p95, 95.0);
+    assert_eq!(summary.max, 100.0);
+    assert!((summary.jitter - 28.866).abs() < 1e-3);
+    assert_eq!(RttSummary::new(vec![]), None);
+}
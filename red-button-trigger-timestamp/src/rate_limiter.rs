@@ -0,0 +1,67 @@
+use std::time::Instant;
+
+/// Token bucket which limits the rate at which triggers are accepted.
+pub(crate) struct RateLimiter {
+    /// tokens added per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+    n_dropped: u64,
+    n_dropped_unreported: u64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_per_second: f64, now: Instant) -> Self {
+        let capacity = max_per_second.max(1.0);
+        Self {
+            rate: max_per_second,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+            n_dropped: 0,
+            n_dropped_unreported: 0,
+        }
+    }
+
+    /// Return whether an event at `now` is within the rate limit.
+    pub(crate) fn check(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.n_dropped += 1;
+            self.n_dropped_unreported += 1;
+            false
+        }
+    }
+
+    /// Total number of events dropped.
+    pub(crate) fn n_dropped(&self) -> u64 {
+        self.n_dropped
+    }
+
+    /// Number of events dropped since the last call to this function.
+    pub(crate) fn take_unreported(&mut self) -> u64 {
+        std::mem::take(&mut self.n_dropped_unreported)
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    use std::time::Duration;
+
+    let t0 = Instant::now();
+    let mut limiter = RateLimiter::new(2.0, t0);
+    assert!(limiter.check(t0));
+    assert!(limiter.check(t0));
+    assert!(!limiter.check(t0));
+    assert!(!limiter.check(t0 + Duration::from_millis(100)));
+    assert!(limiter.check(t0 + Duration::from_millis(600)));
+    assert_eq!(limiter.n_dropped(), 2);
+    assert_eq!(limiter.take_unreported(), 2);
+    assert_eq!(limiter.take_unreported(), 0);
+}
@@ -0,0 +1,698 @@
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, io::Write, path::PathBuf};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TriggerRow {
+    /// Local time, keeping the UTC offset in effect when it was recorded.
+    pub(crate) timestamp_local: chrono::DateTime<chrono::FixedOffset>,
+    pub(crate) epoch_nanos_utc: i64,
+    /// Seconds since the previous trigger, empty for the first trigger.
+    pub(crate) interval_seconds: Option<f64>,
+    /// Seconds between press and release, with `--pair-edges`.
+    pub(crate) hold_seconds: Option<f64>,
+    /// Device tick of the trigger, empty for annotations.
+    pub(crate) device_timestamp: Option<u64>,
+    /// Host time at which the trigger message was received, empty for
+    /// annotations. The difference from `epoch_nanos_utc` is the transport
+    /// latency plus any clock model error.
+    pub(crate) received_epoch_nanos_utc: Option<i64>,
+    /// Clock model prediction minus the measured host time, in seconds, at
+    /// the most recent clock measurement before the trigger. This is the error
+    /// of the model at that moment, which bounds or corrects the trigger's
+    /// time. Empty for annotations and when the model is not fitted.
+    pub(crate) clock_drift_seconds: Option<f64>,
+    /// ADC reading for triggers from the analog input.
+    pub(crate) adc_level: Option<u16>,
+    /// With `--differential`, the trigger input which fired first, empty if
+    /// both fired within the resolution of the device clock.
+    pub(crate) first_channel: Option<u8>,
+    /// With `--differential`, seconds from the first input firing to the
+    /// other, empty if the other did not fire in time.
+    pub(crate) channel_delta_seconds: Option<f64>,
+    /// With `--record-host-delay`, seconds from reading the trigger message
+    /// from the serial port to processing it. This is near zero on a host
+    /// with time to spare. Larger values mean the host was too busy to process
+    /// messages promptly, which also delays the pongs the clock model is
+    /// fitted to, degrading its accuracy. Time spent waiting in the operating
+    /// system's buffer before the read is not included.
+    pub(crate) host_delay_seconds: Option<f64>,
+    /// Non-empty for rows marking an event other than a trigger.
+    pub(crate) annotation: Option<String>,
+}
+
+/// Column names of [TriggerRow], in order.
+pub(crate) const TRIGGER_ROW_COLUMNS: &[&str] = &[
+    "timestamp_local",
+    "epoch_nanos_utc",
+    "interval_seconds",
+    "hold_seconds",
+    "device_timestamp",
+    "received_epoch_nanos_utc",
+    "clock_drift_seconds",
+    "adc_level",
+    "first_channel",
+    "channel_delta_seconds",
+    "host_delay_seconds",
+    "annotation",
+];
+
+/// Parse a `name=value` extra column specification.
+pub(crate) fn parse_extra_column(spec: &str) -> Result<(String, String), String> {
+    let (name, value) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=value, got \"{spec}\""))?;
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!(
+            "column name \"{name}\" must be non-empty and contain only ASCII letters, digits, '_' or '-'"
+        ));
+    }
+    if TRIGGER_ROW_COLUMNS.contains(&name) {
+        return Err(format!("column name \"{name}\" is reserved"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Number of triggers missing between sequence numbers `prev` and `next`, or
+/// `None` if the sequence restarted (e.g. because the device reset).
+fn n_missing(prev: u32, next: u32) -> Option<u32> {
+    if next > prev {
+        Some(next - prev - 1)
+    } else {
+        None
+    }
+}
+
+/// Convert to nanoseconds since the UNIX epoch, or `None` if out of range.
+pub(crate) fn to_epoch_nanos(utc: chrono::DateTime<chrono::Utc>) -> Option<i64> {
+    (utc - chrono::DateTime::UNIX_EPOCH).num_nanoseconds()
+}
+
+/// Details of a trigger which depend on its kind, recorded in the columns of
+/// the same names.
+#[derive(Default)]
+pub(crate) struct TriggerDetails {
+    pub(crate) hold_seconds: Option<f64>,
+    pub(crate) adc_level: Option<u16>,
+    pub(crate) first_channel: Option<u8>,
+    pub(crate) channel_delta_seconds: Option<f64>,
+    pub(crate) host_delay_seconds: Option<f64>,
+}
+
+/// Destination for rows of a [TriggerLog].
+pub(crate) trait TriggerSink {
+    fn write(&mut self, row: &TriggerRow) -> anyhow::Result<()>;
+    fn flush(&mut self) -> anyhow::Result<()>;
+}
+
+/// Writes rows as CSV, e.g. to a file or stdout.
+pub(crate) struct CsvSink {
+    csv_wtr: csv::Writer<Box<dyn std::io::Write>>,
+    /// Values of constant columns appended to every row
+    extra_values: Vec<String>,
+}
+
+impl CsvSink {
+    /// Create the sink, writing the header. Each of `extra_columns` is a
+    /// `(name, value)` pair added as a constant column to every row.
+    pub(crate) fn new(
+        output: Box<dyn std::io::Write>,
+        extra_columns: &[(String, String)],
+    ) -> anyhow::Result<Self> {
+        let header = csv_header(extra_columns)?;
+        // The header is written here rather than by `serialize` because the
+        // extra columns are not part of `TriggerRow`.
+        let mut csv_wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(output);
+        csv_wtr.write_record(&header)?;
+        csv_wtr.flush()?;
+        Ok(Self {
+            csv_wtr,
+            extra_values: extra_columns.iter().map(|(_, v)| v.clone()).collect(),
+        })
+    }
+}
+
+/// Compress what is written to `output` with gzip. Each flush, made after
+/// every row, ends a deflate block at a byte boundary, so that a file cut
+/// short, e.g. by a power failure, still decompresses up to the last row
+/// flushed. At high trigger rates this costs some compression, as blocks of
+/// a few rows compress less well. The gzip trailer is written when the
+/// writer is dropped.
+#[cfg(feature = "compress")]
+pub(crate) fn gzip(output: impl std::io::Write + 'static) -> Box<dyn std::io::Write> {
+    Box::new(flate2::write::GzEncoder::new(
+        output,
+        flate2::Compression::default(),
+    ))
+}
+
+/// Column names of the CSV output with `extra_columns` added.
+fn csv_header(extra_columns: &[(String, String)]) -> anyhow::Result<Vec<&str>> {
+    let mut header: Vec<&str> = TRIGGER_ROW_COLUMNS.to_vec();
+    for (name, _) in extra_columns {
+        if header.contains(&name.as_str()) {
+            anyhow::bail!("duplicate column name \"{name}\"");
+        }
+        header.push(name);
+    }
+    Ok(header)
+}
+
+/// Keeps only the most recent rows in a CSV file, to bound its size when
+/// recording indefinitely. Each flush writes the whole window to a temporary
+/// file which then replaces the output, so the file always holds a complete
+/// window including the latest row, at a cost proportional to the capacity.
+pub(crate) struct RingCsvSink {
+    path: PathBuf,
+    capacity: usize,
+    /// Encoded header line
+    header: Vec<u8>,
+    /// Encoded rows, oldest first
+    rows: VecDeque<Vec<u8>>,
+    /// Values of constant columns appended to every row
+    extra_values: Vec<String>,
+}
+
+impl RingCsvSink {
+    /// Create the sink, writing the header to `path`. Rows beyond the most
+    /// recent `capacity` are dropped.
+    pub(crate) fn new(
+        path: PathBuf,
+        capacity: usize,
+        extra_columns: &[(String, String)],
+    ) -> anyhow::Result<Self> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        wtr.write_record(csv_header(extra_columns)?)?;
+        let mut sink = Self {
+            path,
+            capacity,
+            header: wtr.into_inner()?,
+            rows: VecDeque::with_capacity(capacity + 1),
+            extra_values: extra_columns.iter().map(|(_, v)| v.clone()).collect(),
+        };
+        sink.flush()?;
+        Ok(sink)
+    }
+}
+
+impl TriggerSink for RingCsvSink {
+    fn write(&mut self, row: &TriggerRow) -> anyhow::Result<()> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        wtr.serialize((row, &self.extra_values))?;
+        self.rows.push_back(wtr.into_inner()?);
+        while self.rows.len() > self.capacity {
+            self.rows.pop_front();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let tmp_path = self.path.with_extension("csv.writing");
+        let mut file = std::fs::File::create(&tmp_path)
+            .with_context(|| format!("creating file {}", tmp_path.display()))?;
+        file.write_all(&self.header)?;
+        for row in &self.rows {
+            file.write_all(row)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("replacing file {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+impl TriggerSink for CsvSink {
+    fn write(&mut self, row: &TriggerRow) -> anyhow::Result<()> {
+        self.csv_wtr.serialize((row, &self.extra_values))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.csv_wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Output of triggers to one or more sinks, kept across reconnections to the
+/// device.
+pub(crate) struct TriggerLog {
+    sinks: Vec<Box<dyn TriggerSink>>,
+    /// Number of consecutive failed writes to each sink
+    consecutive_failures: Vec<u32>,
+    /// Consecutive failed writes to a sink which are logged and tolerated.
+    /// One more is fatal.
+    max_consecutive_failures: u32,
+    prev_epoch_nanos_utc: Option<i64>,
+    /// Latest trigger time written, to detect rows out of order
+    max_epoch_nanos_utc: Option<i64>,
+    /// Sequence number of the most recent trigger from the device
+    prev_sequence: Option<u32>,
+    pub(crate) n_written: u64,
+    /// Lines from the device which were not messages, over all sessions
+    pub(crate) n_discarded_lines: u64,
+    /// Bytes lost to overruns of the host's serial buffer, where reported
+    pub(crate) n_overrun_bytes: u64,
+    /// Statistics of `interval_seconds` of the triggers written
+    pub(crate) interval_stats: crate::interval_stats::IntervalStats,
+    /// Whether to skip logging each trigger, e.g. at high trigger rates
+    pub(crate) quiet: bool,
+    /// Whether each trigger is logged in UTC rather than local time
+    pub(crate) display_utc: bool,
+}
+
+impl TriggerLog {
+    pub(crate) fn new(sinks: Vec<Box<dyn TriggerSink>>, max_consecutive_failures: u32) -> Self {
+        Self {
+            consecutive_failures: vec![0; sinks.len()],
+            sinks,
+            max_consecutive_failures,
+            prev_epoch_nanos_utc: None,
+            max_epoch_nanos_utc: None,
+            prev_sequence: None,
+            n_written: 0,
+            n_discarded_lines: 0,
+            n_overrun_bytes: 0,
+            interval_stats: Default::default(),
+            quiet: false,
+            display_utc: false,
+        }
+    }
+
+    /// Write and flush `row` to every sink. A failing sink does not prevent
+    /// writing to the others, and is given up on, with an error, only after
+    /// more than `max_consecutive_failures` failures in a row.
+    fn write_row(&mut self, row: &TriggerRow) -> anyhow::Result<()> {
+        for (sink, n_failures) in self
+            .sinks
+            .iter_mut()
+            .zip(self.consecutive_failures.iter_mut())
+        {
+            match sink.write(row).and_then(|()| sink.flush()) {
+                Ok(()) => *n_failures = 0,
+                Err(e) => {
+                    *n_failures += 1;
+                    if *n_failures > self.max_consecutive_failures {
+                        return Err(e.wrap_err(format!(
+                            "writing trigger failed {} consecutive times",
+                            n_failures
+                        )));
+                    }
+                    tracing::error!("Writing trigger (failure {}): {e}", n_failures);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a trigger which occurred at `trigger_utc`, as computed from
+    /// `device_timestamp`, and whose message was received at `received_utc`.
+    /// `clock_drift_seconds` is the clock model's latest error.
+    pub(crate) fn write(
+        &mut self,
+        trigger_utc: chrono::DateTime<chrono::Utc>,
+        device_timestamp: u64,
+        received_utc: chrono::DateTime<chrono::Utc>,
+        clock_drift_seconds: Option<f64>,
+        details: TriggerDetails,
+    ) -> anyhow::Result<()> {
+        let timestamp_local: chrono::DateTime<chrono::Local> =
+            trigger_utc.with_timezone(&chrono::Local);
+        if !self.quiet {
+            if self.display_utc {
+                tracing::info!("trigger: {}", trigger_utc);
+            } else {
+                tracing::info!("trigger: {}", timestamp_local);
+            }
+        }
+        let Some(epoch_nanos_utc) = to_epoch_nanos(trigger_utc) else {
+            tracing::error!(
+                "Trigger time {} cannot be represented as i64 nanoseconds. Skipping.",
+                trigger_utc
+            );
+            return Ok(());
+        };
+        // Computed from UTC so that DST transitions do not affect it.
+        let interval_seconds = self
+            .prev_epoch_nanos_utc
+            .map(|prev| (epoch_nanos_utc - prev) as f64 / 1e9);
+        self.prev_epoch_nanos_utc = Some(epoch_nanos_utc);
+        if let Some(max) = self.max_epoch_nanos_utc {
+            if epoch_nanos_utc < max {
+                tracing::warn!(
+                    "Trigger at {} is {} ns earlier than a previous trigger. Rows are out of order.",
+                    timestamp_local,
+                    max - epoch_nanos_utc
+                );
+            }
+        }
+        self.max_epoch_nanos_utc = self.max_epoch_nanos_utc.max(Some(epoch_nanos_utc));
+        let trig_row = TriggerRow {
+            timestamp_local: timestamp_local.fixed_offset(),
+            epoch_nanos_utc,
+            interval_seconds,
+            hold_seconds: details.hold_seconds,
+            device_timestamp: Some(device_timestamp),
+            received_epoch_nanos_utc: to_epoch_nanos(received_utc),
+            clock_drift_seconds,
+            adc_level: details.adc_level,
+            first_channel: details.first_channel,
+            channel_delta_seconds: details.channel_delta_seconds,
+            host_delay_seconds: details.host_delay_seconds,
+            annotation: None,
+        };
+        self.write_row(&trig_row)?;
+        self.n_written += 1;
+        if let Some(interval_seconds) = interval_seconds {
+            self.interval_stats.add(interval_seconds);
+        }
+        Ok(())
+    }
+
+    /// Check the device's trigger sequence number for gaps, which indicate
+    /// lost trigger messages, and record any gap at host time `utc`. Returns
+    /// the number of missing triggers.
+    pub(crate) fn check_sequence(
+        &mut self,
+        sequence: u32,
+        utc: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u32> {
+        let mut n_missing_total = 0;
+        if let Some(prev) = self.prev_sequence {
+            match n_missing(prev, sequence) {
+                Some(0) => {}
+                Some(n) => {
+                    tracing::warn!(
+                        "{} trigger(s) missing between sequence numbers {} and {}.",
+                        n,
+                        prev,
+                        sequence
+                    );
+                    self.write_annotation(utc, &format!("{n} triggers missing"))?;
+                    n_missing_total = n;
+                }
+                None => {
+                    tracing::info!(
+                        "Trigger sequence restarted at {} (previous {}).",
+                        sequence,
+                        prev
+                    );
+                }
+            }
+        }
+        self.prev_sequence = Some(sequence);
+        Ok(n_missing_total)
+    }
+
+    /// Write a row marking an event, such as a discontinuity, at host time
+    /// `utc`. The row does not count as a trigger.
+    pub(crate) fn write_annotation(
+        &mut self,
+        utc: chrono::DateTime<chrono::Utc>,
+        annotation: &str,
+    ) -> anyhow::Result<()> {
+        let Some(epoch_nanos_utc) = to_epoch_nanos(utc) else {
+            anyhow::bail!("time {utc} cannot be represented as i64 nanoseconds");
+        };
+        let row = TriggerRow {
+            timestamp_local: utc.with_timezone(&chrono::Local).fixed_offset(),
+            epoch_nanos_utc,
+            interval_seconds: None,
+            hold_seconds: None,
+            device_timestamp: None,
+            received_epoch_nanos_utc: None,
+            clock_drift_seconds: None,
+            adc_level: None,
+            first_channel: None,
+            channel_delta_seconds: None,
+            host_delay_seconds: None,
+            annotation: Some(annotation.to_string()),
+        };
+        self.write_row(&row)?;
+        Ok(())
+    }
+}
+
+/// Copy CSV rows from `rdr` to `wtr`, stably sorted by `epoch_nanos_utc`.
+/// `interval_seconds` is left as recorded.
+fn sort_csv<R: std::io::Read, W: std::io::Write>(rdr: R, wtr: W) -> anyhow::Result<()> {
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(rdr);
+    let header = rdr.headers()?.clone();
+    let col = header
+        .iter()
+        .position(|name| name == "epoch_nanos_utc")
+        .ok_or_else(|| anyhow::anyhow!("no epoch_nanos_utc column"))?;
+    let mut records = rdr.records().collect::<Result<Vec<_>, _>>()?;
+    // Unparseable times sort last.
+    records.sort_by_key(|record| {
+        record
+            .get(col)
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(i64::MAX)
+    });
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(wtr);
+    wtr.write_record(&header)?;
+    for record in records {
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Sort the rows of the CSV file at `path` by `epoch_nanos_utc`, replacing
+/// it only once the sorted copy is complete.
+pub(crate) fn sort_csv_file(path: &std::path::Path) -> anyhow::Result<()> {
+    let sorted_path = path.with_extension("csv.sorting");
+    let input =
+        std::fs::File::open(path).with_context(|| format!("opening file {}", path.display()))?;
+    let output = std::fs::File::create(&sorted_path)
+        .with_context(|| format!("creating file {}", sorted_path.display()))?;
+    sort_csv(input, output)?;
+    std::fs::rename(&sorted_path, path)
+        .with_context(|| format!("replacing file {}", path.display()))?;
+    tracing::info!("Sorted {}", path.display());
+    Ok(())
+}
+
+#[test]
+fn test_sort_csv() {
+    let input = "\
+timestamp_local,epoch_nanos_utc,interval_seconds,hold_seconds,device_timestamp,received_epoch_nanos_utc,clock_drift_seconds,adc_level,first_channel,channel_delta_seconds,host_delay_seconds,annotation,site
+a,3000,,,,,,,,,,,x
+b,1000,,,,,,,,,,,x
+c,2000,,,,,,,,,,note,x
+d,1000,,,,,,,,,,,y
+";
+    let mut output = Vec::new();
+    sort_csv(input.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let order: Vec<&str> = output.lines().skip(1).map(|line| &line[..1]).collect();
+    assert_eq!(order, ["b", "d", "c", "a"]);
+}
+
+#[test]
+fn test_trigger_row_columns() {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.serialize(TriggerRow {
+        timestamp_local: chrono::DateTime::UNIX_EPOCH.fixed_offset(),
+        epoch_nanos_utc: 0,
+        interval_seconds: None,
+        hold_seconds: None,
+        device_timestamp: None,
+        received_epoch_nanos_utc: None,
+        clock_drift_seconds: None,
+        adc_level: None,
+        first_channel: None,
+        channel_delta_seconds: None,
+        host_delay_seconds: None,
+        annotation: None,
+    })
+    .unwrap();
+    let buf = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        buf.lines().next(),
+        Some(TRIGGER_ROW_COLUMNS.join(",").as_str())
+    );
+    assert!(parse_extra_column("epoch_nanos_utc=1").is_err());
+    assert!(parse_extra_column("bad name=1").is_err());
+    assert_eq!(
+        parse_extra_column("subject=fly 1"),
+        Ok(("subject".to_string(), "fly 1".to_string()))
+    );
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn test_gzip_round_trip() {
+    use std::io::Read;
+
+    let path = std::env::temp_dir().join(format!("triggers-{}.csv.gz", std::process::id()));
+    let fd = std::fs::File::create(&path).unwrap();
+    let extra = [("site".to_string(), "lab".to_string())];
+    let sink = CsvSink::new(gzip(fd), &extra).unwrap();
+    let mut log = TriggerLog::new(vec![Box::new(sink)], 0);
+    log.quiet = true;
+    let t = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    for i in 0..3 {
+        let utc = t + chrono::TimeDelta::seconds(i);
+        log.write(utc, i as u64, utc, None, Default::default())
+            .unwrap();
+    }
+
+    // Each row is flushed, so the rows so far decompress before the file is
+    // complete, which the decoder reports at its end.
+    let read = |path: &std::path::Path| {
+        let mut text = String::new();
+        let result = flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap())
+            .read_to_string(&mut text);
+        (text, result.is_ok())
+    };
+    let (partial, complete) = read(&path);
+    assert!(!complete);
+    assert_eq!(partial.lines().count(), 4);
+
+    drop(log);
+    let (text, complete) = read(&path);
+    assert!(complete);
+    let mut rdr = csv::Reader::from_reader(text.as_bytes());
+    assert_eq!(
+        rdr.headers().unwrap().get(TRIGGER_ROW_COLUMNS.len()),
+        Some("site")
+    );
+    let rows: Vec<TriggerRow> = rdr.deserialize().map(Result::unwrap).collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[2].device_timestamp, Some(2));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_n_missing() {
+    assert_eq!(n_missing(1, 2), Some(0));
+    assert_eq!(n_missing(1, 5), Some(3));
+    assert_eq!(n_missing(7, 1), None);
+    assert_eq!(n_missing(3, 3), None);
+}
+
+#[test]
+fn test_check_sequence() {
+    let mut log = TriggerLog::new(vec![], 0);
+    let now = chrono::Utc::now();
+    assert_eq!(log.check_sequence(1, now).unwrap(), 0);
+    assert_eq!(log.check_sequence(2, now).unwrap(), 0);
+    assert_eq!(log.check_sequence(5, now).unwrap(), 2);
+    // A restart is not a gap.
+    assert_eq!(log.check_sequence(1, now).unwrap(), 0);
+}
+
+#[test]
+fn test_multiple_sinks() {
+    use std::{cell::RefCell, rc::Rc};
+
+    struct MemorySink(Rc<RefCell<Vec<i64>>>);
+    impl TriggerSink for MemorySink {
+        fn write(&mut self, row: &TriggerRow) -> anyhow::Result<()> {
+            self.0.borrow_mut().push(row.epoch_nanos_utc);
+            Ok(())
+        }
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let a = Rc::new(RefCell::new(Vec::new()));
+    let b = Rc::new(RefCell::new(Vec::new()));
+    let mut log = TriggerLog::new(
+        vec![
+            Box::new(MemorySink(a.clone())),
+            Box::new(MemorySink(b.clone())),
+        ],
+        0,
+    );
+    let t = chrono::DateTime::UNIX_EPOCH + chrono::TimeDelta::seconds(1);
+    log.write(t, 0, t, None, Default::default()).unwrap();
+    log.write_annotation(t, "note").unwrap();
+    assert_eq!(*a.borrow(), [1_000_000_000, 1_000_000_000]);
+    assert_eq!(*a.borrow(), *b.borrow());
+    assert_eq!(log.n_written, 1);
+}
+
+#[test]
+fn test_ring_capacity_is_enforced() {
+    let path = std::env::temp_dir().join(format!("ring_test_{}.csv", std::process::id()));
+    let sink = RingCsvSink::new(path.clone(), 3, &[("site".into(), "lab".into())]).unwrap();
+    let mut log = TriggerLog::new(vec![Box::new(sink)], 0);
+    for i in 1..=5 {
+        let t = chrono::DateTime::UNIX_EPOCH + chrono::TimeDelta::seconds(i);
+        log.write(t, 0, t, None, Default::default()).unwrap();
+    }
+
+    let mut rdr = csv::Reader::from_path(&path).unwrap();
+    assert_eq!(rdr.headers().unwrap().iter().next_back(), Some("site"));
+    let nanos: Vec<i64> = rdr
+        .records()
+        .map(|record| record.unwrap()[1].parse().unwrap())
+        .collect();
+    assert_eq!(nanos, [3_000_000_000, 4_000_000_000, 5_000_000_000]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_write_failures_are_tolerated_up_to_limit() {
+    use std::{cell::Cell, rc::Rc};
+
+    /// Fails every write while the flag is set.
+    struct FailingWriter(Rc<Cell<bool>>);
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.0.get() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let failing = Rc::new(Cell::new(false));
+    let sink = CsvSink::new(Box::new(FailingWriter(failing.clone())), &[]).unwrap();
+    let mut log = TriggerLog::new(vec![Box::new(sink)], 2);
+    let t = chrono::Utc::now();
+    failing.set(true);
+    assert!(log.write_annotation(t, "a").is_ok());
+    assert!(log.write_annotation(t, "b").is_ok());
+    // A success resets the count.
+    failing.set(false);
+    assert!(log.write_annotation(t, "c").is_ok());
+    failing.set(true);
+    assert!(log.write_annotation(t, "d").is_ok());
+    assert!(log.write_annotation(t, "e").is_ok());
+    assert!(log.write_annotation(t, "f").is_err());
+}
+
+#[test]
+fn test_year_3000_trigger_is_not_representable() {
+    use chrono::Datelike;
+
+    let mut model = crate::clock_model::ClockModel::default();
+    let start = chrono::Utc::now();
+    // One device tick per microsecond, as for the RP2040 timer.
+    for i in 0..10 {
+        let t = start + chrono::TimeDelta::seconds(i);
+        model.update(t, t, i as u64 * 1_000_000, i as u64 * 1_000_000);
+    }
+    let ticks_per_1000_years = 1000 * 365 * 24 * 60 * 60 * 1_000_000;
+    let utc = model.compute_utc(ticks_per_1000_years).unwrap();
+    assert!(utc.year() > 2999);
+    assert_eq!(to_epoch_nanos(utc), None);
+    assert!(to_epoch_nanos(start).is_some());
+}
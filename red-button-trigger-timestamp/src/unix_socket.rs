@@ -0,0 +1,124 @@
+use crate::trigger_log::{TriggerRow, TriggerSink};
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::{
+    io::Write,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+};
+
+/// Streams rows as JSON lines to each process connected to a Unix domain
+/// socket. Connections are accepted as rows are written, so a consumer
+/// receives the rows written after it connects. A consumer which disconnects,
+/// or does not read fast enough for a write to complete without blocking, is
+/// dropped without affecting the others.
+pub(crate) struct UnixSocketSink {
+    path: PathBuf,
+    listener: UnixListener,
+    consumers: Vec<UnixStream>,
+}
+
+impl UnixSocketSink {
+    /// Listen at `path`, replacing a socket left there by a previous run.
+    pub(crate) fn bind(path: PathBuf) -> anyhow::Result<Self> {
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("removing stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("listening on socket {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            path,
+            listener,
+            consumers: Vec::new(),
+        })
+    }
+
+    fn accept_pending(&mut self) -> anyhow::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    tracing::info!("Consumer connected to {}.", self.path.display());
+                    self.consumers.push(stream);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl TriggerSink for UnixSocketSink {
+    fn write(&mut self, row: &TriggerRow) -> anyhow::Result<()> {
+        self.accept_pending()?;
+        let mut line = serde_json::to_vec(row)?;
+        line.push(b'\n');
+        let path = &self.path;
+        self.consumers
+            .retain_mut(|consumer| match consumer.write_all(&line) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::info!("Dropping consumer of {}: {e}", path.display());
+                    false
+                }
+            });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for UnixSocketSink {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_unix_socket_sink() {
+    use std::io::BufRead;
+
+    let path = std::env::temp_dir().join(format!("trigger-socket-{}", std::process::id()));
+    let mut sink = UnixSocketSink::bind(path.clone()).unwrap();
+    let row = |epoch_nanos_utc| TriggerRow {
+        timestamp_local: chrono::DateTime::from_timestamp_nanos(epoch_nanos_utc).fixed_offset(),
+        epoch_nanos_utc,
+        interval_seconds: None,
+        hold_seconds: None,
+        device_timestamp: Some(1),
+        received_epoch_nanos_utc: None,
+        clock_drift_seconds: None,
+        adc_level: None,
+        first_channel: None,
+        channel_delta_seconds: None,
+        host_delay_seconds: None,
+        annotation: None,
+    };
+
+    let a = UnixStream::connect(&path).unwrap();
+    let b = UnixStream::connect(&path).unwrap();
+    sink.write(&row(1)).unwrap();
+    assert_eq!(sink.consumers.len(), 2);
+
+    // A consumer which disconnects is dropped, and the other still receives.
+    drop(b);
+    sink.write(&row(2)).unwrap();
+    sink.write(&row(3)).unwrap();
+    assert_eq!(sink.consumers.len(), 1);
+
+    let mut lines = std::io::BufReader::new(a).lines();
+    for expected in 1..=3 {
+        let line = lines.next().unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["epoch_nanos_utc"], expected);
+    }
+
+    drop(sink);
+    assert!(!path.exists());
+}
@@ -0,0 +1,136 @@
+use crate::trigger_log::{TriggerRow, TRIGGER_ROW_COLUMNS};
+use color_eyre::eyre::{self as anyhow, WrapErr};
+use std::path::Path;
+
+/// Check a recorded trigger CSV file, printing each anomaly found, and fail
+/// if there are any.
+pub(crate) fn validate_file(path: &Path) -> anyhow::Result<()> {
+    let fd =
+        std::fs::File::open(path).with_context(|| format!("opening file {}", path.display()))?;
+    let report = check_csv(fd)?;
+    for anomaly in &report.anomalies {
+        println!("{}: {anomaly}", path.display());
+    }
+    println!(
+        "{}: {} rows, {} triggers, {} anomalies",
+        path.display(),
+        report.n_rows,
+        report.n_triggers,
+        report.anomalies.len()
+    );
+    if !report.anomalies.is_empty() {
+        anyhow::bail!(
+            "{} failed validation with {} anomalies",
+            path.display(),
+            report.anomalies.len()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    n_rows: usize,
+    n_triggers: usize,
+    anomalies: Vec<String>,
+}
+
+/// Check that the header starts with the columns of [TriggerRow], with any
+/// extra columns after, and that each row parses, has a `timestamp_local`
+/// at the same instant as its `epoch_nanos_utc`, and is not an annotation of
+/// missing triggers. `epoch_nanos_utc` must not decrease from one trigger to
+/// the next. Annotations are left out of this check because they are
+/// timestamped when the host noticed the event, which can be after a trigger
+/// recorded later.
+fn check_csv<R: std::io::Read>(rdr: R) -> anyhow::Result<Report> {
+    let mut rdr = csv::Reader::from_reader(rdr);
+    let mut report = Report::default();
+    let header = rdr.headers()?;
+    if header.len() < TRIGGER_ROW_COLUMNS.len()
+        || header.iter().zip(TRIGGER_ROW_COLUMNS).any(|(a, b)| a != *b)
+    {
+        report.anomalies.push(format!(
+            "header does not start with the trigger columns {}",
+            TRIGGER_ROW_COLUMNS.join(",")
+        ));
+        return Ok(report);
+    }
+    let mut prev_trigger: Option<i64> = None;
+    for (i, result) in rdr.deserialize::<TriggerRow>().enumerate() {
+        // Line 1 is the header.
+        let line = i + 2;
+        report.n_rows += 1;
+        let row = match result {
+            Ok(row) => row,
+            Err(e) => {
+                report
+                    .anomalies
+                    .push(format!("line {line}: malformed row: {e}"));
+                continue;
+            }
+        };
+        if row.timestamp_local.timestamp_nanos_opt() != Some(row.epoch_nanos_utc) {
+            report.anomalies.push(format!(
+                "line {line}: timestamp_local {} is not the same time as epoch_nanos_utc {}",
+                row.timestamp_local, row.epoch_nanos_utc
+            ));
+        }
+        match row.annotation.as_deref() {
+            Some(annotation) if annotation.ends_with(" triggers missing") => {
+                report.anomalies.push(format!("line {line}: {annotation}"));
+            }
+            Some(_) => {}
+            None => {
+                report.n_triggers += 1;
+                if let Some(prev) = prev_trigger.filter(|prev| row.epoch_nanos_utc < *prev) {
+                    report.anomalies.push(format!(
+                        "line {line}: epoch_nanos_utc {} is earlier than the previous trigger's {prev}",
+                        row.epoch_nanos_utc
+                    ));
+                }
+                prev_trigger = Some(row.epoch_nanos_utc);
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[test]
+fn test_check_csv() {
+    let header = format!("{},site\n", TRIGGER_ROW_COLUMNS.join(","));
+    let row = |local: &str, nanos: i64, annotation: &str| {
+        format!("{local},{nanos},,,1,,,,,,,{annotation},lab\n")
+    };
+    let good = [
+        header.clone(),
+        row("1970-01-01T01:00:01+01:00", 1_000_000_000, ""),
+        // An annotation noticed later than the next trigger
+        row("1970-01-01T00:00:03Z", 3_000_000_000, "device clock reset"),
+        row("1970-01-01T00:00:02.5Z", 2_500_000_000, ""),
+    ]
+    .concat();
+    let report = check_csv(good.as_bytes()).unwrap();
+    assert_eq!(report.anomalies, Vec::<String>::new());
+    assert_eq!((report.n_rows, report.n_triggers), (3, 2));
+
+    let bad = [
+        header,
+        row("1970-01-01T00:00:02Z", 2_000_000_000, ""),
+        row("1970-01-01T00:00:01Z", 1_000_000_000, ""),
+        row("1970-01-01T00:00:03Z", 3_000_000_001, ""),
+        row("1970-01-01T00:00:04Z", 4_000_000_000, "2 triggers missing"),
+        "not a time,5,,,,,,,,,,,lab\n".to_string(),
+    ]
+    .concat();
+    let report = check_csv(bad.as_bytes()).unwrap();
+    assert_eq!(report.n_rows, 5);
+    let lines: Vec<_> = report
+        .anomalies
+        .iter()
+        .map(|a| a.split(':').next().unwrap())
+        .collect();
+    assert_eq!(lines, ["line 3", "line 4", "line 5", "line 6"]);
+
+    let report = check_csv("timestamp,epoch_nanos_utc\n".as_bytes()).unwrap();
+    assert_eq!(report.anomalies.len(), 1);
+}